@@ -0,0 +1,54 @@
+use interra_api::components::mock::{Fault, MockGateway};
+use std::env;
+use std::time::Duration;
+use tokio::io::{self, AsyncBufReadExt, BufReader};
+
+// fake gateway for poking at the api without the house
+//
+//     cargo run --bin mock_gateway -- 127.0.0.1:9999
+//
+// then point TCP_IP/PORT at it with USERNAME=mock PASSWORD=mock and type commands on stdin:
+//     on <id> / off <id>     flip an object like the wall switch would (pushes the change)
+//     set <id> <value>       change an object's readValue (pushes the change)
+//     push <id>              push an object's current state
+//     drop                   hang up instead of answering the next request
+//     delay <ms>             answer the next request late
+//     garbage                send junk before the next answer
+//     kick                   disconnect everyone right now
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let addr = env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:9999".to_string());
+    let gateway = MockGateway::bind(&addr).await?;
+    println!("Mock Interra listening on {}", gateway.addr());
+
+    let mut lines = BufReader::new(io::stdin()).lines();
+    while let Some(line) = lines.next_line().await? {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let id = words.get(1).and_then(|id| id.parse::<u16>().ok());
+
+        match (words.first().copied(), id) {
+            (Some("on"), Some(id)) | (Some("off"), Some(id)) => {
+                let value = gateway.device(id).and_then(|d| d.read_value);
+                gateway.update(id, words[0] == "on", value.as_deref());
+            }
+            (Some("set"), Some(id)) => {
+                let active = gateway.device(id).is_some_and(|d| d.active);
+                gateway.update(id, active, words.get(2).copied());
+            }
+            (Some("push"), Some(id)) => gateway.push(id),
+            (Some("drop"), _) => gateway.inject(Fault::Drop),
+            (Some("delay"), _) => {
+                let ms = words.get(1).and_then(|ms| ms.parse().ok()).unwrap_or(1000);
+                gateway.inject(Fault::Delay(Duration::from_millis(ms)));
+            }
+            (Some("garbage"), _) => gateway.inject(Fault::Garbage),
+            (Some("kick"), _) => gateway.disconnect_all(),
+            (None, _) => continue,
+            _ => println!("huh? try on/off/set/push/drop/delay/garbage/kick"),
+        }
+    }
+
+    Ok(())
+}
@@ -1,5 +1,7 @@
+use crate::components::interra::InterraTcpClient;
 use crate::components::serde_models::CustomError;
 use actix_web::dev::Payload;
+use actix_web::web::Data;
 use actix_web::{Error, FromRequest, HttpRequest};
 use std::env;
 use std::future::{ready, Ready};
@@ -31,6 +33,12 @@ impl FromRequest for Authorized {
             _ => Err(CustomError::unauthorized("who are you")),
         };
 
+        if out.is_err() {
+            if let Some(client) = req.app_data::<Data<InterraTcpClient>>() {
+                client.metrics().unauthorized();
+            }
+        }
+
         ready(out)
     }
 }
@@ -0,0 +1,207 @@
+use crate::components::protocol::DeviceType;
+use crate::components::serde_models::{DeviceEvent, RoomObject};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Something read from the cache, and how old it is.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Cached<T> {
+    #[serde(flatten)]
+    pub value: T,
+    /// the last time the gateway told us about it, by answering or by pushing
+    pub updated_at: DateTime<Utc>,
+    /// older than the max age, or only what we wrote and the gateway hasn't said so yet. a
+    /// refresh is on its way
+    pub stale: bool,
+}
+
+impl<T> Cached<T> {
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Cached<U> {
+        Cached {
+            value: f(self.value),
+            updated_at: self.updated_at,
+            stale: self.stale,
+        }
+    }
+}
+
+struct Entry {
+    object: RoomObject,
+    updated_at: DateTime<Utc>,
+    // false after one of our writes, until a push or a room query backs it up. a write the
+    // gateway didn't complain about can still have gone nowhere
+    confirmed: bool,
+}
+
+// what a room query last said was in the room (the objects themselves are in `entries`)
+type RoomKey = (u16, DeviceType);
+
+struct Room {
+    ids: Vec<u16>,
+    // when it was asked, so rooms with nothing in them can go stale too
+    seeded_at: DateTime<Utc>,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<u16, Entry>,
+    rooms: HashMap<RoomKey, Room>,
+    // rooms with a background refresh already going
+    refreshing: HashSet<RoomKey>,
+}
+
+/// The last known state of every object that's been read, kept current by push frames and our
+/// own writes so most reads never have to ask the gateway.
+pub struct StateCache {
+    max_age: Duration,
+    state: Mutex<State>,
+}
+
+impl StateCache {
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            max_age,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// A room's objects of one type, if it's been read since the last [`Self::forget`].
+    pub fn room(&self, room_id: u16, object_type: DeviceType) -> Option<Vec<Cached<RoomObject>>> {
+        let state = self.state.lock().unwrap();
+        let room = state.rooms.get(&(room_id, object_type))?;
+        let now = Utc::now();
+
+        Some(
+            room.ids
+                .iter()
+                .filter_map(|id| state.entries.get(id))
+                .map(|entry| Cached {
+                    value: entry.object.clone(),
+                    updated_at: entry.updated_at,
+                    stale: !entry.confirmed || self.is_stale(entry.updated_at, now),
+                })
+                .collect(),
+        )
+    }
+
+    /// Every room with something read from it.
+    pub fn rooms(&self) -> Vec<u16> {
+        let state = self.state.lock().unwrap();
+        let mut rooms: Vec<u16> = state.rooms.keys().map(|(room, _)| *room).collect();
+        rooms.sort_unstable();
+        rooms.dedup();
+        rooms
+    }
+
+    fn is_stale(&self, updated_at: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        (now - updated_at).to_std().unwrap_or_default() > self.max_age
+    }
+
+    /// Everything a room query sent at `asked_at` answered, replacing whatever was known about
+    /// that room. Except for objects that got pushed or written since, those are newer.
+    pub fn seed(
+        &self,
+        room_id: u16,
+        object_type: DeviceType,
+        objects: &[RoomObject],
+        asked_at: DateTime<Utc>,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        let now = Utc::now();
+
+        for object in objects {
+            if state
+                .entries
+                .get(&object.id)
+                .is_some_and(|entry| entry.updated_at > asked_at)
+            {
+                continue;
+            }
+            state.entries.insert(
+                object.id,
+                Entry {
+                    object: object.clone(),
+                    updated_at: now,
+                    confirmed: true,
+                },
+            );
+        }
+        state.rooms.insert(
+            (room_id, object_type),
+            Room {
+                ids: objects.iter().map(|o| o.id).collect(),
+                seeded_at: now,
+            },
+        );
+    }
+
+    /// A push frame. Objects nobody has read yet are left for whoever reads them first.
+    pub fn pushed(&self, event: &DeviceEvent) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(entry) = state.entries.get_mut(&event.object_id) {
+            Self::set(entry, event.active, event.read_value.clone());
+            entry.confirmed = true;
+        }
+    }
+
+    /// What an object should be after a write sent at `sent_at` that the gateway didn't turn
+    /// down. It stays unconfirmed (and stale) until the gateway says so itself, and if it
+    /// already has since `sent_at`, that's what counts (our own earlier writes don't).
+    /// `read_value` of None leaves it as it was.
+    pub fn wrote(&self, id: u16, active: bool, read_value: Option<String>, sent_at: DateTime<Utc>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(entry) = state
+            .entries
+            .get_mut(&id)
+            .filter(|entry| !entry.confirmed || entry.updated_at < sent_at)
+        {
+            Self::set(entry, active, read_value);
+            entry.confirmed = false;
+        }
+    }
+
+    fn set(entry: &mut Entry, active: bool, read_value: Option<String>) {
+        entry.object.active = active;
+        if read_value.is_some() {
+            entry.object.read_value = read_value;
+        }
+        entry.updated_at = Utc::now();
+    }
+
+    /// True if the caller should go and refresh the room, false if it's fresh enough or
+    /// someone is already on it. Call [`Self::refreshed`] after, whether it worked or not.
+    pub fn needs_refresh(&self, room_id: u16, object_type: DeviceType) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some(room) = state.rooms.get(&(room_id, object_type)) else {
+            return false;
+        };
+        let now = Utc::now();
+        // pushes keep a room's objects fresh, but a room with nothing in it never gets any
+        let stale = (room.ids.is_empty() && self.is_stale(room.seeded_at, now))
+            || room
+                .ids
+                .iter()
+                .filter_map(|id| state.entries.get(id))
+                .any(|entry| !entry.confirmed || self.is_stale(entry.updated_at, now));
+        stale && state.refreshing.insert((room_id, object_type))
+    }
+
+    pub fn refreshed(&self, room_id: u16, object_type: DeviceType) {
+        self.state
+            .lock()
+            .unwrap()
+            .refreshing
+            .remove(&(room_id, object_type));
+    }
+
+    /// Drops everything, for when there's been a stretch nobody was listening for pushes.
+    pub fn forget(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.entries.clear();
+        state.rooms.clear();
+    }
+}
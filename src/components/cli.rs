@@ -0,0 +1,347 @@
+use crate::components::config::{range, Config};
+use crate::components::discovery::{Discovery, DiscoveryOptions};
+use crate::components::error::{self, InterraError};
+use crate::components::interra::InterraTcpClient;
+use crate::components::logging::{self, LogFormat};
+use crate::components::serde_models::{ACData, FanSpeed, Light, LightUpdate};
+use actix_web::web::Data;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::Serialize;
+use std::io;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const DEFAULT_BIND: &str = if cfg!(debug_assertions) {
+    "localhost:8080"
+} else {
+    "0.0.0.0:80"
+};
+
+/// The interra api, or a shortcut straight to the house without it.
+#[derive(Parser, Debug)]
+#[command(version, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// what `serve` gets when there's no command at all
+    #[command(flatten)]
+    pub serve: ServeArgs,
+    /// rooms/devices config, toml or json. ./interra.toml or my room if not given
+    #[arg(long, global = true, env = "CONFIG_FILE")]
+    pub config: Option<PathBuf>,
+    /// print JSON instead of sentences, for scripts
+    #[arg(long, global = true)]
+    pub json: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the api (what happens with no command too)
+    Serve(ServeArgs),
+    /// Look at or flip lights
+    Lights {
+        #[command(subcommand)]
+        command: LightsCommand,
+    },
+    /// Look at or change a room's ac
+    Ac {
+        #[command(subcommand)]
+        command: AcCommand,
+    },
+    /// Walk the gateway and list everything it has
+    Discover {
+        /// rooms to ask about, like 1-32, on top of the ones in the config. [discovery] in the
+        /// config if not given
+        #[arg(long, value_parser = range::<u16>)]
+        rooms: Option<RangeInclusive<u16>>,
+        /// object types to ask about, like 1-10. [discovery] in the config if not given
+        #[arg(long, value_parser = range::<u8>)]
+        types: Option<RangeInclusive<u8>>,
+    },
+    /// Check the config file (and the gateway settings) without starting anything
+    CheckConfig,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ServeArgs {
+    /// where to listen
+    #[arg(long, env = "BIND", default_value = DEFAULT_BIND)]
+    pub bind: String,
+    #[arg(long, env = "LOG_FORMAT", value_enum, default_value_t = LogFormat::Human)]
+    pub log_format: LogFormat,
+    #[arg(long, env = "SCENES_FILE", default_value = "scenes.json")]
+    pub scenes_file: PathBuf,
+    /// where schedules (and how their last run went) get saved
+    #[arg(long, env = "SCHEDULES_FILE", default_value = "schedules.json")]
+    pub schedules_file: PathBuf,
+    #[arg(long, env = "RULES_FILE", default_value = "rules.json")]
+    pub rules_file: PathBuf,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum LightsCommand {
+    /// Every light in a room
+    List {
+        /// the config's default room if not given
+        #[arg(long)]
+        room: Option<u16>,
+    },
+    /// Turn a light on (name, alias or id)
+    On { light: String },
+    /// Turn a light off (name, alias or id)
+    Off { light: String },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum AcCommand {
+    Get {
+        #[arg(long)]
+        room: Option<u16>,
+    },
+    /// Change whatever's given, leave the rest alone
+    Set {
+        #[arg(long)]
+        room: Option<u16>,
+        #[arg(long)]
+        temp: Option<u8>,
+        #[arg(long, value_enum)]
+        fan: Option<Fan>,
+        #[arg(long, value_enum)]
+        power: Option<Power>,
+    },
+}
+
+#[derive(ValueEnum, Debug, Copy, Clone)]
+pub enum Fan {
+    Auto,
+    Slow,
+    Medium,
+    Fast,
+}
+
+impl From<Fan> for FanSpeed {
+    fn from(fan: Fan) -> Self {
+        match fan {
+            Fan::Auto => FanSpeed::Auto,
+            Fan::Slow => FanSpeed::Slow,
+            Fan::Medium => FanSpeed::Medium,
+            Fan::Fast => FanSpeed::Fast,
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Copy, Clone)]
+pub enum Power {
+    On,
+    Off,
+}
+
+/// Does whatever the command line said.
+pub async fn run(cli: Cli) -> io::Result<()> {
+    let serve = match &cli.command {
+        None => Some(cli.serve.clone()),
+        Some(Command::Serve(serve)) => Some(serve.clone()),
+        Some(_) => None,
+    };
+    if let Some(serve) = serve {
+        logging::init(serve.log_format)?;
+        return crate::serve(Config::load(cli.config)?, serve).await;
+    }
+
+    // the answer goes to stdout, so the logs go to stderr and only when something's wrong
+    logging::init_quiet()?;
+    let config = Config::load(cli.config.clone())?;
+    let command = cli.command.expect("no command is serve");
+    let output = match command {
+        Command::CheckConfig => check_config(&config, cli.json)?,
+        command => {
+            let client = InterraTcpClient::start(config.gateway()?, config);
+            if let Err(e) = client.wait_ready(login_timeout(client.config())).await {
+                let why = client.status().last_error.unwrap_or_default();
+                return Err(io::Error::other(format!("{e} {why}")));
+            }
+            control(Data::from(client), command, cli.json).await?
+        }
+    };
+    println!("{output}");
+    Ok(())
+}
+
+fn check_config(config: &Config, json: bool) -> io::Result<String> {
+    // it's been validated on the way in, the gateway settings are the only thing left
+    let gateway = config.gateway()?;
+    let devices = config.devices().count();
+    Ok(show(
+        json,
+        &serde_json::json!({
+            "ok": true,
+            "rooms": config.rooms.len(),
+            "devices": devices,
+            "gateway": format!("{}:{}", gateway.host, gateway.port),
+        }),
+        || {
+            format!(
+                "looks good: {} rooms, {devices} devices, gateway at {}:{}",
+                config.rooms.len(),
+                gateway.host,
+                gateway.port
+            )
+        },
+    ))
+}
+
+/// The commands that talk to the gateway, run on `client`. Gives back what to print.
+pub async fn control(
+    client: Data<InterraTcpClient>,
+    command: Command,
+    json: bool,
+) -> error::Result<String> {
+    let room = |room: Option<u16>| {
+        room.or_else(|| client.config().default_room())
+            .ok_or_else(|| InterraError::ConfigMissing("which room though".to_string()))
+    };
+
+    match command {
+        Command::Lights {
+            command: LightsCommand::List { room: r },
+        } => {
+            let lights = client.get_room_lights(room(r)?).await?;
+            Ok(show(json, &lights, || {
+                let width = lights.iter().map(|l| l.id.len()).max().unwrap_or_default();
+                lights
+                    .iter()
+                    .map(|light| format!("{:<width$}  {}", light.id, light_state(light)))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }))
+        }
+        Command::Lights { command } => {
+            let (light, active) = match command {
+                LightsCommand::On { light } => (light, true),
+                LightsCommand::Off { light } => (light, false),
+                LightsCommand::List { .. } => unreachable!("listed above"),
+            };
+            let config = client.config();
+            let object_id = config
+                .light_id(&light)
+                .ok_or_else(|| InterraError::UnknownDevice(format!("no light called {light}")))?;
+            let room_id = room(config.room_of(object_id))?;
+            let update = LightUpdate {
+                active: Some(active),
+                ..LightUpdate::default()
+            };
+            let light = client.update_light(room_id, object_id, &update).await?;
+            Ok(show(json, &light, || {
+                format!("{} is {}", light.id, light_state(&light))
+            }))
+        }
+        Command::Ac {
+            command: AcCommand::Get { room: r },
+        } => {
+            let ac = client.get_ac_info(room(r)?).await?;
+            Ok(show(json, &ac, || ac_state(&ac)))
+        }
+        Command::Ac {
+            command:
+                AcCommand::Set {
+                    room: r,
+                    temp,
+                    fan,
+                    power,
+                },
+        } => {
+            let room_id = room(r)?;
+            let ac = ACData {
+                room_temp: None,
+                set_temp: temp,
+                fan_speed: fan.map(FanSpeed::from),
+                active: power.map(|p| matches!(p, Power::On)),
+            };
+            if ac.is_empty() {
+                return Err(InterraError::Validation(
+                    "--temp, --fan or --power, something to change".to_string(),
+                ));
+            }
+            let config = client.config().ac(room_id).ok_or_else(|| {
+                InterraError::UnknownDevice(format!("room {room_id} has no ac in the config"))
+            })?;
+            ac.validate(config).map_err(InterraError::Validation)?;
+
+            let result = client.set_ac_info(room_id, &ac).await?;
+            Ok(show(json, &result, || match result.converged {
+                true => ac_state(&result.state),
+                false => format!("{} (didn't get all the way there)", ac_state(&result.state)),
+            }))
+        }
+        Command::Discover { rooms, types } => {
+            let walk = &client.config().discovery;
+            let options = DiscoveryOptions {
+                rooms: rooms.unwrap_or_else(|| walk.rooms.clone()),
+                object_types: types.unwrap_or_else(|| walk.object_types.clone()),
+            };
+            let discovery = Discovery::new(client, options);
+            let registry = discovery.discover().await?;
+            Ok(show(json, &registry, || {
+                registry
+                    .rooms
+                    .values()
+                    .map(|room| {
+                        let devices = room
+                            .devices
+                            .iter()
+                            .map(|d| match &d.name {
+                                Some(name) => format!("  {} {name} ({:?})", d.id, d.object_type),
+                                None => format!("  {} ({:?})", d.id, d.object_type),
+                            })
+                            .collect::<Vec<_>>()
+                            .join("\n");
+                        format!("room {}:\n{devices}", room.id)
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }))
+        }
+        Command::Serve(_) | Command::CheckConfig => {
+            unreachable!("not something for the gateway")
+        }
+    }
+}
+
+fn show<T: Serialize>(json: bool, value: &T, human: impl FnOnce() -> String) -> String {
+    match json {
+        true => serde_json::to_string_pretty(value).expect("anything we print serializes"),
+        false => human(),
+    }
+}
+
+fn light_state(light: &Light) -> String {
+    match (light.active, light.brightness) {
+        (true, Some(brightness)) => format!("on ({brightness}%)"),
+        (true, None) => "on".to_string(),
+        (false, _) => "off".to_string(),
+    }
+}
+
+fn ac_state(ac: &ACData) -> String {
+    let mut parts = Vec::new();
+    if let Some(active) = ac.active {
+        parts.push(if active { "on" } else { "off" }.to_string());
+    }
+    if let Some(t) = ac.room_temp {
+        parts.push(format!("{t}° in the room"));
+    }
+    if let Some(t) = ac.set_temp {
+        parts.push(format!("set to {t}°"));
+    }
+    if let Some(fan) = ac.fan_speed {
+        parts.push(format!("fan {}", format!("{fan:?}").to_lowercase()));
+    }
+    parts.join(", ")
+}
+
+// how long a one-off command waits for the login before giving up
+fn login_timeout(config: &Config) -> Duration {
+    let timeouts = &config.timeouts;
+    timeouts.connect() + timeouts.write() + timeouts.response()
+}
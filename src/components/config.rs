@@ -0,0 +1,617 @@
+use crate::components::connection::GatewayConfig;
+use crate::components::protocol::{ActionType, DeviceType};
+use crate::components::serde_models::FanSpeed;
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use std::{env, fmt, fs, io};
+
+// used when no config file is given, if it's there
+const DEFAULT_FILE: &str = "interra.toml";
+// my room, and what the file format looks like
+const BUILT_IN: &str = include_str!("../../static/config.toml");
+
+/// Gateways, rooms and devices, from a TOML (or JSON) file.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// the room `/lights` and `/ac` talk to, the first room if it's not set
+    #[serde(default)]
+    pub default_room: Option<u16>,
+    #[serde(default)]
+    pub gateways: Vec<GatewayEntry>,
+    #[serde(default)]
+    pub rooms: Vec<RoomConfig>,
+    #[serde(default)]
+    pub pacing: Pacing,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    #[serde(default)]
+    pub discovery: DiscoveryConfig,
+}
+
+/// A gateway as written in the file. Anything left out comes from the env vars.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct GatewayEntry {
+    pub name: String,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+// keep the password out of logs
+impl fmt::Debug for GatewayEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GatewayEntry")
+            .field("name", &self.name)
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RoomConfig {
+    pub id: u16,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub devices: Vec<DeviceConfig>,
+    #[serde(default)]
+    pub ac: Option<AcConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    pub id: u16,
+    #[serde(rename = "type")]
+    pub object_type: DeviceType,
+    pub name: String,
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// for gateways that number things differently
+    #[serde(default)]
+    pub codes: WireCodes,
+}
+
+/// What a device goes by on the wire, where it isn't [`DeviceType::id`] / [`ActionType`].
+/// Only the dimmer and cover numbers are in here, those are the ones that are pure guesses.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct WireCodes {
+    /// the `objectType` its room gets asked for it with
+    pub object_type: Option<u8>,
+    /// `actionType`s
+    pub set_value: Option<u8>,
+    pub up: Option<u8>,
+    pub down: Option<u8>,
+    pub stop: Option<u8>,
+}
+
+impl DeviceConfig {
+    fn answers_to(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+            || self.aliases.iter().any(|a| a.eq_ignore_ascii_case(name))
+    }
+}
+
+/// Which objects a room's ac is read from and which ones get pressed to change it.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AcConfig {
+    pub power: u16,
+    pub room_temp: u16,
+    pub set_temp: u16,
+    pub fan_speed: u16,
+    pub power_on: u16,
+    pub power_off: u16,
+    pub temp_up: u16,
+    pub temp_down: u16,
+    pub fan: FanControls,
+    #[serde(default = "AcConfig::default_min")]
+    pub min_temp: u8,
+    #[serde(default = "AcConfig::default_max")]
+    pub max_temp: u8,
+    /// the gateway takes the setpoint as a value on `set_temp`, no up/down pressing needed
+    #[serde(default)]
+    pub set_temp_direct: bool,
+}
+
+impl AcConfig {
+    fn default_min() -> u8 {
+        20
+    }
+
+    fn default_max() -> u8 {
+        25
+    }
+
+    /// Whether a push about `id` says something about this ac.
+    pub fn reads(&self, id: u16) -> bool {
+        [self.power, self.room_temp, self.set_temp, self.fan_speed].contains(&id)
+    }
+
+    /// Whether `id` is one of this ac's objects at all, read or pressed.
+    pub fn has(&self, id: u16) -> bool {
+        self.reads(id)
+            || [
+                self.power_on,
+                self.power_off,
+                self.temp_up,
+                self.temp_down,
+                self.fan.auto,
+                self.fan.slow,
+                self.fan.medium,
+                self.fan.fast,
+            ]
+            .contains(&id)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FanControls {
+    pub auto: u16,
+    pub slow: u16,
+    pub medium: u16,
+    pub fast: u16,
+}
+
+impl FanControls {
+    pub fn press_id(&self, speed: FanSpeed) -> u16 {
+        match speed {
+            FanSpeed::Auto => self.auto,
+            FanSpeed::Slow => self.slow,
+            FanSpeed::Medium => self.medium,
+            FanSpeed::Fast => self.fast,
+        }
+    }
+}
+
+/// How long to leave a device alone after sending it a command, in milliseconds, by type.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct Pacing {
+    pub lights: u64,
+    pub dimmers: u64,
+    pub covers: u64,
+    pub ac: u64,
+}
+
+impl Default for Pacing {
+    fn default() -> Self {
+        Self {
+            lights: 0,
+            dimmers: 0,
+            covers: 0,
+            // it drops presses that come any faster
+            ac: 300,
+        }
+    }
+}
+
+impl Pacing {
+    pub fn interval(&self, object_type: DeviceType) -> Duration {
+        Duration::from_millis(match object_type {
+            DeviceType::Lights => self.lights,
+            DeviceType::Dimmers => self.dimmers,
+            DeviceType::Covers => self.covers,
+            DeviceType::Ac => self.ac,
+            DeviceType::Other(_) => 0,
+        })
+    }
+}
+
+/// How long a cached read is good for before it gets refreshed in the background, in
+/// milliseconds.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct CacheConfig {
+    pub max_age: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self { max_age: 60_000 }
+    }
+}
+
+impl CacheConfig {
+    pub fn max_age(&self) -> Duration {
+        Duration::from_millis(self.max_age)
+    }
+}
+
+/// What discovery walks. The gateway can't list what it has, so every (room, objectType) in
+/// here gets asked. Rooms in the config get asked about too, wherever they are.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct DiscoveryConfig {
+    /// like "1-32", or just "12"
+    #[serde(deserialize_with = "range_from_str")]
+    pub rooms: RangeInclusive<u16>,
+    #[serde(deserialize_with = "range_from_str")]
+    pub object_types: RangeInclusive<u8>,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            rooms: 1..=32,
+            object_types: 1..=10,
+        }
+    }
+}
+
+/// "1-32", or just "12".
+pub fn range<T: FromStr + Copy + PartialOrd>(value: &str) -> Result<RangeInclusive<T>, String> {
+    let parse = |n: &str| {
+        n.trim()
+            .parse::<T>()
+            .map_err(|_| format!("{n:?} isn't a number"))
+    };
+    let (from, to) = match value.split_once('-') {
+        Some((from, to)) => (parse(from)?, parse(to)?),
+        None => (parse(value)?, parse(value)?),
+    };
+    match from <= to {
+        true => Ok(from..=to),
+        false => Err("that range goes backwards".to_string()),
+    }
+}
+
+fn range_from_str<'de, D, T>(deserializer: D) -> Result<RangeInclusive<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr + Copy + PartialOrd,
+{
+    let value = String::deserialize(deserializer)?;
+    range(&value).map_err(de::Error::custom)
+}
+
+/// How long to wait on the gateway before giving up on it, in milliseconds.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct Timeouts {
+    /// getting a socket open
+    pub connect: u64,
+    /// getting a line out
+    pub write: u64,
+    /// getting an answer back, logging in included
+    pub response: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: 5_000,
+            write: 5_000,
+            response: 10_000,
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn connect(&self) -> Duration {
+        Duration::from_millis(self.connect)
+    }
+
+    pub fn write(&self) -> Duration {
+        Duration::from_millis(self.write)
+    }
+
+    pub fn response(&self) -> Duration {
+        Duration::from_millis(self.response)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::parse(BUILT_IN, Format::Toml).expect("the built-in config parses")
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Format {
+    Toml,
+    Json,
+}
+
+impl Format {
+    fn of(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("json") => Self::Json,
+            _ => Self::Toml,
+        }
+    }
+}
+
+impl Config {
+    /// `path` if it's given (`--config` or `CONFIG_FILE`), `interra.toml` if it exists, my room
+    /// otherwise.
+    pub fn load(path: Option<PathBuf>) -> io::Result<Self> {
+        let path = match path {
+            Some(path) => path,
+            None if Path::new(DEFAULT_FILE).exists() => PathBuf::from(DEFAULT_FILE),
+            None => {
+                tracing::info!("no config file, using the built-in one");
+                return Ok(Self::default());
+            }
+        };
+        Self::read(&path)
+    }
+
+    /// Reads, parses and validates a config file.
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+        let config = Self::parse(&contents, Format::of(path))
+            .map_err(|e| io::Error::other(format!("{}: {e}", path.display())))?;
+
+        if let Err(problems) = config.validate() {
+            return Err(io::Error::other(format!(
+                "{} has {} problem(s):\n  - {}",
+                path.display(),
+                problems.len(),
+                problems.join("\n  - ")
+            )));
+        }
+        tracing::info!(
+            path = %path.display(),
+            rooms = config.rooms.len(),
+            devices = config.devices().count(),
+            "loaded the config"
+        );
+        Ok(config)
+    }
+
+    pub fn parse(contents: &str, format: Format) -> io::Result<Self> {
+        match format {
+            Format::Toml => toml::from_str(contents).map_err(io::Error::other),
+            Format::Json => serde_json::from_str(contents).map_err(io::Error::other),
+        }
+    }
+
+    /// Everything wrong with the config, not just the first thing.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+
+        let mut gateways = HashSet::new();
+        for gateway in &self.gateways {
+            if gateway.name.trim().is_empty() {
+                problems.push("a gateway has an empty name".to_string());
+            } else if !gateways.insert(gateway.name.as_str()) {
+                problems.push(format!("gateway {:?} is declared twice", gateway.name));
+            }
+        }
+
+        let mut rooms = HashSet::new();
+        let mut devices = HashMap::new();
+        // lowercased name or alias -> device id
+        let mut names: HashMap<String, u16> = HashMap::new();
+        for room in &self.rooms {
+            if !rooms.insert(room.id) {
+                problems.push(format!("room {} is declared twice", room.id));
+            }
+
+            // a room gets asked once per type, so its devices of a type go by the same number
+            let mut object_types = HashMap::new();
+            for device in &room.devices {
+                let code = device.codes.object_type;
+                if let Some(code) = code {
+                    match object_types.insert(device.object_type, code) {
+                        Some(other) if other != code => problems.push(format!(
+                            "room {} has {:?} with object_type {other} and {code}",
+                            room.id, device.object_type
+                        )),
+                        _ => {}
+                    }
+                }
+
+                if let Some(other_room) = devices.insert(device.id, room.id) {
+                    problems.push(format!(
+                        "device {} is declared twice (rooms {other_room} and {})",
+                        device.id, room.id
+                    ));
+                }
+
+                for name in std::iter::once(&device.name).chain(&device.aliases) {
+                    if name.trim().is_empty() {
+                        problems.push(format!("device {} has an empty name or alias", device.id));
+                        continue;
+                    }
+                    // numbers are how devices without a name get called
+                    if name.parse::<u16>().is_ok() {
+                        problems.push(format!(
+                            "device {} can't be called {name:?}, names can't be numbers",
+                            device.id
+                        ));
+                        continue;
+                    }
+                    match names.insert(name.to_lowercase(), device.id) {
+                        Some(other) if other != device.id => problems.push(format!(
+                            "{name:?} is used by both device {other} and device {}",
+                            device.id
+                        )),
+                        Some(_) => problems
+                            .push(format!("device {} has {name:?} more than once", device.id)),
+                        None => {}
+                    }
+                }
+            }
+
+            if let Some(ac) = &room.ac {
+                if ac.min_temp > ac.max_temp {
+                    problems.push(format!(
+                        "room {}'s ac has min_temp {} above max_temp {}",
+                        room.id, ac.min_temp, ac.max_temp
+                    ));
+                }
+            }
+        }
+
+        if let Some(id) = self.default_room.filter(|id| !rooms.contains(id)) {
+            problems.push(format!("default_room {id} isn't one of the rooms"));
+        }
+
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(problems),
+        }
+    }
+
+    /// The gateway to connect to: the one named by `GATEWAY`, or the first one, with the env
+    /// vars filling in (and overriding) its fields.
+    pub fn gateway(&self) -> io::Result<GatewayConfig> {
+        let entry = match env::var("GATEWAY") {
+            Ok(name) => Some(
+                self.gateways
+                    .iter()
+                    .find(|g| g.name == name)
+                    .ok_or_else(|| io::Error::other(format!("no gateway called {name:?}")))?,
+            ),
+            Err(_) => self.gateways.first(),
+        };
+        GatewayConfig::resolve(entry)
+    }
+
+    pub fn default_room(&self) -> Option<u16> {
+        self.default_room
+            .or_else(|| self.rooms.first().map(|r| r.id))
+    }
+
+    pub fn room(&self, id: u16) -> Option<&RoomConfig> {
+        self.rooms.iter().find(|r| r.id == id)
+    }
+
+    pub fn devices(&self) -> impl Iterator<Item = &DeviceConfig> {
+        self.rooms.iter().flat_map(|r| r.devices.iter())
+    }
+
+    pub fn device(&self, id: u16) -> Option<&DeviceConfig> {
+        self.devices().find(|d| d.id == id)
+    }
+
+    pub fn ac(&self, room_id: u16) -> Option<&AcConfig> {
+        self.room(room_id)?.ac.as_ref()
+    }
+
+    /// The room whose ac reads object `id`.
+    pub fn ac_reading(&self, id: u16) -> Option<(u16, &AcConfig)> {
+        self.rooms
+            .iter()
+            .find_map(|r| r.ac.as_ref().filter(|ac| ac.reads(id)).map(|ac| (r.id, ac)))
+    }
+
+    /// The room a device is declared in.
+    pub fn room_of(&self, id: u16) -> Option<u16> {
+        self.rooms
+            .iter()
+            .find(|r| r.devices.iter().any(|d| d.id == id))
+            .map(|r| r.id)
+    }
+
+    /// Where to read object `id` from: its room and type, if the config says.
+    pub fn locate(&self, id: u16) -> Option<(u16, DeviceType)> {
+        match self.device(id) {
+            Some(device) => Some((self.room_of(id)?, device.object_type)),
+            None => self
+                .ac_reading(id)
+                .map(|(room_id, _)| (room_id, DeviceType::Ac)),
+        }
+    }
+
+    /// The `objectType` room `room_id` gets asked for `object_type` with.
+    pub fn object_type_code(&self, room_id: u16, object_type: DeviceType) -> u8 {
+        self.room(room_id)
+            .into_iter()
+            .flat_map(|r| r.devices.iter())
+            .filter(|d| d.object_type == object_type)
+            .find_map(|d| d.codes.object_type)
+            .unwrap_or_else(|| object_type.id())
+    }
+
+    /// The `actionType` that does `action` to object `id`.
+    pub fn action_code(&self, id: u16, action: ActionType) -> u8 {
+        let codes = self.device(id).map(|d| &d.codes);
+        let custom = codes.and_then(|codes| match action {
+            ActionType::SetValue => codes.set_value,
+            ActionType::Up => codes.up,
+            ActionType::Down => codes.down,
+            ActionType::Stop => codes.stop,
+            ActionType::On | ActionType::Off | ActionType::Press => None,
+        });
+        custom.unwrap_or(action as u8)
+    }
+
+    /// Any device by name, alias or plain object id.
+    pub fn device_id(&self, name: &str) -> Option<u16> {
+        self.id_of(name, |_| true)
+    }
+
+    /// A light we have a name for.
+    pub fn light(&self, id: u16) -> Option<&DeviceConfig> {
+        self.device(id).filter(|d| d.object_type.is_light())
+    }
+
+    /// What a light is called in the api: its name, or just its id if it hasn't got one.
+    pub fn light_name(&self, id: u16) -> String {
+        self.name_of(id, DeviceType::is_light)
+    }
+
+    /// Goes the other way: a light's name, one of its aliases, or a plain object id.
+    pub fn light_id(&self, name: &str) -> Option<u16> {
+        self.id_of(name, DeviceType::is_light)
+    }
+
+    pub fn cover_name(&self, id: u16) -> String {
+        self.name_of(id, |t| *t == DeviceType::Covers)
+    }
+
+    pub fn cover_id(&self, name: &str) -> Option<u16> {
+        self.id_of(name, |t| *t == DeviceType::Covers)
+    }
+
+    fn name_of(&self, id: u16, kind: impl Fn(&DeviceType) -> bool) -> String {
+        match self.device(id).filter(|d| kind(&d.object_type)) {
+            Some(device) => device.name.clone(),
+            None => id.to_string(),
+        }
+    }
+
+    // a plain id only counts if the config doesn't say it's something else: /lights/63 isn't
+    // getting to press the ac's buttons
+    fn id_of(&self, name: &str, kind: impl Fn(&DeviceType) -> bool) -> Option<u16> {
+        self.devices()
+            .filter(|d| kind(&d.object_type))
+            .find(|d| d.answers_to(name))
+            .map(|d| d.id)
+            .or_else(|| {
+                let id = name.parse().ok()?;
+                let is_kind = match self.device(id) {
+                    Some(device) => kind(&device.object_type),
+                    None => !self.is_ac_object(id) || kind(&DeviceType::Ac),
+                };
+                is_kind.then_some(id)
+            })
+    }
+
+    fn is_ac_object(&self, id: u16) -> bool {
+        self.rooms
+            .iter()
+            .filter_map(|r| r.ac.as_ref())
+            .any(|ac| ac.has(id))
+    }
+}
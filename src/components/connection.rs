@@ -0,0 +1,119 @@
+use crate::components::config::GatewayEntry;
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::{env, fmt, io};
+
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// Where the gateway is and who to log in as.
+#[derive(Clone)]
+pub struct GatewayConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub password: String,
+    /// write every frame to this JSONL file (see `recording`)
+    pub record: Option<PathBuf>,
+}
+
+impl GatewayConfig {
+    /// Env vars first, then whatever the config file said about the gateway.
+    pub fn resolve(entry: Option<&GatewayEntry>) -> io::Result<Self> {
+        let host = env::var("TCP_IP")
+            .ok()
+            .or_else(|| entry.and_then(|e| e.host.clone()))
+            .ok_or_else(|| io::Error::other("TCP_IP not supplied in .env"))?;
+        let port = match env::var("PORT") {
+            Ok(port) => port
+                .parse::<u16>()
+                .map_err(|_| io::Error::other("this is not a port"))?,
+            Err(_) => entry
+                .and_then(|e| e.port)
+                .ok_or_else(|| io::Error::other("PORT not supplied in .env"))?,
+        };
+        let username = env::var("USERNAME")
+            .ok()
+            .or_else(|| entry.and_then(|e| e.username.clone()))
+            .ok_or_else(|| io::Error::other("USERNAME not supplied in .env"))?;
+        let password = env::var("PASSWORD")
+            .ok()
+            .or_else(|| entry.and_then(|e| e.password.clone()))
+            .ok_or_else(|| io::Error::other("PASSWORD not supplied in .env"))?;
+
+        Ok(Self {
+            host,
+            port,
+            username,
+            password,
+            record: env::var_os("RECORD_FILE").map(PathBuf::from),
+        })
+    }
+}
+
+// keep the password out of logs
+impl fmt::Debug for GatewayConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GatewayConfig")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .field("record", &self.record)
+            .finish()
+    }
+}
+
+/// Where the link to the gateway is at.
+///
+/// Disconnected → Connecting → Authenticating → Ready, and Ready → Degraded when the link is
+/// still up but stopped behaving (keep-alive went unanswered etc.) and is about to be replaced.
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ConnectionState {
+    Disconnected,
+    Connecting,
+    Authenticating,
+    Ready,
+    Degraded,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionStatus {
+    pub state: ConnectionState,
+    pub since: DateTime<Utc>,
+    pub last_error: Option<String>,
+    /// successful connects after the first one
+    pub reconnects: u64,
+    /// times the gateway forgot our session and we logged back in on the same link
+    pub reauths: u64,
+    /// logins the gateway said no to
+    pub auth_failures: u64,
+}
+
+impl Default for ConnectionStatus {
+    fn default() -> Self {
+        Self {
+            state: ConnectionState::Disconnected,
+            since: Utc::now(),
+            last_error: None,
+            reconnects: 0,
+            reauths: 0,
+            auth_failures: 0,
+        }
+    }
+}
+
+/// How long to wait before reconnect attempt number `attempt` (starting at 0): exponential,
+/// capped, with "equal jitter" so a house full of clients doesn't stampede the gateway.
+pub fn backoff(attempt: u32) -> Duration {
+    let full = BACKOFF_BASE
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(BACKOFF_MAX);
+    let half = full / 2;
+    half + half.mul_f64(rand::thread_rng().gen::<f64>())
+}
@@ -0,0 +1,220 @@
+use chrono::{DateTime, Datelike, Duration, LocalResult, NaiveDateTime, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+// nothing matching in this long means it never will (feb 30th and friends)
+const SEARCH_DAYS: i64 = 366 * 5;
+const ALL_HOURS: u64 = (1 << 24) - 1;
+
+/// A plain five field cron expression (minute hour day-of-month month day-of-week), with
+/// `*`, lists, ranges, steps, jan-dec / sun-sat and the @daily style shorthands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronExpr {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // "either one" instead of "both" when both day fields are restricted, like real cron
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+impl CronExpr {
+    pub fn parse(expr: &str) -> Result<Self, String> {
+        let expr = match expr.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            other => other,
+        };
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!(
+                "cron needs 5 fields (minute hour day month weekday), this has {}",
+                fields.len()
+            ));
+        };
+
+        Ok(Self {
+            minutes: field(minutes, 0, 59, &[], 0).map_err(|e| format!("minute: {e}"))?,
+            hours: field(hours, 0, 23, &[], 0).map_err(|e| format!("hour: {e}"))?,
+            days: field(days, 1, 31, &[], 0).map_err(|e| format!("day: {e}"))?,
+            months: field(months, 1, 12, &MONTHS, 1).map_err(|e| format!("month: {e}"))?,
+            // 7 is sunday too
+            weekdays: field(weekdays, 0, 7, &WEEKDAYS, 0)
+                .map(|w| (w | (w >> 7)) & 0x7f)
+                .map_err(|e| format!("weekday: {e}"))?,
+            days_restricted: days != "*",
+            weekdays_restricted: weekdays != "*",
+        })
+    }
+
+    /// The first wall clock minute after `after` (not counting `after` itself) that matches.
+    pub fn next_local(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        let start =
+            after.date().and_hms_opt(after.hour(), after.minute(), 0)? + Duration::minutes(1);
+        let limit = start + Duration::days(SEARCH_DAYS);
+        let mut t = start;
+
+        while t < limit {
+            if !has(self.months, t.month()) {
+                let (year, month) = match t.month() {
+                    12 => (t.year() + 1, 1),
+                    month => (t.year(), month + 1),
+                };
+                t = chrono::NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+            } else if !self.day_matches(&t) {
+                t = t.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+            } else if !has(self.hours, t.hour()) {
+                t = t.date().and_hms_opt(t.hour(), 0, 0)? + Duration::hours(1);
+            } else if !has(self.minutes, t.minute()) {
+                t += Duration::minutes(1);
+            } else {
+                return Some(t);
+            }
+        }
+        None
+    }
+
+    /// The next time this fires in `tz` after `after`.
+    ///
+    /// DST: a time that doesn't happen at all (clocks going forward) fires when the gap ends
+    /// instead. A time that happens twice (clocks going back) fires on the first one only,
+    /// unless this runs every hour anyway, then the repeated hour is just another hour (like
+    /// vixie cron).
+    pub fn next_after(&self, after: DateTime<Utc>, tz: Tz) -> Option<DateTime<Utc>> {
+        let first = self.next_first(after, tz);
+        let repeat = match self.hours == ALL_HOURS {
+            true => self.next_repeat(after, tz),
+            false => None,
+        };
+        first.into_iter().chain(repeat).min()
+    }
+
+    // counting only the first copy of a repeated hour
+    fn next_first(&self, after: DateTime<Utc>, tz: Tz) -> Option<DateTime<Utc>> {
+        let mut local = after.with_timezone(&tz).naive_local();
+
+        // the repeated hour can hand back a few candidates that are already behind us
+        for _ in 0..128 {
+            let candidate = self.next_local(local)?;
+            let at = match tz.from_local_datetime(&candidate) {
+                LocalResult::Single(at) => at,
+                LocalResult::Ambiguous(first, _) => first,
+                LocalResult::None => end_of_gap(tz, candidate)?,
+            }
+            .with_timezone(&Utc);
+
+            if at > after {
+                return Some(at);
+            }
+            local = candidate;
+        }
+        None
+    }
+
+    // the second copy of a repeated hour, if we're in or right next to one
+    fn next_repeat(&self, after: DateTime<Utc>, tz: Tz) -> Option<DateTime<Utc>> {
+        let local = after.with_timezone(&tz).naive_local();
+        let end = local + Duration::hours(1);
+        let mut t = local - Duration::hours(1);
+
+        while let Some(candidate) = self.next_local(t).filter(|c| *c <= end) {
+            if let LocalResult::Ambiguous(_, second) = tz.from_local_datetime(&candidate) {
+                let second = second.with_timezone(&Utc);
+                if second > after {
+                    return Some(second);
+                }
+            }
+            t = candidate;
+        }
+        None
+    }
+
+    fn day_matches(&self, t: &NaiveDateTime) -> bool {
+        let day = has(self.days, t.day());
+        let weekday = has(self.weekdays, t.weekday().num_days_from_sunday());
+        match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+}
+
+/// A timezone by its IANA name, like "Europe/Athens".
+pub fn timezone(name: &str) -> Result<Tz, String> {
+    name.parse()
+        .map_err(|_| format!("{name:?} isn't a timezone"))
+}
+
+/// For serde, when a timezone's left out.
+pub fn utc() -> String {
+    "UTC".to_string()
+}
+
+fn has(set: u64, value: u32) -> bool {
+    set & (1 << value) != 0
+}
+
+// the first minute after a wall clock time that got skipped
+fn end_of_gap(tz: Tz, mut t: NaiveDateTime) -> Option<chrono::DateTime<Tz>> {
+    for _ in 0..24 * 60 {
+        t += Duration::minutes(1);
+        if let Some(at) = tz.from_local_datetime(&t).earliest() {
+            return Some(at);
+        }
+    }
+    None
+}
+
+// one field as a bitset of the values it allows. `names[i]` means `i + names_from`
+fn field(src: &str, min: u32, max: u32, names: &[&str], names_from: u32) -> Result<u64, String> {
+    let value = |v: &str| -> Result<u32, String> {
+        let v = v.to_ascii_lowercase();
+        let n = match names.iter().position(|name| *name == v) {
+            Some(i) => i as u32 + names_from,
+            None => v.parse().map_err(|_| format!("{v:?} isn't a number"))?,
+        };
+        match (min..=max).contains(&n) {
+            true => Ok(n),
+            false => Err(format!("{n} isn't between {min} and {max}")),
+        }
+    };
+
+    let mut set = 0;
+    for item in src.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<u32>()
+                    .ok()
+                    .filter(|s| *s > 0)
+                    .ok_or_else(|| format!("{step:?} isn't a step"))?,
+            ),
+            None => (item, 1),
+        };
+        let (from, to) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((from, to)) => (value(from)?, value(to)?),
+                // "5/15" means from 5 to the end, every 15
+                None if step > 1 => (value(range)?, max),
+                None => (value(range)?, value(range)?),
+            },
+        };
+        if from > to {
+            return Err(format!("{from}-{to} goes backwards"));
+        }
+        for n in (from..=to).step_by(step as usize) {
+            set |= 1 << n;
+        }
+    }
+    Ok(set)
+}
@@ -0,0 +1,246 @@
+use crate::components::config::DiscoveryConfig;
+use crate::components::error::{InterraError, Result};
+use crate::components::interra::InterraTcpClient;
+use crate::components::protocol::DeviceType;
+use crate::components::serde_models::RoomObject;
+use actix_web::web::Data;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::RangeInclusive;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{Mutex, RwLock};
+
+/// Which rooms and object types to ask the gateway about. It has no "list everything" request,
+/// so discovery just asks every (room, objectType) pair in here and keeps what comes back.
+/// The rooms in the config get asked about too, even outside `rooms`.
+#[derive(Debug, Clone)]
+pub struct DiscoveryOptions {
+    pub rooms: RangeInclusive<u16>,
+    pub object_types: RangeInclusive<u8>,
+}
+
+impl From<&DiscoveryConfig> for DiscoveryOptions {
+    fn from(config: &DiscoveryConfig) -> Self {
+        Self {
+            rooms: config.rooms.clone(),
+            object_types: config.object_types.clone(),
+        }
+    }
+}
+
+impl Default for DiscoveryOptions {
+    fn default() -> Self {
+        Self::from(&DiscoveryConfig::default())
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Device {
+    pub id: u16,
+    pub room_id: u16,
+    pub object_type: DeviceType,
+    pub name: Option<String>,
+    pub active: bool,
+    pub read_value: Option<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Device {
+    fn new(room_id: u16, object_type: DeviceType, object: RoomObject) -> Self {
+        Self {
+            id: object.id,
+            room_id,
+            object_type,
+            name: object.name,
+            active: object.active,
+            read_value: object.read_value,
+            updated_at: Utc::now(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Room {
+    pub id: u16,
+    pub object_types: Vec<DeviceType>,
+    pub devices: Vec<Device>,
+}
+
+/// Everything the last discovery walk found, kept current by push events.
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceRegistry {
+    pub rooms: BTreeMap<u16, Room>,
+    pub discovered_at: Option<DateTime<Utc>>,
+}
+
+impl DeviceRegistry {
+    pub fn devices(&self) -> impl Iterator<Item = &Device> {
+        self.rooms.values().flat_map(|room| room.devices.iter())
+    }
+
+    pub fn device(&self, id: u16) -> Option<&Device> {
+        self.devices().find(|d| d.id == id)
+    }
+
+    fn device_mut(&mut self, id: u16) -> Option<&mut Device> {
+        self.rooms
+            .values_mut()
+            .flat_map(|room| room.devices.iter_mut())
+            .find(|d| d.id == id)
+    }
+}
+
+pub struct Discovery {
+    interra: Data<InterraTcpClient>,
+    options: DiscoveryOptions,
+    registry: RwLock<DeviceRegistry>,
+    // one walk at a time
+    walking: Mutex<()>,
+}
+
+impl Discovery {
+    /// Nothing found yet and nothing running, [`Discovery::discover`] does the walking.
+    pub fn new(interra: Data<InterraTcpClient>, options: DiscoveryOptions) -> Self {
+        Self {
+            interra,
+            options,
+            registry: RwLock::new(DeviceRegistry::default()),
+            walking: Mutex::new(()),
+        }
+    }
+
+    /// Walks the gateway once it's reachable and then follows push events.
+    pub fn start(interra: Data<InterraTcpClient>, options: DiscoveryOptions) -> Data<Self> {
+        let discovery = Data::new(Self::new(interra, options));
+
+        let discovery_loop = discovery.clone();
+        tokio::spawn(async move {
+            let mut events = discovery_loop.interra.subscribe();
+
+            while discovery_loop
+                .interra
+                .wait_ready(Duration::from_secs(60))
+                .await
+                .is_err()
+            {}
+            if let Err(e) = discovery_loop.discover().await {
+                tracing::warn!(error = %e, "discovery failed");
+            }
+
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                };
+
+                let mut registry = discovery_loop.registry.write().await;
+                if let Some(device) = registry.device_mut(event.object_id) {
+                    device.active = event.active;
+                    device.read_value = event.read_value;
+                    device.updated_at = event.received_at;
+                }
+            }
+        });
+
+        discovery
+    }
+
+    pub async fn registry(&self) -> DeviceRegistry {
+        self.registry.read().await.clone()
+    }
+
+    fn rooms(&self) -> Vec<u16> {
+        let mut rooms: BTreeSet<u16> = self.options.rooms.clone().collect();
+        rooms.extend(self.interra.config().rooms.iter().map(|room| room.id));
+        rooms.into_iter().collect()
+    }
+
+    /// Asks the gateway about every room/type in the options and replaces the registry. A pair
+    /// the gateway says nothing about is empty. One it answers with an error keeps what the last
+    /// walk found, that's only an error when none of them worked (or the link itself is gone).
+    pub async fn discover(&self) -> Result<DeviceRegistry> {
+        let _walking = self.walking.lock().await;
+        let room_ids = self.rooms();
+        tracing::info!(rooms = ?room_ids, "discovering");
+
+        let config = self.interra.config();
+        let previous = self.registry.read().await.clone();
+        let mut rooms = BTreeMap::new();
+        let (mut answered, mut failed) = (0, None);
+        for room_id in room_ids {
+            for object_type in self.options.object_types.clone().map(DeviceType::from_id) {
+                // most pairs aren't there at all, and the gateway saying nothing back about one
+                // just means that
+                let objects = match self.interra.probe_room_objects(room_id, object_type).await {
+                    Ok(None) => {
+                        answered += 1;
+                        tracing::debug!(room_id, ?object_type, "no answer, nothing there");
+                        continue;
+                    }
+                    Ok(Some(objects)) => {
+                        answered += 1;
+                        objects
+                            .into_iter()
+                            .map(|mut object| {
+                                // the gateway doesn't name things, the config might
+                                if object.name.is_none() {
+                                    object.name = config.device(object.id).map(|d| d.name.clone());
+                                }
+                                Device::new(room_id, object_type, object)
+                            })
+                            .collect()
+                    }
+                    // nothing after this is getting through either
+                    Err(e @ (InterraError::Connect(_) | InterraError::AuthRejected(_))) => {
+                        return Err(e)
+                    }
+                    Err(e) => {
+                        tracing::warn!(room_id, ?object_type, error = %e, "skipping in discovery");
+                        failed = Some(e);
+                        previous
+                            .rooms
+                            .get(&room_id)
+                            .into_iter()
+                            .flat_map(|room| room.devices.iter())
+                            .filter(|d| d.object_type == object_type)
+                            .cloned()
+                            .collect::<Vec<_>>()
+                    }
+                };
+                if objects.is_empty() {
+                    continue;
+                }
+
+                let room = rooms.entry(room_id).or_insert_with(|| Room {
+                    id: room_id,
+                    object_types: Vec::new(),
+                    devices: Vec::new(),
+                });
+                room.object_types.push(object_type);
+                room.devices.extend(objects);
+            }
+        }
+        if let Some(e) = failed.filter(|_| answered == 0) {
+            return Err(e);
+        }
+
+        let registry = DeviceRegistry {
+            rooms,
+            discovered_at: Some(Utc::now()),
+        };
+        tracing::info!(
+            rooms = registry.rooms.len(),
+            devices = registry.devices().count(),
+            "discovered"
+        );
+        *self.registry.write().await = registry.clone();
+
+        Ok(registry)
+    }
+}
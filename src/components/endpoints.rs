@@ -1,11 +1,25 @@
 use crate::components::auth::Authorized;
+use crate::components::cache::Cached;
+use crate::components::config::AcConfig;
+use crate::components::discovery::{Device, DeviceRegistry, Discovery, Room};
+use crate::components::error::InterraError;
+use crate::components::feed::EventFeed;
 use crate::components::interra::InterraTcpClient;
-use crate::components::serde_models::{ACData, CustomError, Example, Light};
+use crate::components::metrics;
+use crate::components::protocol::DeviceType;
+use crate::components::rules::{Evaluation, Rule, RuleEngine};
+use crate::components::scenes::{self, Scene, SceneReport, SceneStore};
+use crate::components::scheduler::{Schedule, Scheduler};
+use crate::components::serde_models::{
+    ACData, ACSetResult, Cover, CoverMove, CoverUpdate, CustomError, Example, Light, LightUpdate,
+};
+use crate::components::ws;
 use crate::Data;
 use actix_web::http::StatusCode;
-use actix_web::{get, patch, web, Error, HttpRequest, HttpResponse};
+use actix_web::{delete, get, patch, post, put, web, Error, HttpRequest, HttpResponse};
 use serde::Deserialize;
 use serde_json::Value;
+use std::collections::BTreeMap;
 
 #[get("/")]
 pub async fn root() -> HttpResponse {
@@ -18,7 +32,11 @@ pub async fn root() -> HttpResponse {
 pub async fn restart(req: HttpRequest, _: Authorized) -> Result<web::Json<Example>, Error> {
     match req.app_data::<Data<InterraTcpClient>>() {
         Some(interra) => interra.reconnect().await?,
-        None => return Err(CustomError::internal_server_error("couldn't restart tcp client") ),
+        None => {
+            return Err(CustomError::internal_server_error(
+                "couldn't restart tcp client",
+            ))
+        }
     }
 
     Ok(web::Json(Example {
@@ -26,105 +44,604 @@ pub async fn restart(req: HttpRequest, _: Authorized) -> Result<web::Json<Exampl
     }))
 }
 
-#[get("/lights")]
-pub async fn get_lights(req: HttpRequest, _: Authorized) -> Result<web::Json<Vec<Light>>, Error> {
-    match req.app_data::<Data<InterraTcpClient>>() {
-        Some(interra) => Ok(web::Json(interra.get_room_lights(12).await?)),
-        None => Err(CustomError::internal_server_error(
-            "tcp client suffering, sorry!",
-        )),
+fn client(req: &HttpRequest) -> Result<&Data<InterraTcpClient>, Error> {
+    req.app_data::<Data<InterraTcpClient>>()
+        .ok_or_else(|| CustomError::internal_server_error("tcp client suffering, sorry!"))
+}
+
+// the room in the path, or the default one for the routes without a room
+fn room_id(req: &HttpRequest, interra: &InterraTcpClient) -> Result<u16, Error> {
+    match req.match_info().get("room_id") {
+        Some(room_id) => room_id
+            .parse()
+            .map_err(|_| CustomError::bad_request("that is NOT a room")),
+        None => interra.config().default_room().ok_or_else(|| {
+            CustomError::not_found("no default room in the config, use /rooms/{room_id}/...")
+        }),
     }
 }
 
-#[get("/lights/{id}")]
-pub async fn get_light(req: HttpRequest, _: Authorized) -> Result<web::Json<Light>, Error> {
-    let lights = match req.app_data::<Data<InterraTcpClient>>() {
-        Some(interra) => interra.get_room_lights(12).await?,
+fn ac_config(interra: &InterraTcpClient, room_id: u16) -> Result<&AcConfig, Error> {
+    interra.config().ac(room_id).ok_or_else(|| {
+        CustomError::not_found(&format!(
+            "no ac in room {room_id} (not in the config anyway)"
+        ))
+    })
+}
+
+#[derive(Deserialize)]
+struct Freshness {
+    #[serde(default)]
+    fresh: bool,
+}
+
+// reads come out of the cache unless it's `?fresh=true`
+fn fresh(req: &HttpRequest) -> Result<bool, Error> {
+    web::Query::<Freshness>::from_query(req.query_string())
+        .map(|query| query.fresh)
+        .map_err(|_| CustomError::bad_request("fresh is true or false, nothing else"))
+}
+
+async fn lights(req: HttpRequest) -> Result<web::Json<Vec<Cached<Light>>>, Error> {
+    let interra = client(&req)?;
+    let room_id = room_id(&req, interra)?;
+    let lights = interra.room_lights(room_id, fresh(&req)?).await;
+    Ok(web::Json(lights?))
+}
+
+async fn light(req: HttpRequest) -> Result<web::Json<Cached<Light>>, Error> {
+    let interra = client(&req)?;
+    let room_id = room_id(&req, interra)?;
+    // aliases and plain ids work too, the answer uses the real name
+    let name = match interra.config().light_id(req.match_info().query("id")) {
+        Some(id) => interra.config().light_name(id),
         None => {
-            return Err(CustomError::internal_server_error(
-                "tcp client suffering, sorry!",
-            ))
+            return Err(InterraError::UnknownDevice("this is NOT a real ID".to_string()).into())
         }
     };
-    match lights
-        .into_iter()
-        .find(|v| v.id == req.match_info().query("id"))
-    {
+
+    let lights = interra.room_lights(room_id, fresh(&req)?).await?;
+    match lights.into_iter().find(|v| v.value.id == name) {
         Some(light) => Ok(web::Json(light)),
-        None => Err(CustomError::bad_request(
-            "this is NOT a real ID",
-        )),
+        None => {
+            Err(InterraError::UnknownDevice(format!("no light {name} in room {room_id}")).into())
+        }
     }
 }
+
+async fn switch_light(req: HttpRequest, data: web::Json<Value>) -> Result<web::Json<Light>, Error> {
+    let interra = client(&req)?;
+    let room_id = room_id(&req, interra)?;
+    let object_id = interra
+        .config()
+        .light_id(req.match_info().query("id"))
+        .ok_or_else(|| InterraError::UnknownDevice("this is NOT a real ID".to_string()))?;
+
+    let update = LightUpdate::deserialize(&*data)
+        .map_err(|e| InterraError::Validation(format!("terrible json. I am sorry ({e})")))?;
+    update
+        .validate()
+        .map_err(|e| InterraError::Validation(e.to_string()))?;
+
+    // only lights we know the room of can be caught in the wrong one
+    if let Some(room) = interra.config().room_of(object_id) {
+        if room != room_id {
+            return Err(CustomError::not_found(&format!(
+                "that light is in room {room}, not {room_id}"
+            )));
+        }
+    }
+
+    let light = interra.update_light(room_id, object_id, &update).await?;
+    Ok(web::Json(light))
+}
+
+async fn ac(req: HttpRequest) -> Result<web::Json<Cached<ACData>>, Error> {
+    let interra = client(&req)?;
+    let room_id = room_id(&req, interra)?;
+    ac_config(interra, room_id)?;
+    let ac = interra.ac_info(room_id, fresh(&req)?).await;
+    Ok(web::Json(ac?))
+}
+
+async fn set_ac_in(
+    req: HttpRequest,
+    data: web::Json<ACData>,
+) -> Result<web::Json<ACSetResult>, Error> {
+    let interra = client(&req)?;
+    let room_id = room_id(&req, interra)?;
+    data.validate(ac_config(interra, room_id)?)
+        .map_err(InterraError::Validation)?;
+    let result = interra.set_ac_info(room_id, &data).await;
+    Ok(web::Json(result?))
+}
+
+async fn covers(req: HttpRequest) -> Result<web::Json<Vec<Cached<Cover>>>, Error> {
+    let interra = client(&req)?;
+    let room_id = room_id(&req, interra)?;
+    let covers = interra.room_covers(room_id, fresh(&req)?).await;
+    Ok(web::Json(covers?))
+}
+
+// a cover by name/alias/id, looked up in the room the config puts it in (or the default one)
+async fn find_cover(
+    interra: &InterraTcpClient,
+    name: &str,
+    fresh: bool,
+) -> Result<(u16, Cached<Cover>), Error> {
+    let object_id = interra
+        .config()
+        .cover_id(name)
+        .ok_or_else(|| InterraError::UnknownDevice("this is NOT a real ID".to_string()))?;
+    let room_id = interra
+        .config()
+        .room_of(object_id)
+        .or_else(|| interra.config().default_room())
+        .ok_or_else(|| CustomError::not_found("no idea what room that's in"))?;
+
+    let name = interra.config().cover_name(object_id);
+    let cover = interra
+        .room_covers(room_id, fresh)
+        .await?
+        .into_iter()
+        .find(|c| c.value.id == name)
+        .ok_or_else(|| CustomError::not_found(&format!("no cover {name} in room {room_id}")))?;
+    Ok((object_id, cover))
+}
+
+#[get("/lights")]
+pub async fn get_lights(
+    req: HttpRequest,
+    _: Authorized,
+) -> Result<web::Json<Vec<Cached<Light>>>, Error> {
+    lights(req).await
+}
+
+#[get("/lights/{id}")]
+pub async fn get_light(req: HttpRequest, _: Authorized) -> Result<web::Json<Cached<Light>>, Error> {
+    light(req).await
+}
 #[patch("/lights/{id}")]
 pub async fn set_light(
     req: HttpRequest,
     data: web::Json<Value>,
     _: Authorized,
 ) -> Result<web::Json<Light>, Error> {
-    let id = req.match_info().get("id").ok_or(CustomError::bad_request(
-        "this is NOT a real ID",
-    ))?;
+    switch_light(req, data).await
+}
 
-    let active = match data.get("active") {
-        Some(active) => bool::deserialize(active)?,
-        None => {
-            return Err(CustomError::bad_request(
-                "terrible json. I am sorry",
-            ))
-        }
-    };
+#[get("/ac")]
+pub async fn get_ac(req: HttpRequest, _: Authorized) -> Result<web::Json<Cached<ACData>>, Error> {
+    ac(req).await
+}
+#[patch("/ac")]
+pub async fn set_ac(
+    req: HttpRequest,
+    data: web::Json<ACData>,
+    _: Authorized,
+) -> Result<web::Json<ACSetResult>, Error> {
+    set_ac_in(req, data).await
+}
 
-    let light = Light {
-        id: id.to_string(),
-        active,
-    };
+#[get("/rooms/{room_id}/lights")]
+pub async fn get_room_lights(
+    req: HttpRequest,
+    _: Authorized,
+) -> Result<web::Json<Vec<Cached<Light>>>, Error> {
+    lights(req).await
+}
 
-    // let x = req.app_data::<Data>().unwrap();
-    match req.app_data::<Data<InterraTcpClient>>() {
-        Some(interra) => {
-            interra.switch_light(light.id_u16()?, active).await?;
-            Ok(web::Json(light))
-        }
+#[get("/rooms/{room_id}/lights/{id}")]
+pub async fn get_room_light(
+    req: HttpRequest,
+    _: Authorized,
+) -> Result<web::Json<Cached<Light>>, Error> {
+    light(req).await
+}
+#[patch("/rooms/{room_id}/lights/{id}")]
+pub async fn set_room_light(
+    req: HttpRequest,
+    data: web::Json<Value>,
+    _: Authorized,
+) -> Result<web::Json<Light>, Error> {
+    switch_light(req, data).await
+}
+
+#[get("/rooms/{room_id}/ac")]
+pub async fn get_room_ac(
+    req: HttpRequest,
+    _: Authorized,
+) -> Result<web::Json<Cached<ACData>>, Error> {
+    ac(req).await
+}
+#[patch("/rooms/{room_id}/ac")]
+pub async fn set_room_ac(
+    req: HttpRequest,
+    data: web::Json<ACData>,
+    _: Authorized,
+) -> Result<web::Json<ACSetResult>, Error> {
+    set_ac_in(req, data).await
+}
+
+#[get("/covers")]
+pub async fn get_covers(
+    req: HttpRequest,
+    _: Authorized,
+) -> Result<web::Json<Vec<Cached<Cover>>>, Error> {
+    covers(req).await
+}
+
+#[get("/rooms/{room_id}/covers")]
+pub async fn get_room_covers(
+    req: HttpRequest,
+    _: Authorized,
+) -> Result<web::Json<Vec<Cached<Cover>>>, Error> {
+    covers(req).await
+}
+
+#[get("/covers/{id}")]
+pub async fn get_cover(req: HttpRequest, _: Authorized) -> Result<web::Json<Cached<Cover>>, Error> {
+    let interra = client(&req)?;
+    let (_, cover) = find_cover(interra, req.match_info().query("id"), fresh(&req)?).await?;
+    Ok(web::Json(cover))
+}
+#[patch("/covers/{id}")]
+pub async fn set_cover(
+    req: HttpRequest,
+    data: web::Json<Value>,
+    _: Authorized,
+) -> Result<HttpResponse, Error> {
+    let interra = client(&req)?;
+    let update = CoverUpdate::deserialize(&*data).map_err(|_| {
+        InterraError::Validation(
+            "terrible json. it's { \"action\": \"up\" | \"down\" | \"stop\" }".to_string(),
+        )
+    })?;
+    let (object_id, cover) = find_cover(interra, req.match_info().query("id"), false).await?;
+
+    // a read right after would only say where it was when it started moving
+    interra.move_cover(object_id, update.action).await?;
+    Ok(HttpResponse::Accepted().json(CoverMove {
+        id: cover.value.id,
+        action: update.action,
+    }))
+}
+
+fn scene_store(req: &HttpRequest) -> Result<&Data<SceneStore>, Error> {
+    req.app_data::<Data<SceneStore>>()
+        .ok_or_else(|| CustomError::internal_server_error("scenes suffering, sorry!"))
+}
+
+#[get("/scenes")]
+pub async fn get_scenes(
+    req: HttpRequest,
+    _: Authorized,
+) -> Result<web::Json<BTreeMap<String, Scene>>, Error> {
+    Ok(web::Json(scene_store(&req)?.list().await))
+}
+
+#[get("/scenes/{name}")]
+pub async fn get_scene(req: HttpRequest, _: Authorized) -> Result<web::Json<Scene>, Error> {
+    match scene_store(&req)?.get(req.match_info().query("name")).await {
+        Some(scene) => Ok(web::Json(scene)),
+        None => Err(CustomError::not_found("no scene called that")),
+    }
+}
+
+#[put("/scenes/{name}")]
+pub async fn put_scene(
+    req: HttpRequest,
+    data: web::Json<Value>,
+    _: Authorized,
+) -> Result<web::Json<Scene>, Error> {
+    let name = req.match_info().query("name");
+    if !scenes::valid_name(name) {
+        return Err(InterraError::Validation(
+            "scene names are letters, numbers, - and _ (64 max)".to_string(),
+        )
+        .into());
+    }
+    let scene = Scene::deserialize(&*data)
+        .map_err(|e| InterraError::Validation(format!("terrible json. I am sorry ({e})")))?;
+    scene
+        .validate(client(&req)?.config())
+        .map_err(|problems| InterraError::Validation(problems.join("; ")))?;
+
+    scene_store(&req)?.put(name, scene.clone()).await?;
+    Ok(web::Json(scene))
+}
+
+#[delete("/scenes/{name}")]
+pub async fn delete_scene(req: HttpRequest, _: Authorized) -> Result<web::Json<Example>, Error> {
+    match scene_store(&req)?
+        .delete(req.match_info().query("name"))
+        .await?
+    {
+        true => Ok(web::Json(Example {
+            message: "gone!".to_string(),
+        })),
+        false => Err(CustomError::not_found("no scene called that")),
+    }
+}
+
+#[post("/scenes/{name}/activate")]
+pub async fn activate_scene(
+    req: HttpRequest,
+    _: Authorized,
+) -> Result<web::Json<SceneReport>, Error> {
+    let name = req.match_info().query("name");
+    let scene = scene_store(&req)?
+        .get(name)
+        .await
+        .ok_or_else(|| CustomError::not_found("no scene called that"))?;
+
+    let steps = scene.apply(client(&req)?).await;
+    Ok(web::Json(SceneReport {
+        scene: name.to_string(),
+        ok: steps.iter().all(|s| s.ok),
+        steps,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct Capture {
+    name: String,
+    room: Option<u16>,
+}
+
+#[post("/scenes/capture")]
+pub async fn capture_scene(
+    req: HttpRequest,
+    data: web::Json<Capture>,
+    _: Authorized,
+) -> Result<web::Json<Scene>, Error> {
+    let interra = client(&req)?;
+    if !scenes::valid_name(&data.name) {
+        return Err(InterraError::Validation(
+            "scene names are letters, numbers, - and _ (64 max)".to_string(),
+        )
+        .into());
+    }
+    let room_id = data
+        .room
+        .or_else(|| interra.config().default_room())
+        .ok_or_else(|| InterraError::Validation("which room though".to_string()))?;
+
+    let scene = Scene::capture(interra, room_id).await?;
+    if !scene_store(&req)?.insert(&data.name, scene.clone()).await? {
+        return Err(CustomError::conflict("there's already a scene called that"));
+    }
+    Ok(web::Json(scene))
+}
+
+fn scheduler(req: &HttpRequest) -> Result<&Data<Scheduler>, Error> {
+    req.app_data::<Data<Scheduler>>()
+        .ok_or_else(|| CustomError::internal_server_error("scheduler suffering, sorry!"))
+}
+
+#[get("/schedules")]
+pub async fn get_schedules(
+    req: HttpRequest,
+    _: Authorized,
+) -> Result<web::Json<BTreeMap<String, Schedule>>, Error> {
+    Ok(web::Json(scheduler(&req)?.list().await))
+}
+
+#[get("/schedules/{id}")]
+pub async fn get_schedule(req: HttpRequest, _: Authorized) -> Result<web::Json<Schedule>, Error> {
+    match scheduler(&req)?.get(req.match_info().query("id")).await {
+        Some(schedule) => Ok(web::Json(schedule)),
+        None => Err(CustomError::not_found("no schedule called that")),
+    }
+}
+
+#[put("/schedules/{id}")]
+pub async fn put_schedule(
+    req: HttpRequest,
+    data: web::Json<Value>,
+    _: Authorized,
+) -> Result<web::Json<Schedule>, Error> {
+    let id = req.match_info().query("id");
+    if !scenes::valid_name(id) {
+        return Err(InterraError::Validation(
+            "schedule ids are letters, numbers, - and _ (64 max)".to_string(),
+        )
+        .into());
+    }
+    let schedule = Schedule::deserialize(&*data)
+        .map_err(|e| InterraError::Validation(format!("terrible json. I am sorry ({e})")))?;
+    let scheduler = scheduler(&req)?;
+    scheduler
+        .validate(&schedule, client(&req)?.config())
+        .await
+        .map_err(|problems| InterraError::Validation(problems.join("; ")))?;
+
+    Ok(web::Json(scheduler.put(id, schedule).await?))
+}
+
+#[delete("/schedules/{id}")]
+pub async fn delete_schedule(req: HttpRequest, _: Authorized) -> Result<web::Json<Example>, Error> {
+    match scheduler(&req)?
+        .delete(req.match_info().query("id"))
+        .await?
+    {
+        true => Ok(web::Json(Example {
+            message: "gone!".to_string(),
+        })),
+        false => Err(CustomError::not_found("no schedule called that")),
+    }
+}
+
+fn rule_engine(req: &HttpRequest) -> Result<&Data<RuleEngine>, Error> {
+    req.app_data::<Data<RuleEngine>>()
+        .ok_or_else(|| CustomError::internal_server_error("rules suffering, sorry!"))
+}
+
+#[get("/rules")]
+pub async fn get_rules(
+    req: HttpRequest,
+    _: Authorized,
+) -> Result<web::Json<BTreeMap<String, Rule>>, Error> {
+    Ok(web::Json(rule_engine(&req)?.list().await))
+}
+
+#[get("/rules/{id}")]
+pub async fn get_rule(req: HttpRequest, _: Authorized) -> Result<web::Json<Rule>, Error> {
+    match rule_engine(&req)?.get(req.match_info().query("id")).await {
+        Some(rule) => Ok(web::Json(rule)),
+        None => Err(CustomError::not_found("no rule called that")),
+    }
+}
+
+#[put("/rules/{id}")]
+pub async fn put_rule(
+    req: HttpRequest,
+    data: web::Json<Value>,
+    _: Authorized,
+) -> Result<web::Json<Rule>, Error> {
+    let id = req.match_info().query("id");
+    if !scenes::valid_name(id) {
+        return Err(InterraError::Validation(
+            "rule ids are letters, numbers, - and _ (64 max)".to_string(),
+        )
+        .into());
+    }
+    let rule = Rule::deserialize(&*data)
+        .map_err(|e| InterraError::Validation(format!("terrible json. I am sorry ({e})")))?;
+    let engine = rule_engine(&req)?;
+    engine
+        .validate(&rule)
+        .await
+        .map_err(|problems| InterraError::Validation(problems.join("; ")))?;
+
+    Ok(web::Json(engine.put(id, rule).await?))
+}
+
+#[delete("/rules/{id}")]
+pub async fn delete_rule(req: HttpRequest, _: Authorized) -> Result<web::Json<Example>, Error> {
+    match rule_engine(&req)?
+        .delete(req.match_info().query("id"))
+        .await?
+    {
+        true => Ok(web::Json(Example {
+            message: "gone!".to_string(),
+        })),
+        false => Err(CustomError::not_found("no rule called that")),
+    }
+}
+
+#[get("/rules/{id}/log")]
+pub async fn get_rule_log(
+    req: HttpRequest,
+    _: Authorized,
+) -> Result<web::Json<Vec<Evaluation>>, Error> {
+    let engine = rule_engine(&req)?;
+    let id = req.match_info().query("id");
+    if engine.get(id).await.is_none() {
+        return Err(CustomError::not_found("no rule called that"));
+    }
+    Ok(web::Json(engine.log(id)))
+}
+
+#[get("/events")]
+pub async fn events(req: HttpRequest, _: Authorized) -> Result<HttpResponse, Error> {
+    let last_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok());
+
+    match req.app_data::<Data<EventFeed>>() {
+        Some(feed) => Ok(HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .insert_header(("Cache-Control", "no-cache"))
+            .streaming(feed.sse(last_id))),
         None => Err(CustomError::internal_server_error(
-            "tcp client suffering, sorry!",
+            "event feed suffering, sorry!",
         )),
     }
 }
 
-#[get("/ac")]
-pub async fn get_ac(req: HttpRequest, _: Authorized) -> Result<web::Json<ACData>, Error> {
-    match req.app_data::<Data<InterraTcpClient>>() {
-        Some(interra) => Ok(web::Json(interra.get_ac_info(12).await?)),
-        None => {
-            Err(CustomError::internal_server_error(
-                "tcp client suffering, sorry!",
-            ))
-        }
+#[get("/ws")]
+pub async fn websocket(
+    req: HttpRequest,
+    body: web::Payload,
+    _: Authorized,
+) -> Result<HttpResponse, Error> {
+    let (Some(interra), Some(feed)) = (
+        req.app_data::<Data<InterraTcpClient>>(),
+        req.app_data::<Data<EventFeed>>(),
+    ) else {
+        return Err(CustomError::internal_server_error(
+            "tcp client suffering, sorry!",
+        ));
+    };
+    let (interra, feed) = (interra.clone(), feed.clone());
+
+    let (response, session, messages) = actix_ws::handle(&req, body)?;
+    actix_web::rt::spawn(ws::serve(session, messages, interra, feed));
+
+    Ok(response)
+}
+
+#[get("/rooms")]
+pub async fn get_rooms(req: HttpRequest, _: Authorized) -> Result<web::Json<Vec<Room>>, Error> {
+    match req.app_data::<Data<Discovery>>() {
+        Some(discovery) => Ok(web::Json(
+            discovery.registry().await.rooms.into_values().collect(),
+        )),
+        None => Err(CustomError::internal_server_error(
+            "discovery suffering, sorry!",
+        )),
     }
 }
-#[patch("/ac")]
-pub async fn set_ac(
+
+#[derive(Deserialize)]
+pub struct DeviceFilter {
+    room: Option<u16>,
+    #[serde(rename = "type")]
+    object_type: Option<DeviceType>,
+}
+
+#[get("/devices")]
+pub async fn get_devices(
     req: HttpRequest,
-    data: web::Json<ACData>,
+    filter: web::Query<DeviceFilter>,
     _: Authorized,
-) -> Result<web::Json<ACData>, Error> {
-    if let Some(t) = data.set_temp {
-        if t > 25 {
-            return Err(CustomError::bad_request(
-                "sorry, 25 is the max temp!",
-            ));
-        } else if t < 20 {
-            return Err(CustomError::bad_request(
-                "sorry, 20 is the min temp!",
-            ));
+) -> Result<web::Json<Vec<Device>>, Error> {
+    let registry = match req.app_data::<Data<Discovery>>() {
+        Some(discovery) => discovery.registry().await,
+        None => {
+            return Err(CustomError::internal_server_error(
+                "discovery suffering, sorry!",
+            ))
         }
-    }
+    };
 
-    match req.app_data::<Data<InterraTcpClient>>() {
-        Some(interra) => Ok(web::Json(interra.set_ac_info_room12(&data).await?)),
+    Ok(web::Json(
+        registry
+            .devices()
+            .filter(|d| filter.room.is_none_or(|room| d.room_id == room))
+            .filter(|d| filter.object_type.is_none_or(|t| d.object_type == t))
+            .cloned()
+            .collect(),
+    ))
+}
+
+#[post("/devices/discover")]
+pub async fn discover(req: HttpRequest, _: Authorized) -> Result<web::Json<DeviceRegistry>, Error> {
+    match req.app_data::<Data<Discovery>>() {
+        Some(discovery) => Ok(web::Json(discovery.discover().await?)),
         None => Err(CustomError::internal_server_error(
-            "tcp client suffering, sorry!",
+            "discovery suffering, sorry!",
         )),
     }
 }
+
+#[get("/metrics")]
+pub async fn get_metrics(req: HttpRequest, _: Authorized) -> Result<HttpResponse, Error> {
+    let interra = client(&req)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::render(interra)))
+}
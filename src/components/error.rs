@@ -0,0 +1,154 @@
+use crate::components::serde_models::CustomError;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde_json::Value;
+use std::{fmt, io};
+
+pub type Result<T> = std::result::Result<T, InterraError>;
+
+// errorCodes that mean the session is gone. only these count, whatever the message says: a
+// session error gets the action sent again, so guessing wrong would do it twice
+const SESSION_ERROR_CODES: [i64; 2] = [401, 403];
+
+/// Everything that can go wrong talking to the gateway, and whose fault it was.
+#[derive(Debug, Clone)]
+pub enum InterraError {
+    /// something the config (or the env) was supposed to say and doesn't
+    ConfigMissing(String),
+    /// no link to the gateway, or it broke under us
+    Connect(String),
+    /// the gateway didn't like the username/password
+    AuthRejected(String),
+    /// the gateway answered with `meta.error` / `meta.errorCode` set
+    Gateway {
+        code: Option<Value>,
+        message: String,
+    },
+    /// the gateway said something that isn't a frame we understand
+    MalformedFrame(String),
+    Timeout(String),
+    UnknownDevice(String),
+    /// the request makes no sense for this device
+    Validation(String),
+}
+
+impl InterraError {
+    /// What goes in the `code` of the error body, for whoever's handling it in code.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::ConfigMissing(_) => "config_missing",
+            Self::Connect(_) => "connect_failed",
+            Self::AuthRejected(_) => "auth_rejected",
+            Self::Gateway { .. } => "gateway_error",
+            Self::MalformedFrame(_) => "malformed_frame",
+            Self::Timeout(_) => "timeout",
+            Self::UnknownDevice(_) => "unknown_device",
+            Self::Validation(_) => "validation_failed",
+        }
+    }
+
+    /// The error a reply carries, if it carries one. Null and 0 both mean it went fine.
+    pub fn from_meta(error: Option<&Value>, code: Option<&Value>) -> Option<Self> {
+        fn set(v: Option<&Value>) -> Option<&Value> {
+            v.filter(|v| !v.is_null() && **v != 0)
+        }
+        let (error, code) = (set(error), set(code));
+        if error.is_none() && code.is_none() {
+            return None;
+        }
+
+        let message = match error {
+            Some(Value::String(message)) => message.clone(),
+            Some(other) => other.to_string(),
+            None => "interra said no, didn't say why".to_string(),
+        };
+        Some(Self::Gateway {
+            code: code.cloned(),
+            message,
+        })
+    }
+
+    /// Whether the gateway is saying it doesn't know who we are (anymore).
+    pub fn is_auth(&self) -> bool {
+        match self {
+            Self::AuthRejected(_) => true,
+            // numbers, or numbers in a string
+            Self::Gateway { code, .. } => code
+                .as_ref()
+                .and_then(|code| code.as_i64().or_else(|| code.as_str()?.parse().ok()))
+                .is_some_and(|code| SESSION_ERROR_CODES.contains(&code)),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for InterraError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Gateway {
+                code: Some(code),
+                message,
+            } => write!(f, "{message} (error code {code})"),
+            Self::Gateway { message, .. }
+            | Self::ConfigMissing(message)
+            | Self::Connect(message)
+            | Self::AuthRejected(message)
+            | Self::MalformedFrame(message)
+            | Self::Timeout(message)
+            | Self::UnknownDevice(message)
+            | Self::Validation(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for InterraError {}
+
+impl ResponseError for InterraError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::ConfigMissing(_) | Self::Connect(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::AuthRejected(_) => StatusCode::UNAUTHORIZED,
+            Self::Gateway { .. } | Self::MalformedFrame(_) => StatusCode::BAD_GATEWAY,
+            Self::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Self::UnknownDevice(_) => StatusCode::NOT_FOUND,
+            Self::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(CustomError {
+            message: self.to_string(),
+            code: Some(self.code().to_string()),
+        })
+    }
+}
+
+// socket trouble
+impl From<io::Error> for InterraError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut => Self::Timeout(e.to_string()),
+            _ => Self::Connect(e.to_string()),
+        }
+    }
+}
+
+impl From<serde_json::Error> for InterraError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::MalformedFrame(e.to_string())
+    }
+}
+
+// for the places that only deal in io errors, like startup
+impl From<InterraError> for io::Error {
+    fn from(e: InterraError) -> Self {
+        let kind = match e {
+            InterraError::Timeout(_) => io::ErrorKind::TimedOut,
+            InterraError::UnknownDevice(_) => io::ErrorKind::NotFound,
+            InterraError::Validation(_) => io::ErrorKind::InvalidInput,
+            InterraError::Connect(_) => io::ErrorKind::NotConnected,
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, e)
+    }
+}
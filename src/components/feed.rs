@@ -0,0 +1,194 @@
+use crate::components::config::Config;
+use crate::components::interra::InterraTcpClient;
+use crate::components::protocol::DeviceType;
+use crate::components::serde_models::{ACData, ACDatum, DeviceEvent, Light};
+use actix_web::web::{Bytes, Data};
+use futures_util::stream::{self, Stream, StreamExt};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time;
+
+// how far back a client can resume with Last-Event-ID
+const BACKLOG: usize = 256;
+// proxies like to kill quiet connections
+const PING_EVERY: Duration = Duration::from_secs(15);
+
+/// A device changed, in the same shape the REST endpoints hand out (plus the room it's in).
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", content = "data", rename_all = "camelCase")]
+pub enum StateChange {
+    Light(InRoom<Light>),
+    Ac(InRoom<ACData>),
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct InRoom<T> {
+    pub room: u16,
+    #[serde(flatten)]
+    pub state: T,
+}
+
+impl StateChange {
+    /// e.g. `rooms/12/lights/ceilingLights` or `rooms/12/ac`
+    pub fn topic(&self) -> String {
+        match self {
+            Self::Light(light) => format!("rooms/{}/lights/{}", light.room, light.state.id),
+            Self::Ac(ac) => format!("rooms/{}/ac", ac.room),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FeedEntry {
+    pub id: u64,
+    pub change: StateChange,
+}
+
+impl FeedEntry {
+    fn to_sse(&self) -> Bytes {
+        let event = match self.change {
+            StateChange::Light(_) => "light",
+            StateChange::Ac(_) => "ac",
+        };
+        let data = serde_json::to_string(&self.change).unwrap_or_default();
+        Bytes::from(format!("id: {}\nevent: {event}\ndata: {data}\n\n", self.id))
+    }
+}
+
+#[derive(Default)]
+struct Backlog {
+    next_id: u64,
+    entries: VecDeque<FeedEntry>,
+}
+
+/// Turns raw gateway push events into light / ac changes, numbers them and keeps the last few
+/// around so SSE clients can pick up where they left off.
+pub struct EventFeed {
+    backlog: Mutex<Backlog>,
+    tx: broadcast::Sender<FeedEntry>,
+}
+
+impl EventFeed {
+    pub fn start(interra: Data<InterraTcpClient>) -> Data<Self> {
+        let (tx, _) = broadcast::channel(BACKLOG);
+        let feed = Data::new(Self {
+            backlog: Mutex::new(Backlog {
+                next_id: 1,
+                ..Backlog::default()
+            }),
+            tx,
+        });
+
+        let feed_loop = feed.clone();
+        let mut events = interra.subscribe();
+        tokio::spawn(async move {
+            // the gateway repeats itself, only pass on actual changes
+            let mut last_seen: HashMap<u16, (bool, Option<String>)> = HashMap::new();
+
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(n)) => {
+                        tracing::warn!(lost = n, "event feed fell behind");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+
+                let state = (event.active, event.read_value.clone());
+                if last_seen.get(&event.object_id) == Some(&state) {
+                    continue;
+                }
+                last_seen.insert(event.object_id, state);
+
+                if let Some(change) = Self::translate(interra.config(), &event) {
+                    feed_loop.publish(change);
+                }
+            }
+        });
+
+        feed
+    }
+
+    fn translate(config: &Config, event: &DeviceEvent) -> Option<StateChange> {
+        if let Some(light) = config.light(event.object_id) {
+            return Some(StateChange::Light(InRoom {
+                room: config.room_of(light.id)?,
+                state: Light {
+                    id: light.name.clone(),
+                    active: event.active,
+                    brightness: match light.object_type {
+                        DeviceType::Dimmers => Light::brightness_from(event.read_value.as_deref()),
+                        _ => None,
+                    },
+                },
+            }));
+        }
+
+        // a push only carries one object, so this is a partial ACData with just that field set
+        let (room, ac_config) = config.ac_reading(event.object_id)?;
+        let ac = ACData::read(&[ACDatum::from(event)], ac_config);
+        (!ac.is_empty()).then_some(StateChange::Ac(InRoom { room, state: ac }))
+    }
+
+    pub fn publish(&self, change: StateChange) {
+        let mut backlog = self.backlog.lock().unwrap();
+        let entry = FeedEntry {
+            id: backlog.next_id,
+            change,
+        };
+        backlog.next_id += 1;
+
+        if backlog.entries.len() == BACKLOG {
+            backlog.entries.pop_front();
+        }
+        backlog.entries.push_back(entry.clone());
+
+        // sent while holding the backlog so `since` can't miss or double up on this one
+        _ = self.tx.send(entry);
+    }
+
+    /// Everything after `last_id` that's still in the backlog, plus a receiver for what comes next.
+    pub fn since(&self, last_id: Option<u64>) -> (Vec<FeedEntry>, broadcast::Receiver<FeedEntry>) {
+        let backlog = self.backlog.lock().unwrap();
+        let missed = match last_id {
+            Some(last_id) => backlog
+                .entries
+                .iter()
+                .filter(|e| e.id > last_id)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+
+        (missed, self.tx.subscribe())
+    }
+
+    /// The feed as a `text/event-stream` body.
+    pub fn sse(&self, last_id: Option<u64>) -> impl Stream<Item = Result<Bytes, Infallible>> {
+        let (missed, rx) = self.since(last_id);
+        let mut ping = time::interval(PING_EVERY);
+        ping.reset();
+
+        let replay = stream::iter(missed.into_iter().map(|e| Ok(e.to_sse())));
+        let live = stream::unfold((rx, ping), |(mut rx, mut ping)| async move {
+            let chunk = tokio::select! {
+                entry = rx.recv() => match entry {
+                    Ok(entry) => entry.to_sse(),
+                    // lagged or shut down: end the stream, the browser reconnects with
+                    // Last-Event-ID and gets the gap from the backlog
+                    Err(_) => return None,
+                },
+                _ = ping.tick() => Bytes::from_static(b": ping\n\n"),
+            };
+            Some((Ok(chunk), (rx, ping)))
+        });
+
+        replay.chain(live)
+    }
+}
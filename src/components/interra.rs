@@ -1,312 +1,1430 @@
-use crate::components::serde_models::{ACData, ACDatum, FanSpeed, Light};
+use crate::components::cache::{Cached, StateCache};
+use crate::components::config::{AcConfig, Config};
+use crate::components::connection::{backoff, ConnectionState, ConnectionStatus, GatewayConfig};
+use crate::components::error::{InterraError, Result};
+use crate::components::logging::Frame;
+use crate::components::metrics::Metrics;
+use crate::components::protocol::{
+    ActionData, ActionType, AuthData, DeviceType, InterraFrame, Meta, RequestType, RoomQuery,
+    KEEP_ALIVE,
+};
+use crate::components::queue::{Command, CommandQueue, Lane, Sender};
+use crate::components::recording::{self, Direction, Recorder};
+use crate::components::serde_models::{
+    ACData, ACDatum, ACSetResult, Cover, CoverAction, DeviceEvent, Light, LightUpdate, RoomObject,
+};
+use chrono::Utc;
+use serde::Serialize;
 use serde_json::Value;
-use std::io::ErrorKind;
-use std::time::Duration;
-use std::{env, io};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter, Result};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
 use tokio::net::TcpStream;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Mutex, RwLock};
+use tokio::task::JoinHandle;
 use tokio::time;
+use tracing::{debug, info, instrument, warn, Instrument, Span};
+
+// how many push events a slow subscriber can fall behind before it starts missing them
+const EVENT_BACKLOG: usize = 256;
+// how often a fading dimmer gets a new level
+const FADE_STEP: Duration = Duration::from_millis(250);
+// how long a request hangs around for the link to come (back) up before giving up
+const READY_WAIT: Duration = Duration::from_secs(5);
+// how long an ac change keeps re-reading and retrying before settling for what it's got
+const AC_DEADLINE: Duration = Duration::from_secs(10);
+// how long the ac gets to catch up between readbacks after being pressed
+const AC_SETTLE: Duration = Duration::from_millis(300);
+
+// enough for any recording's worth of frames in flight
+const REPLAY_PIPE: usize = 64 * 1024;
+
+// either end of a link, whether that's a socket or a replayed recording
+type LinkReader = BufReader<Box<dyn AsyncRead + Send + Unpin>>;
+type LinkWriter = BufWriter<Box<dyn AsyncWrite + Send + Unpin>>;
+
+/// What the client talks to.
+#[derive(Debug, Clone)]
+pub enum Source {
+    Gateway(GatewayConfig),
+    /// a file written by the recorder, played back instead of a real gateway
+    Replay(PathBuf),
+}
+
+// callers waiting on a reply, oldest first. the gateway answers in order, so the reader hands
+// each reply to the first waiter expecting that request type (or the first keep-alive if the
+// reply doesn't say what it is). that replies carry the requestType they answer (500 for the
+// login) is assumed, nobody has a capture of it
+type Waiters = Arc<StdMutex<VecDeque<Waiter>>>;
+
+struct Waiter {
+    id: u64,
+    expects: Option<RequestType>,
+    tx: oneshot::Sender<InterraFrame>,
+    // whoever's waiting, so the reply gets logged under their request and not just the link's
+    span: Span,
+}
+
+// "link number `generation` is done for", sent to the supervisor
+struct LinkLost {
+    generation: u64,
+    state: ConnectionState,
+    reason: String,
+}
 
 pub struct InterraTcpClient {
-    sink: Mutex<BufWriter<OwnedWriteHalf>>,
-    stream: Mutex<BufReader<OwnedReadHalf>>,
+    source: Source,
+    config: Arc<Config>,
+    recorder: Option<Recorder>,
+    // None while there's no link
+    sink: Mutex<Option<LinkWriter>>,
+    // bumped every time a new link goes in, so stale failures can be told apart
+    generation: AtomicU64,
+    waiters: Waiters,
+    next_waiter: AtomicU64,
+    reader: StdMutex<Option<JoinHandle<()>>>,
     token: RwLock<String>,
+    events: broadcast::Sender<DeviceEvent>,
+    status: watch::Sender<ConnectionStatus>,
+    lost: mpsc::UnboundedSender<LinkLost>,
+    // only one connect attempt at a time, whether it's the supervisor or /restart
+    connecting: Mutex<()>,
+    // and one re-login, however many requests found out the session was gone
+    reauthing: Mutex<()>,
+    // every action goes out through here
+    queue: CommandQueue,
+    cache: Arc<StateCache>,
+    metrics: Metrics,
+    // for the background refreshes the cache asks for, and the reader
+    me: Weak<Self>,
 }
 
 impl InterraTcpClient {
-    async fn establish() -> Result<(BufWriter<OwnedWriteHalf>, BufReader<OwnedReadHalf>, String)> {
-        let ip = env::var("TCP_IP")
-            .map_err(|_| io::Error::new(ErrorKind::Other, "TCP_IP not supplied in .env"))?;
-        let port = env::var("PORT")
-            .map_err(|_| io::Error::new(ErrorKind::Other, "PORT not supplied in .env"))?
-            .parse::<u16>().map_err(|_| io::Error::new(ErrorKind::Other, "this is not a port"))?;
-        let username = env::var("USERNAME")
-            .map_err(|_| io::Error::new(ErrorKind::Other, "USERNAME not supplied in .env"))?;
-        let password = env::var("PASSWORD")
-            .map_err(|_| io::Error::new(ErrorKind::Other, "PASSWORD not supplied in .env"))?;
+    /// Creates the client and starts connecting in the background. Never fails, if the gateway
+    /// is down the supervisor just keeps trying (see [`Self::status`]).
+    pub fn start(gateway: GatewayConfig, config: Config) -> Arc<Self> {
+        let recorder = gateway.record.clone().map(Recorder::create);
+        Self::spawn(Source::Gateway(gateway), config, recorder)
+    }
 
-        let (read, write) = TcpStream::connect((ip, port)).await?.into_split();
+    /// A client fed from a recording (see [`Recorder`]) instead of a socket.
+    pub fn replay(path: impl Into<PathBuf>, config: Config) -> Arc<Self> {
+        Self::spawn(Source::Replay(path.into()), config, None)
+    }
 
-        let mut reader = BufReader::new(read);
-        let mut writer = BufWriter::new(write);
+    fn spawn(source: Source, config: Config, recorder: Option<Recorder>) -> Arc<Self> {
+        let (events, _) = broadcast::channel(EVENT_BACKLOG);
+        let (status, _) = watch::channel(ConnectionStatus::default());
+        let (lost, lost_rx) = mpsc::unbounded_channel();
 
-        println!("Authenticating...");
+        let config = Arc::new(config);
 
-        let payload = format!("{{'data':{{'userName':'{username}','password':'{password}'}},'meta':{{'authID':null,'content_type':null,'error':null,'errorCode':null,'flags':null,'requestType':500,'scheme':null,'serverDateTime':null,'server_version':null,'version':null}}}}\n");
+        let client = Arc::new_cyclic(|me: &Weak<Self>| {
+            let client = me.clone();
+            // the queue sends through the client it belongs to
+            let send: Sender = Arc::new(move |action| {
+                let me = client.clone();
+                Box::pin(async move {
+                    match me.upgrade() {
+                        Some(client) => client.act(&action).await,
+                        None => Err(InterraError::Connect("the client is gone".to_string())),
+                    }
+                })
+            });
 
-        writer.write(payload.as_bytes()).await?;
-        writer.flush().await?;
+            Self {
+                source,
+                queue: CommandQueue::new(config.clone(), send),
+                cache: Arc::new(StateCache::new(config.cache.max_age())),
+                metrics: Metrics::default(),
+                me: me.clone(),
+                config,
+                recorder,
+                sink: Mutex::new(None),
+                generation: AtomicU64::new(0),
+                waiters: Waiters::default(),
+                next_waiter: AtomicU64::new(0),
+                reader: StdMutex::new(None),
+                token: RwLock::new(String::new()),
+                events,
+                status,
+                lost,
+                connecting: Mutex::new(()),
+                reauthing: Mutex::new(()),
+            }
+        });
 
-        let mut token = String::new();
-        reader.read_line(&mut token).await?;
-        println!("TCP Listener >> {}", token.trim());
-        token = serde_json::from_str::<Value>(&token)?["meta"]["authID"].to_string();
+        tokio::spawn(Self::supervise(Arc::downgrade(&client), lost_rx));
+        client
+    }
 
-        println!("Connected.");
+    /// The rooms and devices this client was set up with.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
 
-        Ok((writer, reader, token))
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
     }
 
-    pub async fn connect() -> Result<Self> {
-        println!("Connecting to Interra...");
-        let (w, r, token) = Self::establish().await?;
+    pub fn status(&self) -> ConnectionStatus {
+        self.status.borrow().clone()
+    }
 
-        Ok(Self {
-            sink: Mutex::new(w),
-            stream: Mutex::new(r),
-            token: RwLock::new(token),
-        })
+    pub fn state(&self) -> ConnectionState {
+        self.status.borrow().state
     }
 
-    pub async fn reconnect(&self) -> Result<()> {
-        println!("Reconnecting...");
-        let (w, r, token) = Self::establish().await?;
+    fn set_state(&self, state: ConnectionState, error: Option<String>) {
+        self.status.send_modify(|status| {
+            if status.state != state {
+                info!(from = ?status.state, to = ?state, "interra link changed state");
+                status.since = Utc::now();
+            }
+            status.state = state;
+            if error.is_some() {
+                status.last_error = error;
+            }
+        });
+    }
+
+    /// Waits until the link is ready, or fails after `timeout`.
+    pub async fn wait_ready(&self, timeout: Duration) -> Result<()> {
+        let mut status = self.status.subscribe();
+        let ready = time::timeout(
+            timeout,
+            status.wait_for(|s| s.state == ConnectionState::Ready),
+        )
+        .await
+        .is_ok_and(|r| r.is_ok());
+
+        if ready {
+            return Ok(());
+        }
+        Err(InterraError::Connect(format!(
+            "interra isn't there right now ({:?})",
+            self.state()
+        )))
+    }
+
+    // keeps the link up: connects, waits for it to break, backs off, tries again
+    async fn supervise(client: Weak<Self>, mut lost: mpsc::UnboundedReceiver<LinkLost>) {
+        let mut attempt = 0;
 
-        *self.sink.lock().await = w;
-        *self.stream.lock().await = r;
+        loop {
+            let Some(strong) = client.upgrade() else {
+                return;
+            };
+
+            if !matches!(strong.state(), ConnectionState::Ready) {
+                if let Err(e) = strong.try_connect().await {
+                    let wait = backoff(attempt);
+                    warn!(error = %e, retry_in = ?wait, "couldn't reach interra");
+                    attempt = attempt.saturating_add(1);
+                    drop(strong);
+                    time::sleep(wait).await;
+                    continue;
+                }
+                attempt = 0;
+            }
+            drop(strong);
+
+            // the client owns the sender, so this only ends when the client is gone
+            let Some(lost) = lost.recv().await else {
+                return;
+            };
+            let Some(strong) = client.upgrade() else {
+                return;
+            };
+            if lost.generation == strong.generation.load(Ordering::SeqCst) {
+                warn!(reason = %lost.reason, "interra link lost");
+                strong.set_state(lost.state, Some(lost.reason));
+            }
+        }
+    }
+
+    // tells the supervisor the link that was current at `generation` is broken
+    fn link_lost(&self, generation: u64, state: ConnectionState, reason: String) {
+        _ = self.lost.send(LinkLost {
+            generation,
+            state,
+            reason,
+        });
+    }
+
+    fn record(&self, direction: Direction, line: &str) {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(direction, line);
+        }
+    }
+
+    fn credentials(&self) -> AuthData {
+        match &self.source {
+            Source::Gateway(config) => AuthData {
+                username: config.username.clone(),
+                password: config.password.clone(),
+            },
+            // the recording has the password redacted anyway
+            Source::Replay(_) => AuthData {
+                username: "replay".to_string(),
+                password: "replay".to_string(),
+            },
+        }
+    }
+
+    async fn open(&self) -> Result<(LinkReader, LinkWriter)> {
+        match &self.source {
+            Source::Gateway(config) => {
+                let connect = TcpStream::connect((config.host.as_str(), config.port));
+                let (read, write) = within(self.config.timeouts.connect(), "to connect", connect)
+                    .await?
+                    .into_split();
+                Ok((
+                    BufReader::new(Box::new(read)),
+                    BufWriter::new(Box::new(write)),
+                ))
+            }
+            Source::Replay(path) => {
+                let records = recording::load(path).await?;
+                let (pipe, gateway) = tokio::io::duplex(REPLAY_PIPE);
+                tokio::spawn(recording::replay(records, gateway));
+
+                let (read, write) = tokio::io::split(pipe);
+                Ok((
+                    BufReader::new(Box::new(read)),
+                    BufWriter::new(Box::new(write)),
+                ))
+            }
+        }
+    }
+
+    async fn establish(&self) -> Result<(LinkWriter, LinkReader, String)> {
+        self.set_state(ConnectionState::Connecting, None);
+        let (mut reader, mut writer) = self.open().await?;
+
+        self.set_state(ConnectionState::Authenticating, None);
+
+        let payload = InterraFrame::new(RequestType::Auth, &self.credentials(), None)?.to_line()?;
+
+        let login = async {
+            writer.write_all(payload.as_bytes()).await?;
+            writer.flush().await
+        };
+        within(self.config.timeouts.write(), "to take the login", login).await?;
+        self.record(Direction::Out, &payload);
+
+        let mut line = String::new();
+        within(
+            self.config.timeouts.response(),
+            "to answer the login",
+            reader.read_line(&mut line),
+        )
+        .await?;
+        self.record(Direction::In, &line);
+        debug!(frame = %Frame(&line), "login answered");
+        let token = session(InterraFrame::parse(&line)?.meta)?;
+
+        Ok((writer, reader, token))
+    }
+
+    // one attempt at bringing up a fresh link, replacing whatever is there
+    #[instrument(name = "connect", skip_all, fields(generation))]
+    async fn try_connect(&self) -> Result<()> {
+        let _connecting = self.connecting.lock().await;
+        info!("connecting to interra");
+        let before = self.state();
+
+        let (w, r, token) = match self.establish().await {
+            Ok(link) => link,
+            Err(e) => {
+                if matches!(e, InterraError::AuthRejected(_)) {
+                    self.status.send_modify(|status| status.auth_failures += 1);
+                }
+                // a /restart that didn't work out leaves the link we had alone, so if that one's
+                // still up it's still what we're on
+                let state = match before {
+                    ConnectionState::Ready | ConnectionState::Degraded if self.listening() => {
+                        before
+                    }
+                    _ => ConnectionState::Disconnected,
+                };
+                self.set_state(state, Some(e.to_string()));
+                return Err(e);
+            }
+        };
+
+        // hold the sink so nobody writes on the new link before the reader is listening on it
+        let mut sink = self.sink.lock().await;
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        Span::current().record("generation", generation);
+        *sink = Some(w);
         *self.token.write().await = token;
 
+        let link = tracing::info_span!(parent: None, "link", generation);
+        let reader = tokio::spawn(Self::listen(self.me.clone(), generation, r).instrument(link));
+        if let Some(old) = self.reader.lock().unwrap().replace(reader) {
+            old.abort();
+        }
+        // anything still waiting asked the old link, it's never getting an answer
+        self.waiters.lock().unwrap().clear();
+        // and whatever got pushed while there was no link went nowhere
+        self.cache.forget();
+
+        let first = generation == 1;
+        self.status.send_modify(|status| {
+            if !first {
+                status.reconnects += 1;
+            }
+        });
+        self.set_state(ConnectionState::Ready, None);
+        info!("connected");
+
         Ok(())
     }
 
-    // todo rm maybe
-    pub async fn keep_alive(&self) -> Result<()> {
-        // PREVENT R/W ATTEMPTS WHILE KEEPALIVE IN PROGRESS
-        let mut lock_sink = self.sink.lock().await;
-        let mut lock_stream = self.stream.lock().await;
+    // whether the current link's reader is still going, i.e. it hasn't seen the link close
+    fn listening(&self) -> bool {
+        self.reader
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|reader| !reader.is_finished())
+    }
 
-        lock_sink.write_all(b"{}\n").await?;
-        if let Err(_) = lock_sink.flush().await {
-            println!("KeepAlive sink failed, restarting TCP connection...");
-            drop(lock_stream);
-            drop(lock_sink);
+    pub async fn reconnect(&self) -> Result<()> {
+        info!("reconnecting");
+        self.try_connect().await
+    }
 
-            return self.reconnect().await;
+    // logs in again on the link we've got, for when the gateway has forgotten the session
+    // `stale`. whoever comes second finds the token already changed and has nothing to do
+    #[instrument(name = "reauth", skip_all)]
+    async fn reauth(&self, stale: &str, why: &InterraError) -> Result<()> {
+        let _reauthing = self.reauthing.lock().await;
+        if *self.token.read().await != stale {
+            return Ok(());
         }
+        warn!(reason = %why, "interra forgot our session, logging in again");
 
-        // loop until you get the "response" instead of {"data":{"readValue":"1","isActive":true,"id":108},"meta":{"requestType":19}}
-        // todo find a way to stop it but use this solution for now
-        let mut out = String::new();
-        let mut attempt = 1;
+        let generation = self.generation.load(Ordering::SeqCst);
+        let payload = InterraFrame::new(RequestType::Auth, &self.credentials(), None)?.to_line()?;
+        let reply = self.exchange(&payload, RequestType::Auth).await?;
 
-        loop {
-            println!("KeepAlive attempt ({attempt}) in progress...");
-            lock_stream.read_line(&mut out).await?;
-
-            let json = serde_json::from_str::<Value>(&out);
-            match json {
-                Ok(value) if value.get("data").is_none() => {
-                    println!("KeepAlive successful with TCP output >> {out}");
-                    break;
+        match session(reply.meta) {
+            Ok(token) => {
+                // a whole new link since then already came with its own session
+                if generation == self.generation.load(Ordering::SeqCst) {
+                    *self.token.write().await = token;
                 }
-                _ => {
-                    println!("KeepAlive failed with TCP output >> {out}\nCollecting next line in stream...");
-                    out.clear();
-                    attempt += 1;
+                self.status.send_modify(|status| status.reauths += 1);
+                info!("logged back into interra");
+                Ok(())
+            }
+            Err(e) => {
+                warn!(error = %e, "interra wouldn't take us back");
+                self.status.send_modify(|status| {
+                    status.auth_failures += 1;
+                    status.last_error = Some(e.to_string());
+                });
+                Err(e)
+            }
+        }
+    }
+
+    // owns the read half for the lifetime of one connection. only holds on to the client while
+    // it's dealing with a frame, so a client nobody uses anymore can still go away
+    async fn listen(client: Weak<Self>, generation: u64, mut reader: LinkReader) {
+        let mut line = String::new();
+
+        let reason = loop {
+            line.clear();
+            let read = reader.read_line(&mut line).await;
+            let Some(client) = client.upgrade() else {
+                return;
+            };
+            match read {
+                Ok(0) => break "connection closed by interra".to_string(),
+                Ok(bytes) => debug!(bytes, frame = %Frame(&line), "frame in"),
+                Err(e) => break format!("read failed: {e}"),
+            }
+            client.record(Direction::In, &line);
+
+            let frame = match InterraFrame::parse(&line) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    warn!(error = %e, "skipping unreadable frame");
+                    client.metrics.skipped_frame();
+                    continue;
                 }
             };
 
-            // unlikely but just to be sure
-            if attempt > 5 {
-                println!("KeepAlive attempt limit reached, restarting TCP connection...");
-                drop(lock_stream);
-                drop(lock_sink);
+            // push events like {"data":{"readValue":"1","isActive":true,"id":108},"meta":{"requestType":19}}
+            // aren't the answer to anything we asked
+            if frame.is_push() {
+                match DeviceEvent::from_frame(&frame) {
+                    Some(event) => {
+                        client.cache.pushed(&event);
+                        // no subscribers is an error here, but not one we care about
+                        _ = client.events.send(event);
+                    }
+                    None => {
+                        warn!("push frame without a device in it");
+                        client.metrics.skipped_frame();
+                    }
+                }
+                continue;
+            }
 
-                self.reconnect().await?;
-                break;
+            let mut waiters = client.waiters.lock().unwrap();
+            let position = match frame.request_type() {
+                Some(t) => waiters.iter().position(|w| w.expects == Some(t)),
+                // keep-alives are the ones that don't say, so they go first. an action waiting
+                // on a complaint that never came mustn't get one
+                None => waiters
+                    .iter()
+                    .position(|w| w.expects.is_none())
+                    .or((!waiters.is_empty()).then_some(0)),
+            };
+            match position.and_then(|position| waiters.remove(position)) {
+                // if the caller gave up the reply just goes nowhere, which is fine
+                Some(waiter) => {
+                    waiter
+                        .span
+                        .in_scope(|| debug!(frame = %Frame(&line), "reply in"));
+                    _ = waiter.tx.send(frame);
+                }
+                // an answer to something whose caller stopped listening. if it says the session is
+                // gone, a new link at least logs in again
+                None if InterraError::from_meta(
+                    frame.meta.error.as_ref(),
+                    frame.meta.error_code.as_ref(),
+                )
+                .is_some_and(|e| e.is_auth()) =>
+                {
+                    client.link_lost(
+                        generation,
+                        ConnectionState::Degraded,
+                        "interra forgot our session".to_string(),
+                    );
+                }
+                None => {
+                    debug!("nobody asked for that one, dropping it");
+                    client.metrics.skipped_frame();
+                }
             }
+        };
+
+        info!(%reason, "link closed");
+        let Some(client) = client.upgrade() else {
+            return;
+        };
+        // dropping the senders wakes everyone still waiting with an error
+        client.waiters.lock().unwrap().clear();
+        client.link_lost(generation, ConnectionState::Disconnected, reason);
+    }
+
+    /// Live device state changes pushed by the gateway. Lagging receivers lose the oldest events.
+    pub fn subscribe(&self) -> broadcast::Receiver<DeviceEvent> {
+        self.events.subscribe()
+    }
+
+    // the sink, waiting a little for the link if it isn't up yet
+    async fn sink(&self) -> Result<tokio::sync::MutexGuard<'_, Option<LinkWriter>>> {
+        let lock = self.sink.lock().await;
+        if lock.is_some() {
+            return Ok(lock);
+        }
+        drop(lock);
+
+        self.wait_ready(READY_WAIT).await?;
+        let lock = self.sink.lock().await;
+        match *lock {
+            Some(_) => Ok(lock),
+            None => Err(InterraError::Connect(
+                "interra link went away again".to_string(),
+            )),
         }
-        Ok(())
     }
 
-    pub async fn read_line(&self) -> Result<Value> {
-        let mut out = String::new();
-        let mut lock = self.stream.lock().await;
+    async fn write_line(&self, sink: &mut Option<LinkWriter>, line: &str) -> Result<()> {
+        if sink.is_none() {
+            return Err(InterraError::Connect("no link to interra".to_string()));
+        }
 
-        loop {
-            let byte = lock.read_line(&mut out).await?;
-            println!("TCP Listener ({byte}) >> {out}");
+        let mut writing = Writing {
+            client: self,
+            sink,
+            failed: "gave up halfway through a line".to_string(),
+            done: false,
+        };
+        let written = within(self.config.timeouts.write(), "to take a line", async {
+            let writer = writing.sink.as_mut().expect("checked it's there");
+            writer.write_all(line.as_bytes()).await?;
+            debug!(bytes = line.len(), frame = %Frame(line), "frame out");
+            self.record(Direction::Out, line);
+            writer.flush().await
+        })
+        .await;
 
-            let json = serde_json::from_str::<Value>(&out)?;
+        match &written {
+            Ok(()) => writing.done = true,
+            Err(e) => writing.failed = format!("write failed: {e}"),
+        }
+        written
+    }
 
-            match json.pointer("/data/id").map(|v| v.as_u64()).flatten() {
-                Some(v) if v == 108 => {
-                    continue;
+    async fn send(&self, line: &str) -> Result<()> {
+        let mut lock = self.sink().await?;
+        self.write_line(&mut lock, line).await
+    }
+
+    // registers for the reply while holding the sink, so the waiter queue stays in the same
+    // order as the requests on the wire
+    async fn send_expecting(
+        &self,
+        line: &str,
+        expects: Option<RequestType>,
+    ) -> Result<oneshot::Receiver<InterraFrame>> {
+        let mut lock = self.sink().await?;
+
+        let (tx, rx) = oneshot::channel();
+        let id = self.next_waiter.fetch_add(1, Ordering::Relaxed);
+        self.waiters.lock().unwrap().push_back(Waiter {
+            id,
+            expects,
+            tx,
+            span: Span::current(),
+        });
+
+        if let Err(e) = self.write_line(&mut lock, line).await {
+            // don't leave a waiter behind to steal someone else's reply
+            self.waiters.lock().unwrap().retain(|w| w.id != id);
+            return Err(e);
+        }
+        Ok(rx)
+    }
+
+    async fn reply(rx: oneshot::Receiver<InterraFrame>) -> Result<InterraFrame> {
+        rx.await
+            .map_err(|_| InterraError::Connect("interra hung up before answering".to_string()))
+    }
+
+    #[instrument(name = "keep_alive", skip_all)]
+    pub async fn keep_alive(&self) -> Result<()> {
+        // nothing to keep alive, the supervisor is already on it
+        if self.sink.lock().await.is_none() {
+            return Ok(());
+        }
+
+        debug!("keep-alive going out");
+        let generation = self.generation.load(Ordering::SeqCst);
+        let started = Instant::now();
+        let rx = match self.send_expecting(KEEP_ALIVE, None).await {
+            Ok(rx) => rx,
+            Err(e) => {
+                self.metrics.keep_alive(false);
+                return Err(e);
+            }
+        };
+
+        let reply = time::timeout(self.config.timeouts.response(), Self::reply(rx)).await;
+        self.metrics.round_trip("KeepAlive", started.elapsed());
+        self.metrics.keep_alive(matches!(reply, Ok(Ok(_))));
+        match reply {
+            Ok(Ok(frame)) => {
+                debug!(answer = ?frame.data, "keep-alive answered");
+                Ok(())
+            }
+            _ => {
+                warn!("keep-alive got no answer, replacing the link");
+                self.link_lost(
+                    generation,
+                    ConnectionState::Degraded,
+                    "keep-alive went unanswered".to_string(),
+                );
+                Err(timed_out("to answer the keep-alive"))
+            }
+        }
+    }
+
+    #[instrument(
+        name = "gateway_send",
+        skip_all,
+        fields(?request_type, object_id, bytes)
+    )]
+    pub async fn request<T: Serialize>(&self, request_type: RequestType, data: &T) -> Result<()> {
+        let out = self.frame(request_type, data).await?;
+        Span::current().record("bytes", out.len());
+        self.send(&out).await
+    }
+
+    /// Sends an action and makes sure the gateway took it. If it was turned down because the
+    /// session is gone, logs in again and sends it once more.
+    pub async fn act(&self, action: &ActionData) -> Result<()> {
+        let token = self.token.read().await.clone();
+        match self.confirmed(action).await {
+            Err(e) if e.is_auth() => {
+                self.reauth(&token, &e).await?;
+                self.confirmed(action).await
+            }
+            result => result,
+        }
+    }
+
+    // the gateway only answers an action to turn it down, so a keep-alive goes right behind
+    // it. it's answered in order, so once the keep-alive is, any complaint would have come first
+    #[instrument(
+        name = "gateway_send",
+        skip_all,
+        fields(request_type = ?RequestType::Action, object_id, bytes, latency_ms)
+    )]
+    async fn confirmed(&self, action: &ActionData) -> Result<()> {
+        let mut data = serde_json::to_value(action)?;
+        if let Ok(id) = action.id.parse() {
+            data["actionType"] = self.config.action_code(id, action.action_type).into();
+        }
+        let out = self.frame(RequestType::Action, &data).await?;
+        Span::current().record("bytes", out.len());
+        let generation = self.generation.load(Ordering::SeqCst);
+        let started = Instant::now();
+
+        let mut lock = self.sink().await?;
+        let (action_tx, mut action_rx) = oneshot::channel();
+        let (barrier_tx, barrier_rx) = oneshot::channel();
+        let action_id = self.next_waiter.fetch_add(1, Ordering::Relaxed);
+        let barrier_id = self.next_waiter.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut waiters = self.waiters.lock().unwrap();
+            waiters.push_back(Waiter {
+                id: action_id,
+                expects: Some(RequestType::Action),
+                tx: action_tx,
+                span: Span::current(),
+            });
+            waiters.push_back(Waiter {
+                id: barrier_id,
+                expects: None,
+                tx: barrier_tx,
+                span: Span::current(),
+            });
+        }
+        let mut written = self.write_line(&mut lock, &out).await;
+        if written.is_ok() {
+            written = self.write_line(&mut lock, KEEP_ALIVE).await;
+        }
+        drop(lock);
+        if let Err(e) = written {
+            self.waiters
+                .lock()
+                .unwrap()
+                .retain(|w| w.id != action_id && w.id != barrier_id);
+            return Err(e);
+        }
+
+        let reply = time::timeout(self.config.timeouts.response(), Self::reply(barrier_rx)).await;
+        let took = started.elapsed();
+        self.metrics.round_trip("Action", took);
+        Span::current().record("latency_ms", took.as_millis() as u64);
+        match reply {
+            Ok(reply) => _ = reply?,
+            // same as a read that never got an answer, the waiters stay where they are
+            Err(_) => {
+                self.link_lost(
+                    generation,
+                    ConnectionState::Degraded,
+                    "no answer behind an action".to_string(),
+                );
+                return Err(timed_out("to take an action"));
+            }
+        }
+
+        // nothing came for the action before the keep-alive's answer, so nothing's coming
+        self.waiters.lock().unwrap().retain(|w| w.id != action_id);
+        match action_rx.try_recv() {
+            Ok(frame) => {
+                match InterraError::from_meta(
+                    frame.meta.error.as_ref(),
+                    frame.meta.error_code.as_ref(),
+                ) {
+                    Some(e) => Err(e),
+                    None => Ok(()),
                 }
-                _ => return Ok(json),
             }
+            Err(_) => Ok(()),
         }
     }
 
-    pub async fn request(
+    pub async fn request_read<T: Serialize>(
         &self,
-        data: Option<&str>,
-        request_type: u8,
-        flags: Option<&str>,
-    ) -> Result<()> {
-        let mut lock = self.sink.lock().await;
-
-        // let readable = lock.get_ref().ready(Interest::READABLE | Interest::WRITABLE).await?.is_readable();
-        // if !readable {
-        //     drop(lock);  // todo not dropping WILL deadlock execution, fix later for better design
-        //     self.reconnect().await?;
-        //     lock = self.sink.lock().await;
-        // }
-
-        let out = format!(
-            "{{'data':{},'meta':{{'authID':{},'content_type':null,'error':null,'errorCode':null,'flags':{},'requestType':{request_type},'scheme':null,'serverDateTime':null,'server_version':null,'version':null}}}}\n",
-            data.unwrap_or("null"),
-            self.token.read().await,
-            flags.unwrap_or("null")
-        );
-        lock.write_all(out.as_bytes()).await?;
-
-        println!("TCP Listener () << {out}");
+        request_type: RequestType,
+        data: &T,
+    ) -> Result<Value> {
+        let token = self.token.read().await.clone();
+        match self.round_trip(request_type, data).await {
+            // the gateway turned it down without doing anything, so whatever it was can go again
+            Err(e) if e.is_auth() => {
+                self.reauth(&token, &e).await?;
+                self.round_trip(request_type, data).await
+            }
+            // reads are safe to send twice, so give them one more go on the next link. only when
+            // it was the link though: a timeout has waited long enough, and an answer is an answer
+            Err(e @ InterraError::Connect(_)) if request_type.is_idempotent() => {
+                warn!(error = %e, ?request_type, "read failed, retrying once the link is back");
+                self.wait_ready(READY_WAIT).await?;
+                self.round_trip(request_type, data).await
+            }
+            result => result,
+        }
+    }
 
-        lock.flush().await
+    #[instrument(
+        name = "gateway_round_trip",
+        skip_all,
+        fields(?request_type, object_id, bytes, latency_ms)
+    )]
+    async fn round_trip<T: Serialize>(&self, request_type: RequestType, data: &T) -> Result<Value> {
+        let out = self.frame(request_type, data).await?;
+        let frame = self.exchange(&out, request_type).await?;
+        match InterraError::from_meta(frame.meta.error.as_ref(), frame.meta.error_code.as_ref()) {
+            Some(e) => Err(e),
+            None => Ok(frame.data),
+        }
     }
-    pub async fn request_read(
+
+    // a read with a keep-alive right behind it, keeping the link to itself until the keep-alive
+    // is answered. the gateway answers in order, so if nothing came for the read by then nothing
+    // is coming, and with nobody else on the link no other answer can turn up in its place.
+    // only a keep-alive going unanswered means the link's gone
+    #[instrument(
+        name = "gateway_probe",
+        skip_all,
+        fields(?request_type, object_id, bytes, latency_ms)
+    )]
+    async fn probe<T: Serialize>(
         &self,
-        data: Option<&str>,
-        request_type: u8,
-        flags: Option<&str>,
-    ) -> Result<String> {
-        self.request(data, request_type, flags).await?;
-        Ok(self.read_line().await?["data"].to_string())
+        request_type: RequestType,
+        data: &T,
+    ) -> Result<Option<Value>> {
+        let out = self.frame(request_type, data).await?;
+        Span::current().record("bytes", out.len());
+        let generation = self.generation.load(Ordering::SeqCst);
+        let started = Instant::now();
+
+        let mut lock = self.sink().await?;
+        let (read_tx, mut read_rx) = oneshot::channel();
+        let (barrier_tx, barrier_rx) = oneshot::channel();
+        let read_id = self.next_waiter.fetch_add(1, Ordering::Relaxed);
+        let barrier_id = self.next_waiter.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut waiters = self.waiters.lock().unwrap();
+            waiters.push_back(Waiter {
+                id: read_id,
+                expects: Some(request_type),
+                tx: read_tx,
+                span: Span::current(),
+            });
+            waiters.push_back(Waiter {
+                id: barrier_id,
+                expects: None,
+                tx: barrier_tx,
+                span: Span::current(),
+            });
+        }
+        let mut written = self.write_line(&mut lock, &out).await;
+        if written.is_ok() {
+            written = self.write_line(&mut lock, KEEP_ALIVE).await;
+        }
+        if let Err(e) = written {
+            self.waiters
+                .lock()
+                .unwrap()
+                .retain(|w| w.id != read_id && w.id != barrier_id);
+            return Err(e);
+        }
+
+        let reply = time::timeout(self.config.timeouts.response(), Self::reply(barrier_rx)).await;
+        self.waiters.lock().unwrap().retain(|w| w.id != read_id);
+        drop(lock);
+        let took = started.elapsed();
+        self.metrics.round_trip(&format!("{request_type:?}"), took);
+        Span::current().record("latency_ms", took.as_millis() as u64);
+        match reply {
+            Ok(reply) => _ = reply?,
+            Err(_) => {
+                self.link_lost(
+                    generation,
+                    ConnectionState::Degraded,
+                    format!("no answer behind {request_type:?}"),
+                );
+                return Err(timed_out(&format!("to answer {request_type:?}")));
+            }
+        }
+
+        match read_rx.try_recv() {
+            Ok(frame) => {
+                match InterraError::from_meta(
+                    frame.meta.error.as_ref(),
+                    frame.meta.error_code.as_ref(),
+                ) {
+                    Some(e) => Err(e),
+                    None => Ok(Some(frame.data)),
+                }
+            }
+            Err(_) => {
+                debug!("nothing came back for it");
+                Ok(None)
+            }
+        }
+    }
+
+    // sends a line and waits for the answer, whatever the answer says
+    async fn exchange(&self, line: &str, request_type: RequestType) -> Result<InterraFrame> {
+        let generation = self.generation.load(Ordering::SeqCst);
+        let started = Instant::now();
+        let rx = self.send_expecting(line, Some(request_type)).await?;
+
+        let reply = time::timeout(self.config.timeouts.response(), Self::reply(rx)).await;
+        let took = started.elapsed();
+        self.metrics.round_trip(&format!("{request_type:?}"), took);
+        Span::current()
+            .record("bytes", line.len())
+            .record("latency_ms", took.as_millis() as u64);
+        debug!(answered = reply.is_ok(), "round trip done");
+        match reply {
+            Ok(reply) => reply,
+            // its waiter stays queued so a late answer can't go to the wrong caller, but a link
+            // that stops answering isn't one worth keeping
+            Err(_) => {
+                self.link_lost(
+                    generation,
+                    ConnectionState::Degraded,
+                    format!("no answer to {request_type:?}"),
+                );
+                Err(timed_out(&format!("to answer {request_type:?}")))
+            }
+        }
+    }
+
+    // also puts the object it's about on the current span
+    async fn frame<T: Serialize>(&self, request_type: RequestType, data: &T) -> Result<String> {
+        let token = self.token.read().await.clone();
+        let frame = InterraFrame::new(request_type, data, Some(token))?;
+        if let Some(id) = frame.data.get("id").and_then(Value::as_str) {
+            Span::current().record("object_id", id);
+        }
+        Ok(frame.to_line()?)
     }
 
     // actual commands start here
     pub async fn switch_light(&self, id: u16, enable: bool) -> Result<()> {
-        self.request(
-            Some(&format!(
-                "{{'actionType':'{}','id':'{id}','url':null,'value':'0'}}",
-                if enable { 1 } else { 2 }
-            )),
-            14,
-            None,
-        )
-        .await?;
+        let action = if enable {
+            ActionType::On
+        } else {
+            ActionType::Off
+        };
+        let sent_at = Utc::now();
+        self.queue
+            .submit(Lane::Object(id), Command::Set(ActionData::new(action, id)))
+            .await?;
+        self.cache.wrote(id, enable, None, sent_at);
         Ok(())
     }
 
-    pub async fn get_room_lights(&self, room_id: u16) -> Result<Vec<Light>> {
+    /// Every object of one type in a room, raw, straight from the gateway.
+    pub async fn get_room_objects(
+        &self,
+        room_id: u16,
+        object_type: DeviceType,
+    ) -> Result<Vec<RoomObject>> {
+        let asked_at = Utc::now();
         let response = self
             .request_read(
-                Some(&format!(
-                    "{{'id':'{room_id}','objectType':'{}'}}",
-                    DeviceType::Lights.id()
-                )),
-                20,
-                None,
+                RequestType::RoomQuery,
+                &RoomQuery::new(room_id, self.config.object_type_code(room_id, object_type)),
             )
             .await?;
-        let lights: Vec<Light> = serde_json::from_str(&response)?;
+        let objects = Self::objects(response)?;
+        self.cache.seed(room_id, object_type, &objects, asked_at);
+        Ok(objects)
+    }
+
+    /// Like [`Self::get_room_objects`], but a room the gateway says nothing at all about comes
+    /// back as `None` instead of timing out and taking the link with it. For discovery, which
+    /// mostly asks about rooms and types that aren't there.
+    pub async fn probe_room_objects(
+        &self,
+        room_id: u16,
+        object_type: DeviceType,
+    ) -> Result<Option<Vec<RoomObject>>> {
+        let query = RoomQuery::new(room_id, self.config.object_type_code(room_id, object_type));
+        let token = self.token.read().await.clone();
+        let asked_at = Utc::now();
+        let response = match self.probe(RequestType::RoomQuery, &query).await {
+            Err(e) if e.is_auth() => {
+                self.reauth(&token, &e).await?;
+                self.probe(RequestType::RoomQuery, &query).await
+            }
+            result => result,
+        }?;
+
+        let Some(response) = response else {
+            return Ok(None);
+        };
+        let objects = Self::objects(response)?;
+        self.cache.seed(room_id, object_type, &objects, asked_at);
+        Ok(Some(objects))
+    }
+
+    // rooms without anything of that type come back empty (or null)
+    fn objects(response: Value) -> Result<Vec<RoomObject>> {
+        Ok(match response.is_null() {
+            true => Vec::new(),
+            false => serde_json::from_value(response)?,
+        })
+    }
+
+    /// Like [`Self::get_room_objects`], but out of the cache unless `fresh` is set (or the room
+    /// hasn't been read yet). Stale answers still get served, with a refresh started behind them.
+    pub async fn room_objects(
+        &self,
+        room_id: u16,
+        object_type: DeviceType,
+        fresh: bool,
+    ) -> Result<Vec<Cached<RoomObject>>> {
+        if let Some(objects) = self.cache.room(room_id, object_type).filter(|_| !fresh) {
+            if self.cache.needs_refresh(room_id, object_type) {
+                self.refresh_later(room_id, object_type);
+            }
+            return Ok(objects);
+        }
+
+        let objects = self.get_room_objects(room_id, object_type).await?;
+        // the cache has them now, along with anything pushed while they were on their way
+        if let Some(objects) = self.cache.room(room_id, object_type) {
+            return Ok(objects);
+        }
+        let now = Utc::now();
+        Ok(objects
+            .into_iter()
+            .map(|value| Cached {
+                value,
+                updated_at: now,
+                stale: false,
+            })
+            .collect())
+    }
+
+    fn refresh_later(&self, room_id: u16, object_type: DeviceType) {
+        let Some(client) = self.me.upgrade() else {
+            return;
+        };
+        tokio::spawn(async move {
+            if let Err(e) = client.get_room_objects(room_id, object_type).await {
+                warn!(error = %e, room_id, ?object_type, "couldn't refresh room");
+            }
+            client.cache.refreshed(room_id, object_type);
+        });
+    }
+
+    /// One object, raw, from wherever the config says it lives.
+    pub async fn read_object(&self, id: u16) -> Result<RoomObject> {
+        let (room_id, object_type) = self.config.locate(id).ok_or_else(|| {
+            InterraError::UnknownDevice(format!(
+                "object {id} isn't in the config, no idea where to look"
+            ))
+        })?;
+        self.get_room_objects(room_id, object_type)
+            .await?
+            .into_iter()
+            .find(|object| object.id == id)
+            .ok_or_else(|| {
+                InterraError::UnknownDevice(format!("object {id} isn't in room {room_id}"))
+            })
+    }
+
+    /// Sets a dimmer to an absolute level (0-100).
+    pub async fn set_brightness(&self, id: u16, level: u8) -> Result<()> {
+        let action = ActionData::with_value(ActionType::SetValue, id, level);
+        let sent_at = Utc::now();
+        self.queue
+            .submit(Lane::Object(id), Command::Set(action))
+            .await?;
+        self.cache
+            .wrote(id, level > 0, Some(level.to_string()), sent_at);
+        Ok(())
+    }
+
+    /// Walks a dimmer from `from` to `to` in steps spread over `fade`.
+    pub async fn fade_light(&self, id: u16, from: u8, to: u8, fade: Duration) -> Result<()> {
+        let steps = (fade.as_millis() / FADE_STEP.as_millis()).max(1) as u32;
+        let mut last = from;
+
+        for step in 1..=steps {
+            let level = from as f64 + (to as f64 - from as f64) * step as f64 / steps as f64;
+            let level = level.round() as u8;
+            // slow fades have more steps than levels, no point sending the same one twice
+            if level != last {
+                self.set_brightness(id, level).await?;
+                last = level;
+            }
+            if step < steps {
+                time::sleep(fade / steps).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Switches a light, or sets a dimmer's level (fading if asked). Gives back what the light
+    /// should be now.
+    pub async fn update_light(
+        &self,
+        room_id: u16,
+        object_id: u16,
+        update: &LightUpdate,
+    ) -> Result<Light> {
+        let name = self.config.light_name(object_id);
+        let Some(level) = update.brightness else {
+            let active = update.active.unwrap_or_default();
+            self.switch_light(object_id, active).await?;
+            return Ok(Light {
+                id: name,
+                active,
+                brightness: None,
+            });
+        };
+
+        // has to be a dimmer, and a fade has to know where it starts
+        let current = self
+            .get_room_lights(room_id)
+            .await?
+            .into_iter()
+            .find(|l| l.id == name)
+            .ok_or_else(|| {
+                InterraError::UnknownDevice(format!("no light {name} in room {room_id}"))
+            })?;
+        let Some(from) = current.brightness else {
+            return Err(InterraError::Validation(
+                "that light doesn't dim, it's on or off".to_string(),
+            ));
+        };
+
+        match update.fade {
+            Some(fade) if fade > 0 => {
+                self.fade_light(object_id, from, level, Duration::from_millis(fade))
+                    .await?
+            }
+            _ => self.set_brightness(object_id, level).await?,
+        }
+        Ok(Light {
+            id: name,
+            active: level > 0,
+            brightness: Some(level),
+        })
+    }
+
+    pub async fn move_cover(&self, id: u16, action: CoverAction) -> Result<()> {
+        let action = match action {
+            CoverAction::Up => ActionType::Up,
+            CoverAction::Down => ActionType::Down,
+            CoverAction::Stop => ActionType::Stop,
+        };
+        self.queue
+            .submit(Lane::Object(id), Command::Set(ActionData::new(action, id)))
+            .await
+    }
+
+    pub async fn get_room_covers(&self, room_id: u16) -> Result<Vec<Cover>> {
+        Ok(uncached(self.room_covers(room_id, true).await?))
+    }
+
+    pub async fn room_covers(&self, room_id: u16, fresh: bool) -> Result<Vec<Cached<Cover>>> {
+        let objects = self
+            .room_objects(room_id, DeviceType::Covers, fresh)
+            .await?;
+        Ok(objects
+            .into_iter()
+            .map(|object| {
+                object.map(|object| Cover {
+                    id: self.config.cover_name(object.id),
+                    position: Cover::position_from(object.read_value.as_deref()),
+                })
+            })
+            .collect())
+    }
+
+    /// Plain lights and dimmers both.
+    pub async fn get_room_lights(&self, room_id: u16) -> Result<Vec<Light>> {
+        Ok(uncached(self.room_lights(room_id, true).await?))
+    }
+
+    pub async fn room_lights(&self, room_id: u16, fresh: bool) -> Result<Vec<Cached<Light>>> {
+        let mut lights = Vec::new();
+        for object_type in [DeviceType::Lights, DeviceType::Dimmers] {
+            let objects = self.room_objects(room_id, object_type, fresh).await?;
+            lights.extend(
+                objects
+                    .into_iter()
+                    .map(|object| object.map(|object| self.light(object, object_type))),
+            );
+        }
         Ok(lights)
     }
 
+    fn light(&self, object: RoomObject, object_type: DeviceType) -> Light {
+        Light {
+            id: self.config.light_name(object.id),
+            active: object.active,
+            brightness: match object_type {
+                DeviceType::Dimmers => Light::brightness_from(object.read_value.as_deref()),
+                _ => None,
+            },
+        }
+    }
+
+    /// Every light the cache knows about, by room. Never asks the gateway.
+    pub fn known_lights(&self) -> Vec<(u16, Light)> {
+        let mut lights = Vec::new();
+        for room_id in self.cache.rooms() {
+            for object_type in [DeviceType::Lights, DeviceType::Dimmers] {
+                let objects = self.cache.room(room_id, object_type).unwrap_or_default();
+                lights.extend(
+                    objects
+                        .into_iter()
+                        .map(|object| (room_id, self.light(object.value, object_type))),
+                );
+            }
+        }
+        lights
+    }
+
+    /// Every configured ac the cache has read, by room. Never asks the gateway either.
+    pub fn known_ac(&self) -> Vec<(u16, ACData)> {
+        self.config
+            .rooms
+            .iter()
+            .filter_map(|room| {
+                let config = room.ac.as_ref()?;
+                let objects = self.cache.room(room.id, DeviceType::Ac)?;
+                let ac: Vec<ACDatum> = objects.iter().map(|o| ACDatum::from(&o.value)).collect();
+                Some((room.id, ACData::read(&ac, config)))
+            })
+            .collect()
+    }
+
     pub async fn get_ac_info(&self, room_id: u16) -> Result<ACData> {
-        let response = self
-            .request_read(
-                Some(&format!(
-                    "{{'id':'{room_id}','objectType':'{}'}}",
-                    DeviceType::Ac.id()
-                )),
-                20,
-                None,
-            )
-            .await?;
-        let ac: Vec<ACDatum> = serde_json::from_str(&response)?;
-        Ok(ACData::from(ac))
+        Ok(self.ac_info(room_id, true).await?.value)
+    }
+
+    /// A room's ac. It's read from a handful of objects, so it's as old as the oldest of them.
+    pub async fn ac_info(&self, room_id: u16, fresh: bool) -> Result<Cached<ACData>> {
+        let config = self.ac_config(room_id)?;
+        let objects = self.room_objects(room_id, DeviceType::Ac, fresh).await?;
+
+        let ac: Vec<ACDatum> = objects.iter().map(|o| ACDatum::from(&o.value)).collect();
+        Ok(Cached {
+            value: ACData::read(&ac, config),
+            updated_at: objects
+                .iter()
+                .map(|o| o.updated_at)
+                .min()
+                .unwrap_or_else(Utc::now),
+            stale: objects.iter().any(|o| o.stale),
+        })
     }
 
-    // hardcoded my room
-    pub async fn set_ac_info_room12(&self, ac: &ACData) -> Result<ACData> {
-        let mut ac_old = self.get_ac_info(12).await?;
+    fn ac_config(&self, room_id: u16) -> Result<&AcConfig> {
+        self.config.ac(room_id).ok_or_else(|| {
+            InterraError::UnknownDevice(format!("room {room_id} has no ac in the config"))
+        })
+    }
 
-        if let (Some(t), Some(t_old)) = (ac.set_temp, ac_old.set_temp) {
-            ac_old.set_temp = Some(t);
-            let increase_temp = t as i8 - t_old as i8;
+    /// Moves a room's ac towards `ac` by pressing its command objects (see [`AcConfig`]), then
+    /// reads it back and goes again for whatever didn't take, until it matches or
+    /// [`AC_DEADLINE`] is up. Gives back what was actually read.
+    ///
+    /// Changes to the same ac run one whole call after another, they don't fold together:
+    /// each one steps from what it read, so two +2s on top of each other would be a +4. The
+    /// [`CommandQueue`] only ever gets to fold together the steps of one call.
+    pub async fn set_ac_info(&self, room_id: u16, ac: &ACData) -> Result<ACSetResult> {
+        let config = self.ac_config(room_id)?;
+        let lane = Lane::Ac(room_id);
+        // one change at a time per ac, so the next one starts from where this one left it. this
+        // is why concurrent PATCHes queue up behind each other instead of adding up
+        let _hold = self.queue.hold(lane).await;
+        let deadline = time::Instant::now() + AC_DEADLINE;
+        let mut state = self.get_ac_info(room_id).await?;
+        // which way the setpoint is being stepped. a readback on the far side of the target
+        // means something else is going on, stepping back would just chase it
+        let mut direction = None;
 
-            if increase_temp > 0 {
-                for _ in 0..increase_temp {
-                    self.request(
-                        Some("{'actionType':13,'id':'64','url':null,'value':'0'}"),
-                        14,
-                        None,
-                    )
-                    .await?;
-                    time::sleep(Duration::from_millis(300)).await;
+        loop {
+            let mut commands = Vec::new();
+            if let Some(t) = ac.set_temp.filter(|t| state.set_temp != Some(*t)) {
+                let command = Self::setpoint_command(config, state.set_temp, t);
+                let overshot = match &command {
+                    Some(Command::Step { n, .. }) => {
+                        *direction.get_or_insert(n.signum()) != n.signum()
+                    }
+                    _ => false,
+                };
+                if !overshot {
+                    commands.extend(command);
                 }
-            } else if increase_temp < 0 {
-                for _ in 0..increase_temp.abs() {
-                    self.request(
-                        Some("{'actionType':13,'id':'63','url':null,'value':'0'}"),
-                        14,
-                        None,
-                    )
+            }
+            if let Some(f) = ac.fan_speed.filter(|f| state.fan_speed != Some(*f)) {
+                commands.push(Command::Press(config.fan.press_id(f)));
+            }
+            if let Some(a) = ac.active.filter(|a| state.active != Some(*a)) {
+                commands.push(Command::Press(match a {
+                    true => config.power_on,
+                    false => config.power_off,
+                }));
+            }
+
+            let pressed = !commands.is_empty();
+            let mut events = self.subscribe();
+            let handles: Vec<_> = commands
+                .into_iter()
+                .map(|command| self.queue.submit(lane, command))
+                .collect();
+            for handle in handles {
+                handle.await?;
+            }
+
+            if pressed {
+                state = self
+                    .settled_ac(room_id, config, &mut events, deadline)
                     .await?;
-                    time::sleep(Duration::from_millis(300)).await;
-                }
             }
+            let converged = ac.set_temp.is_none_or(|t| state.set_temp == Some(t))
+                && ac.fan_speed.is_none_or(|f| state.fan_speed == Some(f))
+                && ac.active.is_none_or(|a| state.active == Some(a));
 
-            time::sleep(Duration::from_millis(300)).await;
+            // nothing pressed means nothing left we know how to fix
+            if converged || !pressed || time::Instant::now() >= deadline {
+                if !converged {
+                    warn!(room_id, "ac didn't end up where it was asked to");
+                }
+                return Ok(ACSetResult { state, converged });
+            }
         }
+    }
 
-        if let Some(f) = ac.fan_speed {
-            ac_old.fan_speed = Some(f);
-            let id: u8 = match f {
-                FanSpeed::Auto => 66,
-                FanSpeed::Slow => 67,
-                FanSpeed::Medium => 68,
-                FanSpeed::Fast => 69,
-            };
+    // reads the ac back until it's caught up with the presses: two reads in a row that agree.
+    // it gets a moment between them, cut short if it pushes something
+    async fn settled_ac(
+        &self,
+        room_id: u16,
+        config: &AcConfig,
+        events: &mut broadcast::Receiver<DeviceEvent>,
+        deadline: time::Instant,
+    ) -> Result<ACData> {
+        let controls = |ac: &ACData| (ac.set_temp, ac.fan_speed, ac.active);
+        let mut last = None;
+        loop {
+            let state = self.get_ac_info(room_id).await?;
+            if last == Some(controls(&state)) || time::Instant::now() >= deadline {
+                return Ok(state);
+            }
+            last = Some(controls(&state));
 
-            self.request(
-                Some(&format!(
-                    "{{'actionType':13,'id':'{id}','url':null,'value':'0'}}"
-                )),
-                14,
-                None,
-            )
-            .await?;
+            // only what comes in after this read says anything new
+            *events = events.resubscribe();
+            let pushed = async {
+                loop {
+                    match events.recv().await {
+                        Ok(event) if config.reads(event.object_id) => return,
+                        Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                        Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+                    }
+                }
+            };
+            _ = time::timeout(AC_SETTLE, pushed).await;
+        }
+    }
 
-            time::sleep(Duration::from_millis(300)).await;
+    // what gets the setpoint from `from` to `to`, if anything can
+    fn setpoint_command(config: &AcConfig, from: Option<u8>, to: u8) -> Option<Command> {
+        if config.set_temp_direct {
+            return Some(Command::Set(ActionData::with_value(
+                ActionType::SetValue,
+                config.set_temp,
+                to,
+            )));
         }
 
-        if let Some(a) = ac.active {
-            ac_old.active = Some(a);
-            let id: u8 = match a {
-                true => 57,
-                false => 58,
-            };
+        // no idea where it is, no idea which way to go
+        let from = from?;
+        Some(Command::Step {
+            up: config.temp_up,
+            down: config.temp_down,
+            n: to as i32 - from as i32,
+        })
+    }
+}
 
-            self.request(
-                Some(&format!(
-                    "{{'actionType':13,'id':'{id}','url':null,'value':'0'}}"
-                )),
-                14,
-                None,
-            )
-            .await?;
+// the session a login reply hands out, if it hands one out
+fn session(meta: Meta) -> Result<String> {
+    if let Some(e) = InterraError::from_meta(meta.error.as_ref(), meta.error_code.as_ref()) {
+        return Err(InterraError::AuthRejected(format!("interra said no: {e}")));
+    }
+    meta.auth_id.filter(|id| !id.is_empty()).ok_or_else(|| {
+        InterraError::AuthRejected("interra said no (no authID in auth reply)".to_string())
+    })
+}
 
-            time::sleep(Duration::from_millis(300)).await;
-        }
+fn timed_out(what: &str) -> InterraError {
+    InterraError::Timeout(format!("interra took too long {what}"))
+}
 
-        Ok(ac_old)
+async fn within<T>(
+    limit: Duration,
+    what: &str,
+    future: impl Future<Output = io::Result<T>>,
+) -> Result<T> {
+    match time::timeout(limit, future).await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(timed_out(what)),
     }
 }
 
-pub enum DeviceType {
-    Ac,
-    Lights,
+// a line on its way out. if it doesn't make it all the way (an error, a timeout, or the caller
+// giving up), the gateway has half a frame and the link is no good: drop it, get a new one
+struct Writing<'a> {
+    client: &'a InterraTcpClient,
+    sink: &'a mut Option<LinkWriter>,
+    failed: String,
+    done: bool,
 }
-impl DeviceType {
-    fn id(&self) -> u8 {
-        match self {
-            Self::Ac => 4,
-            Self::Lights => 1,
+
+impl Drop for Writing<'_> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        *self.sink = None;
+        let generation = self.client.generation.load(Ordering::SeqCst);
+        self.client.link_lost(
+            generation,
+            ConnectionState::Disconnected,
+            std::mem::take(&mut self.failed),
+        );
+    }
+}
+
+fn uncached<T>(cached: Vec<Cached<T>>) -> Vec<T> {
+    cached.into_iter().map(|c| c.value).collect()
+}
+
+impl Drop for InterraTcpClient {
+    fn drop(&mut self) {
+        if let Some(reader) = self.reader.lock().unwrap().take() {
+            reader.abort();
         }
     }
 }
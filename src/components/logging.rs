@@ -0,0 +1,134 @@
+use crate::components::recording;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::Error;
+use futures_util::future::LocalBoxFuture;
+use std::future::{ready, Ready};
+use std::time::Instant;
+use std::{fmt, io};
+use tracing::{field, Instrument};
+use tracing_subscriber::EnvFilter;
+
+// what gets logged when RUST_LOG doesn't say
+const DEFAULT_FILTER: &str = "info";
+// same, for the commands that aren't the server
+const QUIET_FILTER: &str = "warn";
+// anything longer than this from a client is made up on the spot instead
+const MAX_REQUEST_ID: usize = 128;
+
+pub const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+#[derive(clap::ValueEnum, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LogFormat {
+    /// lines for people
+    Human,
+    /// JSON lines for machines, with the spans on every line
+    Json,
+}
+
+/// Sets up logging for the whole process. `RUST_LOG` picks the levels (info for everything if
+/// it's not set).
+pub fn init(format: LogFormat) -> io::Result<()> {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+    let logs = tracing_subscriber::fmt().with_env_filter(filter);
+
+    let result = match format {
+        LogFormat::Json => logs
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .try_init(),
+        LogFormat::Human => logs.try_init(),
+    };
+    result.map_err(|e| io::Error::other(format!("can't set up logging: {e}")))
+}
+
+/// Logging for the one-off commands: warnings and worse on stderr (unless `RUST_LOG` says
+/// otherwise), so stdout is just the answer.
+pub fn init_quiet() -> io::Result<()> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(QUIET_FILTER));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .try_init()
+        .map_err(|e| io::Error::other(format!("can't set up logging: {e}")))
+}
+
+/// A line off the gateway link, for logging. The password and authID never make it into the
+/// log, and it's only redacted if something actually gets logged.
+pub struct Frame<'a>(pub &'a str);
+
+impl fmt::Display for Frame<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&recording::redacted(self.0))
+    }
+}
+
+/// A span per request, with the `X-Request-Id` it came with (or a made-up one) that's also sent
+/// back. Everything the request does on the gateway link happens inside it, so the id is on
+/// those spans too.
+pub struct RequestTracing;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestTracing
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestTracingMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestTracingMiddleware { service }))
+    }
+}
+
+pub struct RequestTracingMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestTracingMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID)
+            .and_then(|id| id.to_str().ok())
+            .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID)
+            .map_or_else(|| format!("{:016x}", rand::random::<u64>()), str::to_string);
+        let span = tracing::info_span!(
+            "http_request",
+            request_id = %request_id,
+            method = %req.method(),
+            path = %req.path(),
+            status = field::Empty,
+            latency_ms = field::Empty,
+        );
+        let started = Instant::now();
+        let call = span.in_scope(|| self.service.call(req));
+
+        Box::pin(async move {
+            let mut res = call.instrument(span.clone()).await?;
+            span.record("status", res.status().as_u16());
+            span.record("latency_ms", started.elapsed().as_millis() as u64);
+            span.in_scope(|| tracing::info!("request done"));
+
+            if let Ok(id) = HeaderValue::from_str(&request_id) {
+                res.headers_mut().insert(REQUEST_ID, id);
+            }
+            Ok(res)
+        })
+    }
+}
@@ -0,0 +1,341 @@
+use crate::components::connection::ConnectionState;
+use crate::components::interra::InterraTcpClient;
+use crate::components::serde_models::ACData;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::web::Data;
+use actix_web::Error;
+use futures_util::future::LocalBoxFuture;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::{ready, Ready};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// upper bounds in seconds. the gateway answers in a few ms, an ac change takes seconds
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    // how many took at most BUCKETS[i], not just the ones in between
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, took: Duration) {
+        let seconds = took.as_secs_f64();
+        for (count, bound) in self.buckets.iter_mut().zip(BUCKETS) {
+            if seconds <= bound {
+                *count += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        for (count, bound) in self.buckets.iter().zip(BUCKETS) {
+            _ = writeln!(out, "{name}_bucket{{{labels},le=\"{bound}\"}} {count}");
+        }
+        _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {}", self.count);
+        _ = writeln!(out, "{name}_sum{{{labels}}} {}", self.sum);
+        _ = writeln!(out, "{name}_count{{{labels}}} {}", self.count);
+    }
+}
+
+/// Counters and timings for `/metrics`. The client has the one everything records into, see
+/// [`InterraTcpClient::metrics`].
+#[derive(Default)]
+pub struct Metrics {
+    // (route, method, status)
+    requests: Mutex<BTreeMap<(String, String, u16), Histogram>>,
+    // by request type
+    round_trips: Mutex<BTreeMap<String, Histogram>>,
+    keep_alives: AtomicU64,
+    keep_alive_failures: AtomicU64,
+    skipped_frames: AtomicU64,
+    unauthorized: AtomicU64,
+}
+
+impl Metrics {
+    pub fn request(&self, route: &str, method: &str, status: u16, took: Duration) {
+        self.requests
+            .lock()
+            .unwrap()
+            .entry((route.to_string(), method.to_string(), status))
+            .or_default()
+            .observe(took);
+    }
+
+    pub fn round_trip(&self, request_type: &str, took: Duration) {
+        self.round_trips
+            .lock()
+            .unwrap()
+            .entry(request_type.to_string())
+            .or_default()
+            .observe(took);
+    }
+
+    pub fn keep_alive(&self, answered: bool) {
+        self.keep_alives.fetch_add(1, Ordering::Relaxed);
+        if !answered {
+            self.keep_alive_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// A frame from the gateway that was unreadable or nobody's answer.
+    pub fn skipped_frame(&self) {
+        self.skipped_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A request [`crate::components::auth::Authorized`] turned away.
+    pub fn unauthorized(&self) {
+        self.unauthorized.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Everything in the Prometheus text format. The device gauges come out of the cache, so a
+/// scrape never has to ask the gateway anything (and only has what's been read so far).
+pub fn render(client: &InterraTcpClient) -> String {
+    let metrics = client.metrics();
+    let status = client.status();
+    let counter = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let mut out = String::new();
+
+    let requests = metrics.requests.lock().unwrap();
+    let labelled: Vec<_> = requests
+        .iter()
+        .map(|((route, method, code), histogram)| {
+            let labels = format!(
+                "route=\"{}\",method=\"{method}\",status=\"{code}\"",
+                escape(route)
+            );
+            (labels, histogram)
+        })
+        .collect();
+    header(
+        &mut out,
+        "interra_http_requests_total",
+        "counter",
+        "http requests, by route and status",
+    );
+    for (labels, histogram) in &labelled {
+        _ = writeln!(
+            out,
+            "interra_http_requests_total{{{labels}}} {}",
+            histogram.count
+        );
+    }
+    header(
+        &mut out,
+        "interra_http_request_duration_seconds",
+        "histogram",
+        "how long http requests took",
+    );
+    for (labels, histogram) in &labelled {
+        histogram.write(&mut out, "interra_http_request_duration_seconds", labels);
+    }
+    drop(labelled);
+    drop(requests);
+
+    header(
+        &mut out,
+        "interra_gateway_round_trip_seconds",
+        "histogram",
+        "from sending a request to the gateway answering it",
+    );
+    for (request_type, histogram) in metrics.round_trips.lock().unwrap().iter() {
+        let labels = format!("request_type=\"{request_type}\"");
+        histogram.write(&mut out, "interra_gateway_round_trip_seconds", &labels);
+    }
+
+    for (name, kind, help, value) in [
+        (
+            "interra_http_unauthorized_total",
+            "counter",
+            "requests turned away for a bad or missing token",
+            counter(&metrics.unauthorized),
+        ),
+        (
+            "interra_gateway_up",
+            "gauge",
+            "1 if the link to the gateway is ready",
+            u64::from(status.state == ConnectionState::Ready),
+        ),
+        (
+            "interra_gateway_reconnects_total",
+            "counter",
+            "new links after the first one",
+            status.reconnects,
+        ),
+        (
+            "interra_gateway_reauths_total",
+            "counter",
+            "logins again after the gateway forgot the session",
+            status.reauths,
+        ),
+        (
+            "interra_gateway_auth_failures_total",
+            "counter",
+            "logins the gateway said no to",
+            status.auth_failures,
+        ),
+        (
+            "interra_gateway_keep_alives_total",
+            "counter",
+            "keep-alives sent",
+            counter(&metrics.keep_alives),
+        ),
+        (
+            "interra_gateway_keep_alive_failures_total",
+            "counter",
+            "keep-alives that went unanswered",
+            counter(&metrics.keep_alive_failures),
+        ),
+        (
+            "interra_gateway_frames_skipped_total",
+            "counter",
+            "frames from the gateway that were unreadable or nobody's answer",
+            counter(&metrics.skipped_frames),
+        ),
+    ] {
+        header(&mut out, name, kind, help);
+        _ = writeln!(out, "{name} {value}");
+    }
+
+    let lights = client.known_lights();
+    header(
+        &mut out,
+        "interra_light_on",
+        "gauge",
+        "1 if the light is on",
+    );
+    for (room, light) in &lights {
+        let labels = format!("room=\"{room}\",light=\"{}\"", escape(&light.id));
+        _ = writeln!(
+            out,
+            "interra_light_on{{{labels}}} {}",
+            u8::from(light.active)
+        );
+    }
+    header(
+        &mut out,
+        "interra_light_brightness",
+        "gauge",
+        "dimmer level, 0 to 100",
+    );
+    for (room, light) in &lights {
+        if let Some(brightness) = light.brightness {
+            let labels = format!("room=\"{room}\",light=\"{}\"", escape(&light.id));
+            _ = writeln!(out, "interra_light_brightness{{{labels}}} {brightness}");
+        }
+    }
+
+    let ac = client.known_ac();
+    for (name, help, value) in [
+        (
+            "interra_ac_power",
+            "1 if the ac is on",
+            (|ac: &ACData| ac.active.map(|a| f64::from(u8::from(a)))) as fn(&ACData) -> Option<f64>,
+        ),
+        (
+            "interra_ac_room_temperature_celsius",
+            "what the ac thinks the room is at",
+            |ac| ac.room_temp,
+        ),
+        (
+            "interra_ac_setpoint_celsius",
+            "what the ac is set to",
+            |ac| ac.set_temp.map(f64::from),
+        ),
+        (
+            "interra_ac_fan_speed",
+            "0 auto, 1 slow, 2 medium, 3 fast",
+            |ac| ac.fan_speed.map(|f| f64::from(f as u8)),
+        ),
+    ] {
+        header(&mut out, name, "gauge", help);
+        for (room, ac) in &ac {
+            if let Some(value) = value(ac) {
+                _ = writeln!(out, "{name}{{room=\"{room}\"}} {value}");
+            }
+        }
+    }
+
+    out
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    _ = writeln!(out, "# HELP {name} {help}");
+    _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+// label values are quoted, so quotes (and the rest) need escaping
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Times every request into the client's [`Metrics`], labelled by the route it matched (the
+/// pattern, so `/rooms/{room}/lights` and not every room on its own).
+pub struct RequestMetrics;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestMetricsMiddleware { service }))
+    }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let started = Instant::now();
+        let call = self.service.call(req);
+
+        Box::pin(async move {
+            let res = call.await?;
+            let req = res.request();
+            if let Some(client) = req.app_data::<Data<InterraTcpClient>>() {
+                let route = req
+                    .match_pattern()
+                    .unwrap_or_else(|| "unmatched".to_string());
+                client.metrics().request(
+                    &route,
+                    req.method().as_str(),
+                    res.status().as_u16(),
+                    started.elapsed(),
+                );
+            }
+            Ok(res)
+        })
+    }
+}
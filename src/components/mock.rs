@@ -0,0 +1,521 @@
+use crate::components::connection::GatewayConfig;
+use crate::components::protocol::{
+    ActionData, ActionType, InterraFrame, Meta, RequestType, RoomQuery,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+pub const LIGHTS: u8 = 1;
+pub const DIMMERS: u8 = 2;
+pub const COVERS: u8 = 3;
+pub const AC: u8 = 4;
+
+/// One object in the mock's device table.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct MockDevice {
+    pub id: u16,
+    #[serde(rename = "isActive")]
+    pub active: bool,
+    #[serde(rename = "readValue", skip_serializing_if = "Option::is_none")]
+    pub read_value: Option<String>,
+}
+
+impl MockDevice {
+    pub fn new(id: u16, active: bool, read_value: Option<&str>) -> Self {
+        Self {
+            id,
+            active,
+            read_value: read_value.map(str::to_string),
+        }
+    }
+}
+
+/// Something to go wrong with the next reply the mock sends.
+#[derive(Debug, Clone)]
+pub enum Fault {
+    /// hang up instead of answering
+    Drop,
+    /// answer, but only after this long
+    Delay(Duration),
+    /// send a line of junk before the real answer
+    Garbage,
+    /// never answer at all
+    Silence,
+    /// answer with this error code instead of the real answer
+    Refuse(i64),
+}
+
+struct State {
+    username: String,
+    password: String,
+    auth_id: String,
+    sessions: u32,
+    // (room id, object type) -> objects
+    rooms: BTreeMap<(u16, u8), Vec<MockDevice>>,
+    faults: VecDeque<Fault>,
+    // actions to ignore, like a gateway that missed them
+    lost_actions: usize,
+    // actions to turn down instead, and what to say
+    refused_actions: VecDeque<(i64, String)>,
+    // how long actions take to show up
+    lag: Option<Duration>,
+    received: Vec<InterraFrame>,
+}
+
+impl State {
+    fn device_mut(&mut self, id: u16) -> Option<&mut MockDevice> {
+        self.rooms
+            .values_mut()
+            .flat_map(|objects| objects.iter_mut())
+            .find(|d| d.id == id)
+    }
+
+    fn device(&self, id: u16) -> Option<&MockDevice> {
+        self.rooms
+            .values()
+            .flat_map(|objects| objects.iter())
+            .find(|d| d.id == id)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Outbound {
+    Line(String),
+    Disconnect,
+}
+
+/// A fake interra gateway on localhost that speaks the same newline-delimited frames.
+///
+/// Starts out with my room (12): the two lights and the ac objects, all off.
+pub struct MockGateway {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    outbound: broadcast::Sender<Outbound>,
+    task: JoinHandle<()>,
+}
+
+impl MockGateway {
+    /// Listens on a random local port.
+    pub async fn start() -> io::Result<Self> {
+        Self::bind("127.0.0.1:0").await
+    }
+
+    pub async fn bind(addr: &str) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+
+        let mut rooms = BTreeMap::new();
+        rooms.insert(
+            (12, LIGHTS),
+            vec![
+                MockDevice::new(13, false, None),
+                MockDevice::new(146, false, None),
+            ],
+        );
+        rooms.insert(
+            (12, AC),
+            vec![
+                MockDevice::new(57, false, None),
+                MockDevice::new(60, true, Some("24.38")),
+                MockDevice::new(62, true, Some("23")),
+                MockDevice::new(67, true, Some("00")),
+            ],
+        );
+
+        let state = Arc::new(Mutex::new(State {
+            username: "mock".to_string(),
+            password: "mock".to_string(),
+            auth_id: "mock-session".to_string(),
+            sessions: 0,
+            rooms,
+            faults: VecDeque::new(),
+            lost_actions: 0,
+            refused_actions: VecDeque::new(),
+            lag: None,
+            received: Vec::new(),
+        }));
+        let (outbound, _) = broadcast::channel(64);
+
+        let task = tokio::spawn(Self::accept(listener, state.clone(), outbound.clone()));
+
+        Ok(Self {
+            addr,
+            state,
+            outbound,
+            task,
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Client settings that log into this mock.
+    pub fn config(&self) -> GatewayConfig {
+        let state = self.state.lock().unwrap();
+        GatewayConfig {
+            host: self.addr.ip().to_string(),
+            port: self.addr.port(),
+            username: state.username.clone(),
+            password: state.password.clone(),
+            record: None,
+        }
+    }
+
+    pub fn set_credentials(&self, username: &str, password: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.username = username.to_string();
+        state.password = password.to_string();
+    }
+
+    /// Adds or replaces an object in a room. Doesn't tell anyone, see [`Self::push`].
+    pub fn set_device(&self, room_id: u16, object_type: u8, device: MockDevice) {
+        let mut state = self.state.lock().unwrap();
+        let objects = state.rooms.entry((room_id, object_type)).or_default();
+        objects.retain(|d| d.id != device.id);
+        objects.push(device);
+    }
+
+    pub fn device(&self, id: u16) -> Option<MockDevice> {
+        self.state.lock().unwrap().device(id).cloned()
+    }
+
+    /// Changes an object like a wall switch would, and pushes the change to every client.
+    pub fn update(&self, id: u16, active: bool, read_value: Option<&str>) {
+        if let Some(device) = self.state.lock().unwrap().device_mut(id) {
+            device.active = active;
+            device.read_value = read_value.map(str::to_string);
+        }
+        self.push(id);
+    }
+
+    /// Sends an unsolicited requestType 19 frame with the object's current state.
+    pub fn push(&self, id: u16) {
+        let device = self.state.lock().unwrap().device(id).cloned();
+        if let Some(device) = device {
+            _ = self.outbound.send(Outbound::Line(push_frame(&device)));
+        }
+    }
+
+    /// Queues a fault, used up by the next reply the mock would have sent.
+    pub fn inject(&self, fault: Fault) {
+        self.state.lock().unwrap().faults.push_back(fault);
+    }
+
+    /// Ignores the next `n` actions (they're still in [`Self::received`]).
+    pub fn lose_actions(&self, n: usize) {
+        self.state.lock().unwrap().lost_actions = n;
+    }
+
+    /// Turns down the next action with this errorCode and message, without doing it.
+    pub fn refuse_action(&self, code: i64, message: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.refused_actions.push_back((code, message.to_string()));
+    }
+
+    /// Acts on actions this long after they come in, like devices that are slow to catch up.
+    /// Reads in the meantime still say how things were.
+    pub fn lag(&self, delay: Duration) {
+        self.state.lock().unwrap().lag = Some(delay);
+    }
+
+    /// Forgets the session everyone is logged in with, like the real one does after a while.
+    /// Anything sent with the old authID gets an error until the client logs in again.
+    pub fn expire_session(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.sessions += 1;
+        state.auth_id = format!("mock-session-{}", state.sessions);
+    }
+
+    /// Hangs up on every connected client.
+    pub fn disconnect_all(&self) {
+        _ = self.outbound.send(Outbound::Disconnect);
+    }
+
+    /// Every frame clients sent, oldest first.
+    pub fn received(&self) -> Vec<InterraFrame> {
+        self.state.lock().unwrap().received.clone()
+    }
+
+    pub fn logins(&self) -> usize {
+        self.received()
+            .iter()
+            .filter(|f| f.request_type() == Some(RequestType::Auth))
+            .count()
+    }
+
+    async fn accept(
+        listener: TcpListener,
+        state: Arc<Mutex<State>>,
+        outbound: broadcast::Sender<Outbound>,
+    ) {
+        while let Ok((socket, _)) = listener.accept().await {
+            tokio::spawn(Self::serve(
+                socket,
+                state.clone(),
+                outbound.clone(),
+                outbound.subscribe(),
+            ));
+        }
+    }
+
+    async fn serve(
+        socket: TcpStream,
+        state: Arc<Mutex<State>>,
+        outbound: broadcast::Sender<Outbound>,
+        mut pushes: broadcast::Receiver<Outbound>,
+    ) {
+        let (read, mut write) = socket.into_split();
+        let mut read = BufReader::new(read);
+        let mut line = String::new();
+
+        loop {
+            // a push can interrupt read_line halfway through a line, which is fine as long as
+            // the partial line stays in the buffer until the rest of it shows up
+            let (out, reply) = tokio::select! {
+                read = read.read_line(&mut line) => match read {
+                    Ok(0) | Err(_) => return,
+                    Ok(_) => {
+                        let (reply, pushed) = Self::handle(&state, &outbound, &line);
+                        line.clear();
+                        for push in pushed {
+                            _ = outbound.send(Outbound::Line(push));
+                        }
+                        match reply {
+                            Some(reply) => (reply, true),
+                            None => continue,
+                        }
+                    }
+                },
+                push = pushes.recv() => match push {
+                    Ok(Outbound::Line(push)) => (push, false),
+                    Ok(Outbound::Disconnect) | Err(broadcast::error::RecvError::Closed) => return,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                },
+            };
+
+            // faults only hit replies, pushes always go out as they are
+            let fault = match reply {
+                true => state.lock().unwrap().faults.pop_front(),
+                false => None,
+            };
+            let mut out = out;
+            match fault {
+                Some(Fault::Drop) => return,
+                Some(Fault::Delay(delay)) => time::sleep(delay).await,
+                Some(Fault::Garbage) => _ = write.write_all(b"}}not json at all{{\n").await,
+                Some(Fault::Silence) => continue,
+                Some(Fault::Refuse(code)) => {
+                    let meta = InterraFrame::parse(&out)
+                        .map(|f| f.meta)
+                        .unwrap_or_default();
+                    out = line_of(
+                        Value::Null,
+                        Meta {
+                            error: Some(json!("interra is having a bad day")),
+                            error_code: Some(json!(code)),
+                            ..meta
+                        },
+                    );
+                }
+                None => {}
+            }
+
+            if write.write_all(out.as_bytes()).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    // works out the reply to one line (if any) and the push frames it causes
+    fn handle(
+        shared: &Arc<Mutex<State>>,
+        outbound: &broadcast::Sender<Outbound>,
+        line: &str,
+    ) -> (Option<String>, Vec<String>) {
+        let Ok(frame) = InterraFrame::parse(line) else {
+            return (None, Vec::new());
+        };
+        let mut state = shared.lock().unwrap();
+        state.received.push(frame.clone());
+
+        let request_type = frame.request_type();
+        if !matches!(request_type, None | Some(RequestType::Auth))
+            && frame.meta.auth_id.as_deref() != Some(state.auth_id.as_str())
+        {
+            let meta = Meta {
+                error: Some(json!("session expired, log in again")),
+                error_code: Some(json!(401)),
+                request_type,
+                ..Meta::default()
+            };
+            return (Some(line_of(Value::Null, meta)), Vec::new());
+        }
+
+        match request_type {
+            // keep-alive
+            None => (Some("{}\n".to_string()), Vec::new()),
+            Some(RequestType::Auth) => {
+                let ok = frame.data["userName"] == state.username
+                    && frame.data["password"] == state.password;
+                let meta = if ok {
+                    Meta {
+                        auth_id: Some(state.auth_id.clone()),
+                        request_type: Some(RequestType::Auth),
+                        ..Meta::default()
+                    }
+                } else {
+                    Meta {
+                        error: Some(json!("wrong username or password")),
+                        error_code: Some(json!(1)),
+                        request_type: Some(RequestType::Auth),
+                        ..Meta::default()
+                    }
+                };
+                (Some(line_of(Value::Null, meta)), Vec::new())
+            }
+            Some(RequestType::RoomQuery) => {
+                let objects = serde_json::from_value::<RoomQuery>(frame.data)
+                    .ok()
+                    .and_then(|q| Some((q.id.parse().ok()?, q.object_type.parse().ok()?)))
+                    .and_then(|key: (u16, u8)| state.rooms.get(&key).cloned())
+                    .unwrap_or_default();
+                let meta = Meta {
+                    request_type: Some(RequestType::RoomQuery),
+                    ..Meta::default()
+                };
+                (Some(line_of(json!(objects), meta)), Vec::new())
+            }
+            Some(RequestType::Action) => {
+                let Ok(action) = serde_json::from_value::<ActionData>(frame.data) else {
+                    return (None, Vec::new());
+                };
+                let Ok(id) = action.id.parse::<u16>() else {
+                    return (None, Vec::new());
+                };
+                if state.lost_actions > 0 {
+                    state.lost_actions -= 1;
+                    return (None, Vec::new());
+                }
+                if let Some((code, message)) = state.refused_actions.pop_front() {
+                    let meta = Meta {
+                        error: Some(json!(message)),
+                        error_code: Some(json!(code)),
+                        request_type,
+                        ..Meta::default()
+                    };
+                    return (Some(line_of(Value::Null, meta)), Vec::new());
+                }
+
+                if let Some(lag) = state.lag {
+                    let (shared, outbound) = (shared.clone(), outbound.clone());
+                    tokio::spawn(async move {
+                        time::sleep(lag).await;
+                        let mut state = shared.lock().unwrap();
+                        for push in Self::apply(&mut state, &action, id) {
+                            _ = outbound.send(Outbound::Line(push));
+                        }
+                    });
+                    return (None, Vec::new());
+                }
+                // actions don't get an answer, just the push frames
+                (None, Self::apply(&mut state, &action, id))
+            }
+            Some(_) => (None, Vec::new()),
+        }
+    }
+
+    // acts on an action, giving back the push frames for whatever changed
+    fn apply(state: &mut State, action: &ActionData, id: u16) -> Vec<String> {
+        let changed = Self::act(state, action, id);
+        changed
+            .into_iter()
+            .filter_map(|id| state.device(id).map(push_frame))
+            .collect()
+    }
+
+    // applies an action to the table, returning the objects that changed. it speaks the default
+    // codes, the dimmer and cover ones are the same guesses as ActionType's (see
+    // tests/fixtures/synthetic_room3_own_codes.jsonl for a made-up gateway that doesn't)
+    fn act(state: &mut State, action: &ActionData, id: u16) -> Option<u16> {
+        match action.action_type {
+            ActionType::On | ActionType::Off => {
+                state.device_mut(id)?.active = action.action_type == ActionType::On;
+                Some(id)
+            }
+            // dimmers: the level is the read value, and 0 is off
+            ActionType::SetValue => {
+                let level = action.value.parse::<f64>().ok()?;
+                let device = state.device_mut(id)?;
+                device.active = level > 0.0;
+                device.read_value = Some(action.value.clone());
+                Some(id)
+            }
+            // covers get where they're going instantly here, so there's never anything to stop
+            ActionType::Up | ActionType::Down => {
+                let position = if action.action_type == ActionType::Up {
+                    "0"
+                } else {
+                    "100"
+                };
+                state.device_mut(id)?.read_value = Some(position.to_string());
+                Some(id)
+            }
+            ActionType::Stop => None,
+            // the ac is driven by pressing command objects that then change the read objects
+            ActionType::Press => match id {
+                57 | 58 => {
+                    state.device_mut(57)?.active = id == 57;
+                    Some(57)
+                }
+                63 | 64 => {
+                    let setpoint = state.device_mut(62)?;
+                    let current = setpoint
+                        .read_value
+                        .as_deref()
+                        .and_then(|v| v.parse::<f64>().ok())
+                        .unwrap_or_default();
+                    let step = if id == 64 { 1.0 } else { -1.0 };
+                    setpoint.read_value = Some(format!("{}", current + step));
+                    Some(62)
+                }
+                66..=69 => {
+                    state.device_mut(67)?.read_value = Some(format!("0{}", id - 66));
+                    Some(67)
+                }
+                _ => None,
+            },
+        }
+    }
+}
+
+impl Drop for MockGateway {
+    fn drop(&mut self) {
+        self.task.abort();
+        self.disconnect_all();
+    }
+}
+
+fn line_of(data: Value, meta: Meta) -> String {
+    InterraFrame { data, meta }
+        .to_line()
+        .expect("frames always serialize")
+}
+
+fn push_frame(device: &MockDevice) -> String {
+    let meta = Meta {
+        request_type: Some(RequestType::PushEvent),
+        ..Meta::default()
+    };
+    line_of(json!(device), meta)
+}
@@ -0,0 +1,279 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use serde_repr::{Deserialize_repr, Serialize_repr};
+use std::fmt;
+
+// interra doesn't answer the keep-alive with a real frame, it just wants *something* on the wire
+pub const KEEP_ALIVE: &str = "{}\n";
+
+/// One newline-delimited frame on the interra tcp link, in either direction.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct InterraFrame {
+    #[serde(default)]
+    pub data: Value,
+    #[serde(default)]
+    pub meta: Meta,
+}
+
+// the gateway sends (and expects) every one of these, even when they're all null
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Meta {
+    #[serde(rename = "authID", default)]
+    pub auth_id: Option<String>,
+    #[serde(default)]
+    pub content_type: Option<Value>,
+    #[serde(default)]
+    pub error: Option<Value>,
+    #[serde(rename = "errorCode", default)]
+    pub error_code: Option<Value>,
+    #[serde(default)]
+    pub flags: Option<Value>,
+    #[serde(rename = "requestType", default)]
+    pub request_type: Option<RequestType>,
+    #[serde(default)]
+    pub scheme: Option<Value>,
+    #[serde(rename = "serverDateTime", default)]
+    pub server_date_time: Option<Value>,
+    #[serde(default)]
+    pub server_version: Option<Value>,
+    #[serde(default)]
+    pub version: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[serde(from = "u16", into = "u16")]
+pub enum RequestType {
+    /// fire an action on an object (switch, step, scene...)
+    Action,
+    /// unsolicited state change sent by the gateway
+    PushEvent,
+    /// list the objects of a type in a room
+    RoomQuery,
+    /// login with username + password, answers with an authID
+    Auth,
+    Other(u16),
+}
+impl From<u16> for RequestType {
+    fn from(value: u16) -> Self {
+        match value {
+            14 => Self::Action,
+            19 => Self::PushEvent,
+            20 => Self::RoomQuery,
+            500 => Self::Auth,
+            other => Self::Other(other),
+        }
+    }
+}
+impl From<RequestType> for u16 {
+    fn from(value: RequestType) -> Self {
+        match value {
+            RequestType::Action => 14,
+            RequestType::PushEvent => 19,
+            RequestType::RoomQuery => 20,
+            RequestType::Auth => 500,
+            RequestType::Other(other) => other,
+        }
+    }
+}
+
+impl RequestType {
+    /// Safe to send again if the first one got lost with the connection.
+    pub fn is_idempotent(&self) -> bool {
+        matches!(self, Self::RoomQuery)
+    }
+}
+
+impl InterraFrame {
+    pub fn new<T: Serialize>(
+        request_type: RequestType,
+        data: &T,
+        auth_id: Option<String>,
+    ) -> serde_json::Result<Self> {
+        Ok(Self {
+            data: serde_json::to_value(data)?,
+            meta: Meta {
+                auth_id,
+                request_type: Some(request_type),
+                ..Meta::default()
+            },
+        })
+    }
+
+    pub fn parse(line: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(line.trim())
+    }
+
+    /// Serialized frame including the trailing newline the gateway splits on.
+    pub fn to_line(&self) -> serde_json::Result<String> {
+        let mut out = serde_json::to_string(self)?;
+        out.push('\n');
+        Ok(out)
+    }
+
+    pub fn request_type(&self) -> Option<RequestType> {
+        self.meta.request_type
+    }
+
+    pub fn is_push(&self) -> bool {
+        self.request_type() == Some(RequestType::PushEvent)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AuthData {
+    #[serde(rename = "userName")]
+    pub username: String,
+    pub password: String,
+}
+
+// same as GatewayConfig, the password stays out of logs
+impl fmt::Debug for AuthData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthData")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+/// On, off and press are the numbers the api has sent since its first version. Nobody has
+/// captured the frames to check them, so they're assumed. The dimmer and cover ones are plain
+/// guesses: a device's `codes` in the config win over them, see
+/// [`crate::components::config::WireCodes`]. (The fixtures in tests/fixtures are hand-written
+/// from these same values, so they don't prove anything either.)
+#[derive(Serialize_repr, Deserialize_repr, Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ActionType {
+    On = 1,
+    Off = 2,
+    /// write an absolute value (a dimmer's level...), which goes in `value`
+    SetValue = 3,
+    /// covers: roll up, roll down, stop wherever it is
+    Up = 10,
+    Down = 11,
+    Stop = 12,
+    /// "press" a command object, which is how the ac steps/modes are driven
+    Press = 13,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ActionData {
+    #[serde(rename = "actionType")]
+    pub action_type: ActionType,
+    pub id: String,
+    pub url: Option<String>,
+    pub value: String,
+}
+impl ActionData {
+    pub fn new(action_type: ActionType, id: u16) -> Self {
+        Self {
+            action_type,
+            id: id.to_string(),
+            url: None,
+            value: "0".to_string(),
+        }
+    }
+
+    pub fn with_value(action_type: ActionType, id: u16, value: impl ToString) -> Self {
+        Self {
+            value: value.to_string(),
+            ..Self::new(action_type, id)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomQuery {
+    pub id: String,
+    #[serde(rename = "objectType")]
+    pub object_type: String,
+}
+impl RoomQuery {
+    /// `object_type` is the number on the wire, which the config gets the last word on.
+    pub fn new(room_id: u16, object_type: u8) -> Self {
+        Self {
+            id: room_id.to_string(),
+            object_type: object_type.to_string(),
+        }
+    }
+}
+
+/// The `objectType` of a room query. Serialized as a name when we know it, the raw id otherwise.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(try_from = "DeviceTypeRepr", into = "DeviceTypeRepr")]
+pub enum DeviceType {
+    Lights,
+    /// lights with a level (0-100) in `readValue`
+    Dimmers,
+    /// blinds and shutters, position (0 is all the way up, 100 all the way down) in `readValue`
+    Covers,
+    Ac,
+    Other(u8),
+}
+
+impl DeviceType {
+    const KNOWN: [Self; 4] = [Self::Lights, Self::Dimmers, Self::Covers, Self::Ac];
+
+    /// The usual `objectType`. Assumed, not captured: lights and ac are what the api has always
+    /// asked for, dimmers and covers are guesses a device's `codes.object_type` in the config can
+    /// correct.
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::Lights => 1,
+            Self::Dimmers => 2,
+            Self::Covers => 3,
+            Self::Ac => 4,
+            Self::Other(id) => *id,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Self {
+        Self::KNOWN
+            .into_iter()
+            .find(|t| t.id() == id)
+            .unwrap_or(Self::Other(id))
+    }
+
+    /// Anything that shows up under `/lights`.
+    pub fn is_light(&self) -> bool {
+        matches!(self, Self::Lights | Self::Dimmers)
+    }
+
+    fn name(&self) -> Option<&'static str> {
+        match self {
+            Self::Lights => Some("lights"),
+            Self::Dimmers => Some("dimmers"),
+            Self::Covers => Some("covers"),
+            Self::Ac => Some("ac"),
+            Self::Other(_) => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum DeviceTypeRepr {
+    Id(u8),
+    Name(String),
+}
+impl TryFrom<DeviceTypeRepr> for DeviceType {
+    type Error = String;
+
+    fn try_from(value: DeviceTypeRepr) -> Result<Self, Self::Error> {
+        match value {
+            DeviceTypeRepr::Id(id) => Ok(Self::from_id(id)),
+            DeviceTypeRepr::Name(name) => Self::KNOWN
+                .into_iter()
+                .find(|t| t.name() == Some(name.as_str()))
+                .ok_or_else(|| format!("unknown device type {name:?}")),
+        }
+    }
+}
+impl From<DeviceType> for DeviceTypeRepr {
+    fn from(value: DeviceType) -> Self {
+        match value.name() {
+            Some(name) => Self::Name(name.to_string()),
+            None => Self::Id(value.id()),
+        }
+    }
+}
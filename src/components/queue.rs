@@ -0,0 +1,271 @@
+use crate::components::config::Config;
+use crate::components::error::{InterraError, Result};
+use crate::components::protocol::{ActionData, ActionType, DeviceType};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::{oneshot, Mutex, Notify, OwnedMutexGuard};
+use tokio::time;
+use tracing::{Instrument, Span};
+
+// how often an idle worker checks whether its lane can go, for when nothing wakes it up
+const IDLE_CHECK: Duration = Duration::from_secs(1);
+
+type Lanes = Arc<StdMutex<HashMap<Lane, Arc<LaneState>>>>;
+
+/// Sends one action frame. The queue doesn't care how.
+pub type Sender =
+    Arc<dyn Fn(ActionData) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> + Send + Sync>;
+
+/// What gets its commands one at a time: a single object, or all of a room's ac buttons
+/// (they all drive the same unit).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Lane {
+    Object(u16),
+    Ac(u16),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// on/off, a level, a cover moving: a newer one on the same object replaces it
+    Set(ActionData),
+    /// one press of a button, pressing it twice in a row is the same as once
+    Press(u16),
+    /// `n` presses of `up`, or of `down` if it's negative. steps waiting back to back add up
+    Step { up: u16, down: u16, n: i32 },
+}
+
+// a command waiting its turn, and everyone waiting on it (more than one once it's been merged)
+struct Entry {
+    command: Command,
+    done: Vec<oneshot::Sender<Result<()>>>,
+    // whoever asked first, so what the worker sends shows up under their request
+    span: Span,
+}
+
+impl Entry {
+    fn finish(self, result: &Result<()>) {
+        for done in self.done {
+            _ = done.send(result.clone());
+        }
+    }
+}
+
+struct LaneState {
+    pending: StdMutex<VecDeque<Entry>>,
+    wake: Notify,
+    // see `CommandQueue::hold`
+    hold: Arc<Mutex<()>>,
+}
+
+/// Everything that changes a device goes through here: one worker per [`Lane`], sending its
+/// commands in order with the configured pause after each one, and folding together the ones
+/// that haven't gone out yet where that means the same thing. A worker goes away once its lane
+/// has nothing left to do, and the next command on it starts a new one.
+pub struct CommandQueue {
+    config: Arc<Config>,
+    send: Sender,
+    lanes: Lanes,
+}
+
+/// Resolves once the command has gone out and the lane's pause after it is over.
+pub struct CommandHandle(oneshot::Receiver<Result<()>>);
+
+impl Future for CommandHandle {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx).map(|result| {
+            result.unwrap_or_else(|_| Err(InterraError::Connect("command dropped".to_string())))
+        })
+    }
+}
+
+impl CommandQueue {
+    pub fn new(config: Arc<Config>, send: Sender) -> Self {
+        Self {
+            config,
+            send,
+            lanes: Arc::new(StdMutex::new(HashMap::new())),
+        }
+    }
+
+    /// How many lanes have a worker right now.
+    pub fn lanes(&self) -> usize {
+        self.lanes.lock().unwrap().len()
+    }
+
+    /// Queues a command behind whatever the lane is already doing.
+    pub fn submit(&self, lane: Lane, command: Command) -> CommandHandle {
+        let (tx, rx) = oneshot::channel();
+        let state = self.lane(lane);
+        let mut pending = state.pending.lock().unwrap();
+
+        let mut entry = Entry {
+            command,
+            done: vec![tx],
+            span: Span::current(),
+        };
+        match Self::merge(&mut pending, &mut entry) {
+            Merge::Into(i) => pending[i].done.append(&mut entry.done),
+            Merge::Cancelled(i) => {
+                // five ups and five downs is nothing at all
+                let cancelled = pending
+                    .remove(i)
+                    .expect("merged with something that's there");
+                cancelled.finish(&Ok(()));
+                entry.finish(&Ok(()));
+            }
+            Merge::No => pending.push_back(entry),
+        }
+        drop(pending);
+
+        state.wake.notify_one();
+        CommandHandle(rx)
+    }
+
+    /// One holder per lane at a time, for read-modify-write sequences (read the ac, step it,
+    /// read it again) that would trip over each other. Plain [`Self::submit`]s don't wait for it.
+    pub async fn hold(&self, lane: Lane) -> OwnedMutexGuard<()> {
+        self.lane(lane).hold.clone().lock_owned().await
+    }
+
+    fn merge(pending: &mut VecDeque<Entry>, entry: &mut Entry) -> Merge {
+        match &entry.command {
+            Command::Set(action) => {
+                // the newer one goes to the back, it was asked for last
+                if let Some(i) = pending
+                    .iter()
+                    .position(|e| matches!(&e.command, Command::Set(a) if a.id == action.id))
+                {
+                    let replaced = pending.remove(i).expect("found it a moment ago");
+                    entry.done.extend(replaced.done);
+                }
+                Merge::No
+            }
+            // presses and steps only fold into the one right before them. low, high, low is
+            // three presses, folding the last low into the first would leave the fan on high
+            Command::Press(id) => match pending.back() {
+                Some(last) if last.command == Command::Press(*id) => Merge::Into(pending.len() - 1),
+                _ => Merge::No,
+            },
+            Command::Step { up, down, n } => {
+                let Some(i) = pending.len().checked_sub(1).filter(
+                    |i| matches!(pending[*i].command, Command::Step { up: u, down: d, .. } if u == *up && d == *down),
+                ) else {
+                    return Merge::No;
+                };
+                let Command::Step { n: waiting, .. } = &mut pending[i].command else {
+                    unreachable!("just matched a step");
+                };
+                *waiting += n;
+                match *waiting {
+                    0 => Merge::Cancelled(i),
+                    _ => Merge::Into(i),
+                }
+            }
+        }
+    }
+
+    fn lane(&self, lane: Lane) -> Arc<LaneState> {
+        let mut lanes = self.lanes.lock().unwrap();
+        if let Some(state) = lanes.get(&lane) {
+            return state.clone();
+        }
+
+        let state = Arc::new(LaneState {
+            pending: StdMutex::new(VecDeque::new()),
+            wake: Notify::new(),
+            hold: Arc::new(Mutex::new(())),
+        });
+        lanes.insert(lane, state.clone());
+        tokio::spawn(Self::work(
+            lane,
+            self.lanes.clone(),
+            state.clone(),
+            self.send.clone(),
+            self.config.pacing.interval(self.object_type(lane)),
+        ));
+        state
+    }
+
+    // objects the config doesn't know about get paced like lights
+    fn object_type(&self, lane: Lane) -> DeviceType {
+        match lane {
+            Lane::Ac(_) => DeviceType::Ac,
+            Lane::Object(id) => self
+                .config
+                .device(id)
+                .map_or(DeviceType::Lights, |d| d.object_type),
+        }
+    }
+
+    async fn work(lane: Lane, lanes: Lanes, state: Arc<LaneState>, send: Sender, pause: Duration) {
+        loop {
+            let next = state.pending.lock().unwrap().pop_front();
+            let Some(entry) = next else {
+                if Self::retire(lane, &lanes, &state) {
+                    return;
+                }
+                _ = time::timeout(IDLE_CHECK, state.wake.notified()).await;
+                continue;
+            };
+
+            let result = async {
+                match &entry.command {
+                    Command::Set(action) => Self::send(&send, action.clone(), pause).await,
+                    Command::Press(id) => Self::send(&send, press(*id), pause).await,
+                    Command::Step { up, down, n } => {
+                        let id = if *n > 0 { *up } else { *down };
+                        let mut result = Ok(());
+                        for _ in 0..n.unsigned_abs() {
+                            result = Self::send(&send, press(id), pause).await;
+                            if result.is_err() {
+                                break;
+                            }
+                        }
+                        result
+                    }
+                }
+            }
+            .instrument(entry.span.clone())
+            .await;
+            entry.finish(&result);
+        }
+    }
+
+    // takes the lane out of the map if nothing's waiting on it and nobody else has a hold of it.
+    // `lane()` hands out clones under the same lock, so anyone about to submit or hold shows up
+    // in the counts here (the map's and ours make two)
+    fn retire(lane: Lane, lanes: &Lanes, state: &Arc<LaneState>) -> bool {
+        let mut lanes = lanes.lock().unwrap();
+        let idle = Arc::strong_count(state) == 2
+            && Arc::strong_count(&state.hold) == 1
+            && state.pending.lock().unwrap().is_empty();
+        if idle {
+            lanes.remove(&lane);
+        }
+        idle
+    }
+
+    async fn send(send: &Sender, action: ActionData, pause: Duration) -> Result<()> {
+        send(action).await?;
+        time::sleep(pause).await;
+        Ok(())
+    }
+}
+
+enum Merge {
+    /// folded into the entry at this index
+    Into(usize),
+    /// folded into the entry at this index, and the two cancel out
+    Cancelled(usize),
+    No,
+}
+
+fn press(id: u16) -> ActionData {
+    ActionData::new(ActionType::Press, id)
+}
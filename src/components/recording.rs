@@ -0,0 +1,194 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io;
+use std::path::PathBuf;
+use tokio::fs::{self, OpenOptions};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream};
+use tokio::sync::mpsc;
+
+const REDACTED: &str = "<redacted>";
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Direction {
+    /// gateway -> us
+    In,
+    /// us -> gateway
+    Out,
+}
+
+/// One line of a recording file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Record {
+    pub at: DateTime<Utc>,
+    pub direction: Direction,
+    /// the frame as json, or as a plain string if it wasn't json
+    pub frame: Value,
+}
+
+impl Record {
+    fn new(direction: Direction, line: &str) -> Self {
+        let line = line.trim();
+        let mut frame =
+            serde_json::from_str(line).unwrap_or_else(|_| Value::String(line.to_string()));
+        redact(&mut frame);
+
+        Self {
+            at: Utc::now(),
+            direction,
+            frame,
+        }
+    }
+
+    // back to what went over the wire (minus the secrets)
+    fn to_line(&self) -> String {
+        let mut line = match &self.frame {
+            Value::String(raw) => raw.clone(),
+            frame => frame.to_string(),
+        };
+        line.push('\n');
+        line
+    }
+}
+
+/// A line with the password and authID blanked out, for printing.
+pub fn redacted(line: &str) -> String {
+    let line = line.trim();
+    match serde_json::from_str::<Value>(line) {
+        Ok(mut frame) => {
+            redact(&mut frame);
+            frame.to_string()
+        }
+        Err(_) => line.to_string(),
+    }
+}
+
+// the password goes out in the login frame and the authID in the reply and every request after
+fn redact(frame: &mut Value) {
+    if let Some(password) = frame.pointer_mut("/data/password") {
+        *password = Value::String(REDACTED.to_string());
+    }
+    if let Some(auth_id) = frame.pointer_mut("/meta/authID") {
+        if !auth_id.is_null() {
+            *auth_id = Value::String(REDACTED.to_string());
+        }
+    }
+}
+
+/// Appends every frame the client sends or gets to a JSONL file.
+#[derive(Clone)]
+pub struct Recorder {
+    tx: mpsc::UnboundedSender<Record>,
+}
+
+impl Recorder {
+    pub fn create(path: PathBuf) -> Self {
+        let (tx, mut rx) = mpsc::unbounded_channel::<Record>();
+
+        tokio::spawn(async move {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await;
+            let mut file = match file {
+                Ok(file) => file,
+                Err(e) => {
+                    tracing::warn!(path = %path.display(), error = %e, "can't record");
+                    return;
+                }
+            };
+
+            while let Some(record) = rx.recv().await {
+                let mut line = serde_json::to_string(&record).unwrap_or_default();
+                line.push('\n');
+                if let Err(e) = file.write_all(line.as_bytes()).await {
+                    tracing::warn!(path = %path.display(), error = %e, "recording stopped");
+                    return;
+                }
+            }
+        });
+
+        Self { tx }
+    }
+
+    pub fn record(&self, direction: Direction, line: &str) {
+        _ = self.tx.send(Record::new(direction, line));
+    }
+}
+
+pub async fn load(path: &PathBuf) -> io::Result<Vec<Record>> {
+    fs::read_to_string(path)
+        .await?
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| serde_json::from_str(l).map_err(io::Error::from))
+        .collect()
+}
+
+/// Plays the gateway's side of a recording into `pipe`: every recorded outbound frame waits
+/// for the client to send the same request, every inbound one gets written back. If the client
+/// asks for something else it gets an error instead and the rest of the recording is skipped,
+/// so a replay notices when what goes out on the wire changes.
+///
+/// Once the recording runs out the pipe stays open, so the client doesn't go reconnecting.
+pub async fn replay(records: Vec<Record>, pipe: DuplexStream) {
+    let (read, mut write) = tokio::io::split(pipe);
+    let mut read = BufReader::new(read);
+    let mut line = String::new();
+
+    for record in records {
+        match record.direction {
+            Direction::Out => {
+                line.clear();
+                if matches!(read.read_line(&mut line).await, Ok(0) | Err(_)) {
+                    return;
+                }
+                let sent = Record::new(Direction::Out, &line).frame;
+                if !same_request(&record.frame, &sent) {
+                    tracing::warn!(recorded = %record.frame, %sent, "replay went off script");
+                    let error = serde_json::json!({
+                        "data": null,
+                        "meta": {
+                            "error": format!("the recording has {} here", record.frame["data"]),
+                            "errorCode": 400,
+                            "requestType": sent.pointer("/meta/requestType"),
+                        },
+                    });
+                    if write
+                        .write_all(format!("{error}\n").as_bytes())
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                    break;
+                }
+            }
+            Direction::In => {
+                if write.write_all(record.to_line().as_bytes()).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    // swallow whatever else the client says until it goes away
+    loop {
+        line.clear();
+        if matches!(read.read_line(&mut line).await, Ok(0) | Err(_)) {
+            return;
+        }
+    }
+}
+
+// the same kind of request about the same thing. logins only have to be logins, whoever's
+// replaying has their own username
+fn same_request(recorded: &Value, sent: &Value) -> bool {
+    let request_type = |frame: &Value| frame.pointer("/meta/requestType").cloned();
+    if request_type(recorded) != request_type(sent) {
+        return false;
+    }
+    request_type(recorded) == Some(Value::from(500)) || recorded.get("data") == sent.get("data")
+}
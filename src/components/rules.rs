@@ -0,0 +1,669 @@
+use crate::components::config::Config;
+use crate::components::cron::{self, CronExpr};
+use crate::components::interra::InterraTcpClient;
+use crate::components::persist;
+use crate::components::scenes::SceneStore;
+use crate::components::scheduler::Action;
+use crate::components::serde_models::DeviceEvent;
+use actix_web::web::Data;
+use chrono::{DateTime, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::PathBuf;
+use std::time::Duration;
+use std::{fmt, io};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, Mutex, Notify};
+use tokio::time;
+
+// evaluations kept around for `/rules/{id}/log`, across every rule
+const LOG_SIZE: usize = 256;
+// a threshold read less than once a day might as well be a time trigger
+const MAX_EVERY: u64 = 24 * 60 * 60;
+// and a debounce longer than a week is just turning the rule off
+const MAX_DEBOUNCE: u64 = 7 * 24 * 60 * 60 * 1000;
+
+/// What a device has to look like. Everything that's set has to match, `above`/`below` go by
+/// its read value (a room temp, a dimmer level...).
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct StateMatch {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub above: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub below: Option<f64>,
+}
+
+impl StateMatch {
+    pub fn matches(&self, active: bool, read_value: Option<&str>) -> bool {
+        let value = read_value.and_then(|v| v.trim().parse::<f64>().ok());
+        self.active.is_none_or(|a| a == active)
+            && self
+                .above
+                .is_none_or(|above| value.is_some_and(|v| v > above))
+            && self
+                .below
+                .is_none_or(|below| value.is_some_and(|v| v < below))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        match (self.above, self.below) {
+            (Some(above), Some(below)) if above >= below => Err(format!(
+                "above {above} and below {below} at the same time? never happens"
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for StateMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(active) = self.active {
+            parts.push(match active {
+                true => "on".to_string(),
+                false => "off".to_string(),
+            });
+        }
+        if let Some(above) = self.above {
+            parts.push(format!("above {above}"));
+        }
+        if let Some(below) = self.below {
+            parts.push(format!("below {below}"));
+        }
+        match parts.is_empty() {
+            true => write!(f, "anything"),
+            false => write!(f, "{}", parts.join(" and ")),
+        }
+    }
+}
+
+/// What sets a rule off.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Trigger {
+    /// the gateway pushes a change to `id` that makes it match (any change at all if nothing's
+    /// set to match)
+    Device {
+        id: String,
+        #[serde(flatten)]
+        state: StateMatch,
+    },
+    /// `id` gets read every `every` seconds, and it goes off when the reading starts matching
+    Threshold {
+        id: String,
+        #[serde(flatten)]
+        state: StateMatch,
+        #[serde(default = "Trigger::default_every")]
+        every: u64,
+    },
+    Time {
+        cron: String,
+        #[serde(default = "cron::utc")]
+        timezone: String,
+    },
+}
+
+impl Trigger {
+    fn default_every() -> u64 {
+        60
+    }
+
+    // the object it watches, for device and threshold triggers
+    fn object(&self, config: &Config) -> Option<u16> {
+        match self {
+            Self::Device { id, .. } | Self::Threshold { id, .. } => config.device_id(id),
+            Self::Time { .. } => None,
+        }
+    }
+
+    fn validate(&self, config: &Config) -> Vec<String> {
+        let mut problems = Vec::new();
+        match self {
+            Self::Device { id, state } => {
+                if config.device_id(id).is_none() {
+                    problems.push(format!("trigger: {id:?} isn't a device"));
+                }
+                problems.extend(state.validate().err());
+            }
+            Self::Threshold { id, state, every } => {
+                if config
+                    .device_id(id)
+                    .and_then(|id| config.locate(id))
+                    .is_none()
+                {
+                    problems.push(format!(
+                        "trigger: {id:?} isn't in the config, so there's nowhere to read it from"
+                    ));
+                }
+                if state.is_empty() {
+                    problems.push("trigger: a threshold needs active, above or below".to_string());
+                }
+                problems.extend(state.validate().err());
+                if *every == 0 {
+                    problems.push("trigger: reading every 0 seconds is a bit much".to_string());
+                }
+                if *every > MAX_EVERY {
+                    problems.push(format!(
+                        "trigger: every is in seconds, {MAX_EVERY} (a day) at the most"
+                    ));
+                }
+            }
+            Self::Time { cron, timezone } => {
+                problems.extend(CronExpr::parse(cron).err());
+                problems.extend(cron::timezone(timezone).err());
+            }
+        }
+        problems
+    }
+
+    // when it next needs looking at: time triggers fire, thresholds get read
+    fn next_check(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Self::Device { .. } => None,
+            Self::Threshold { .. } => Some(now),
+            Self::Time { cron, timezone } => CronExpr::parse(cron)
+                .ok()?
+                .next_after(now, cron::timezone(timezone).ok()?),
+        }
+    }
+}
+
+/// What else has to be true when a rule goes off.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Condition {
+    /// read fresh from the gateway when the rule goes off
+    Device {
+        id: String,
+        #[serde(flatten)]
+        state: StateMatch,
+    },
+    /// wall clock time between `after` and `before`, past midnight if `after` is the later one
+    Time {
+        after: NaiveTime,
+        before: NaiveTime,
+        #[serde(default = "cron::utc")]
+        timezone: String,
+    },
+}
+
+impl Condition {
+    fn validate(&self, config: &Config) -> Vec<String> {
+        let mut problems = Vec::new();
+        match self {
+            Self::Device { id, state } => {
+                if config
+                    .device_id(id)
+                    .and_then(|id| config.locate(id))
+                    .is_none()
+                {
+                    problems.push(format!(
+                        "condition: {id:?} isn't in the config, so there's nowhere to read it from"
+                    ));
+                }
+                if state.is_empty() {
+                    problems.push(format!("condition: {id} has to be... what?"));
+                }
+                problems.extend(state.validate().err());
+            }
+            Self::Time { timezone, .. } => problems.extend(cron::timezone(timezone).err()),
+        }
+        problems
+    }
+
+    async fn check(&self, interra: &InterraTcpClient, now: DateTime<Utc>) -> Check {
+        let result = match self {
+            Self::Device { id, state } => match interra.config().device_id(id) {
+                Some(object_id) => interra
+                    .read_object(object_id)
+                    .await
+                    .map(|object| state.matches(object.active, object.read_value.as_deref()))
+                    .map_err(|e| e.to_string()),
+                None => Err(format!("{id:?} isn't a device")),
+            },
+            Self::Time {
+                after,
+                before,
+                timezone,
+            } => cron::timezone(timezone).map(|tz| {
+                let t = now.with_timezone(&tz).time();
+                match after <= before {
+                    true => *after <= t && t < *before,
+                    false => *after <= t || t < *before,
+                }
+            }),
+        };
+
+        Check {
+            condition: self.to_string(),
+            ok: result.as_ref().is_ok_and(|ok| *ok),
+            error: result.err(),
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Device { id, state } => write!(f, "{id} is {state}"),
+            Self::Time {
+                after,
+                before,
+                timezone,
+            } => write!(f, "between {after} and {before} ({timezone})"),
+        }
+    }
+}
+
+/// When this, if that, do these.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Rule {
+    pub trigger: Trigger,
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+    pub actions: Vec<Action>,
+    /// milliseconds after going off that it won't go off again
+    #[serde(default)]
+    pub debounce: u64,
+    #[serde(default = "Rule::enabled")]
+    pub enabled: bool,
+    /// goes through the motions and logs what it would've done, without doing it
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub last_fired: Option<DateTime<Utc>>,
+    /// when a time trigger fires next, or a threshold gets read next
+    #[serde(default, skip_deserializing)]
+    pub next_check: Option<DateTime<Utc>>,
+    // whether the trigger matched last time we looked, so it only goes off on the way in
+    #[serde(skip)]
+    matching: bool,
+}
+
+impl Rule {
+    fn enabled() -> bool {
+        true
+    }
+
+    /// Everything wrong with it.
+    pub async fn validate(&self, config: &Config, scenes: &SceneStore) -> Result<(), Vec<String>> {
+        let mut problems = self.trigger.validate(config);
+        for condition in &self.conditions {
+            problems.extend(condition.validate(config));
+        }
+        if self.actions.is_empty() {
+            problems.push("a rule that does nothing? bold".to_string());
+        }
+        if self.debounce > MAX_DEBOUNCE {
+            problems.push(format!(
+                "debounce is in milliseconds, {MAX_DEBOUNCE} (a week) at the most"
+            ));
+        }
+        for action in &self.actions {
+            problems.extend(action.validate(config, scenes).await);
+        }
+
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(problems),
+        }
+    }
+
+    // a new reading of the watched object. true if that sets it off
+    fn observe(&mut self, active: bool, read_value: Option<&str>) -> bool {
+        let state = match &self.trigger {
+            Trigger::Device { state, .. } | Trigger::Threshold { state, .. } => state,
+            Trigger::Time { .. } => return false,
+        };
+        // nothing to match means any change does it
+        if state.is_empty() {
+            return true;
+        }
+        let was_matching = self.matching;
+        self.matching = state.matches(active, read_value);
+        self.matching && !was_matching
+    }
+
+    fn debounced(&self, now: DateTime<Utc>) -> bool {
+        // validation keeps it sane, but one too long to count would just never run out
+        let debounce = i64::try_from(self.debounce)
+            .ok()
+            .and_then(chrono::Duration::try_milliseconds);
+        self.last_fired.is_some_and(|last| {
+            debounce.is_none_or(|debounce| now.signed_duration_since(last) < debounce)
+        })
+    }
+}
+
+/// One condition, as it was when a rule went off.
+#[derive(Serialize, Debug, Clone)]
+pub struct Check {
+    pub condition: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Outcome {
+    /// a condition wasn't met (or couldn't be read)
+    ConditionsFailed,
+    /// went off too soon after last time
+    Debounced,
+    /// would've run, but it's a dry run
+    DryRun,
+    Ran,
+    /// ran, and at least one action failed
+    Failed,
+}
+
+/// A rule going off, and what came of it.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Evaluation {
+    pub at: DateTime<Utc>,
+    pub rule: String,
+    /// what set it off
+    pub trigger: String,
+    pub conditions: Vec<Check>,
+    pub outcome: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Watches push frames, reads thresholds and keeps time for the rules, kept in a JSON file.
+pub struct RuleEngine {
+    interra: Data<InterraTcpClient>,
+    scenes: Data<SceneStore>,
+    path: PathBuf,
+    rules: Mutex<BTreeMap<String, Rule>>,
+    log: std::sync::Mutex<VecDeque<Evaluation>>,
+    // poked whenever the rules change so the timer can start over
+    changed: Notify,
+}
+
+impl RuleEngine {
+    /// Loads the rules (checked like the ones coming in through the api) and starts watching.
+    pub async fn start(
+        interra: Data<InterraTcpClient>,
+        scenes: Data<SceneStore>,
+        path: PathBuf,
+    ) -> io::Result<Data<Self>> {
+        let mut rules: BTreeMap<String, Rule> = persist::load(&path).await?;
+
+        let mut problems = Vec::new();
+        for (id, rule) in &rules {
+            if let Err(e) = rule.validate(interra.config(), &scenes).await {
+                problems.extend(e.into_iter().map(|problem| format!("{id}: {problem}")));
+            }
+        }
+        if !problems.is_empty() {
+            return Err(io::Error::other(format!(
+                "{} has {} problem(s):\n  - {}",
+                path.display(),
+                problems.len(),
+                problems.join("\n  - ")
+            )));
+        }
+
+        let now = Utc::now();
+        for rule in rules.values_mut() {
+            rule.next_check = rule.trigger.next_check(now);
+        }
+
+        let events = interra.subscribe();
+        let engine = Data::new(Self {
+            interra,
+            scenes,
+            path,
+            rules: Mutex::new(rules),
+            log: std::sync::Mutex::new(VecDeque::new()),
+            changed: Notify::new(),
+        });
+        tokio::spawn(Self::run(engine.clone(), events));
+        Ok(engine)
+    }
+
+    async fn run(engine: Data<Self>, mut events: broadcast::Receiver<DeviceEvent>) {
+        // the gateway repeats itself, only actual changes count
+        let mut last_seen: HashMap<u16, (bool, Option<String>)> = HashMap::new();
+
+        loop {
+            let next = engine
+                .rules
+                .lock()
+                .await
+                .values()
+                .filter(|r| r.enabled)
+                .filter_map(|r| r.next_check)
+                .min();
+            // nothing timed, sleep until something changes
+            let wait = match next {
+                Some(next) => (next - Utc::now()).to_std().unwrap_or_default(),
+                None => Duration::from_secs(60 * 60 * 24 * 365),
+            };
+
+            tokio::select! {
+                _ = time::sleep(wait) => Self::tick(&engine).await,
+                _ = engine.changed.notified() => {}
+                event = events.recv() => match event {
+                    Ok(event) => {
+                        let state = (event.active, event.read_value.clone());
+                        if last_seen.insert(event.object_id, state.clone()) != Some(state) {
+                            Self::pushed(&engine, &event).await;
+                        }
+                    }
+                    Err(RecvError::Lagged(n)) => {
+                        tracing::warn!(lost = n, "rules fell behind");
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+            }
+        }
+    }
+
+    async fn pushed(engine: &Data<Self>, event: &DeviceEvent) {
+        let config = engine.interra.config();
+        let mut rules = engine.rules.lock().await;
+
+        for (id, rule) in rules.iter_mut() {
+            if !rule.enabled
+                || !matches!(rule.trigger, Trigger::Device { .. })
+                || rule.trigger.object(config) != Some(event.object_id)
+            {
+                continue;
+            }
+            if rule.observe(event.active, event.read_value.as_deref()) {
+                let trigger = describe(event.object_id, event.active, &event.read_value);
+                tokio::spawn(Self::evaluate(engine.clone(), id.clone(), trigger));
+            }
+        }
+    }
+
+    async fn tick(engine: &Data<Self>) {
+        let now = Utc::now();
+        let mut rules = engine.rules.lock().await;
+
+        for (id, rule) in rules.iter_mut() {
+            if !rule.enabled || rule.next_check.is_none_or(|next| next > now) {
+                continue;
+            }
+            match &rule.trigger {
+                Trigger::Time { cron, .. } => {
+                    rule.next_check = rule.trigger.next_check(now);
+                    tokio::spawn(Self::evaluate(engine.clone(), id.clone(), cron.clone()));
+                }
+                Trigger::Threshold { every, .. } => {
+                    // too far off to say when is never
+                    rule.next_check = i64::try_from(*every)
+                        .ok()
+                        .and_then(chrono::Duration::try_seconds)
+                        .and_then(|every| now.checked_add_signed(every));
+                    tokio::spawn(Self::poll(engine.clone(), id.clone()));
+                }
+                Trigger::Device { .. } => rule.next_check = None,
+            }
+        }
+    }
+
+    // reads a threshold's object and sets the rule off if it's just gone into range
+    async fn poll(engine: Data<Self>, id: String) {
+        let config = engine.interra.config();
+        let Some(object_id) = engine
+            .rules
+            .lock()
+            .await
+            .get(&id)
+            .and_then(|rule| rule.trigger.object(config))
+        else {
+            return;
+        };
+
+        let object = match engine.interra.read_object(object_id).await {
+            Ok(object) => object,
+            Err(e) => {
+                tracing::warn!(rule = %id, object_id, error = %e, "rule couldn't read");
+                return;
+            }
+        };
+
+        let fired = match engine.rules.lock().await.get_mut(&id) {
+            Some(rule) => rule.observe(object.active, object.read_value.as_deref()),
+            None => false,
+        };
+        if fired {
+            let trigger = describe(object_id, object.active, &object.read_value);
+            Self::evaluate(engine, id, trigger).await;
+        }
+    }
+
+    async fn evaluate(engine: Data<Self>, id: String, trigger: String) {
+        let Some(rule) = engine.rules.lock().await.get(&id).cloned() else {
+            return;
+        };
+        let now = Utc::now();
+
+        let mut conditions = Vec::new();
+        for condition in &rule.conditions {
+            conditions.push(condition.check(&engine.interra, now).await);
+        }
+        let mut evaluation = Evaluation {
+            at: now,
+            rule: id.clone(),
+            trigger,
+            conditions,
+            outcome: Outcome::ConditionsFailed,
+            error: None,
+        };
+
+        if evaluation.conditions.iter().all(|c| c.ok) {
+            evaluation.outcome = engine.fire(&id, &rule, now).await;
+            if evaluation.outcome == Outcome::Ran {
+                let mut errors = Vec::new();
+                for action in &rule.actions {
+                    if let Err(e) = action.run(&engine.interra, &engine.scenes).await {
+                        errors.push(e);
+                    }
+                }
+                if !errors.is_empty() {
+                    evaluation.outcome = Outcome::Failed;
+                    evaluation.error = Some(errors.join("; "));
+                }
+            }
+        }
+
+        tracing::info!(rule = %id, outcome = ?evaluation.outcome, "rule evaluated");
+        let mut log = engine.log.lock().unwrap();
+        if log.len() == LOG_SIZE {
+            log.pop_front();
+        }
+        log.push_back(evaluation);
+    }
+
+    // the conditions passed. works out whether it's actually going to run, and marks it if so
+    async fn fire(&self, id: &str, rule: &Rule, now: DateTime<Utc>) -> Outcome {
+        let mut rules = self.rules.lock().await;
+        let Some(stored) = rules.get_mut(id) else {
+            return Outcome::ConditionsFailed;
+        };
+        if stored.debounced(now) {
+            return Outcome::Debounced;
+        }
+        if rule.dry_run {
+            return Outcome::DryRun;
+        }
+
+        stored.last_fired = Some(now);
+        if let Err(e) = persist::save(&self.path, &*rules).await {
+            tracing::warn!(error = %e, "couldn't save rules");
+        }
+        Outcome::Ran
+    }
+
+    /// Everything wrong with a rule before it goes in.
+    pub async fn validate(&self, rule: &Rule) -> Result<(), Vec<String>> {
+        rule.validate(self.interra.config(), &self.scenes).await
+    }
+
+    pub async fn list(&self) -> BTreeMap<String, Rule> {
+        self.rules.lock().await.clone()
+    }
+
+    pub async fn get(&self, id: &str) -> Option<Rule> {
+        self.rules.lock().await.get(id).cloned()
+    }
+
+    /// Adds or replaces a rule, starting it over fresh.
+    pub async fn put(&self, id: &str, mut rule: Rule) -> io::Result<Rule> {
+        rule.last_fired = None;
+        rule.next_check = rule.trigger.next_check(Utc::now());
+
+        let mut rules = self.rules.lock().await;
+        rules.insert(id.to_string(), rule.clone());
+        persist::save(&self.path, &*rules).await?;
+        self.changed.notify_one();
+        Ok(rule)
+    }
+
+    /// False if there was nothing to delete.
+    pub async fn delete(&self, id: &str) -> io::Result<bool> {
+        let mut rules = self.rules.lock().await;
+        if rules.remove(id).is_none() {
+            return Ok(false);
+        }
+        persist::save(&self.path, &*rules).await?;
+        self.changed.notify_one();
+        Ok(true)
+    }
+
+    /// The latest evaluations of one rule, oldest first.
+    pub fn log(&self, id: &str) -> Vec<Evaluation> {
+        self.log
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|e| e.rule == id)
+            .cloned()
+            .collect()
+    }
+}
+
+// e.g. "13 off" or "60 on (26.5)"
+fn describe(object_id: u16, active: bool, read_value: &Option<String>) -> String {
+    let state = match active {
+        true => "on",
+        false => "off",
+    };
+    match read_value {
+        Some(value) => format!("{object_id} {state} ({value})"),
+        None => format!("{object_id} {state}"),
+    }
+}
@@ -0,0 +1,281 @@
+use crate::components::config::Config;
+use crate::components::error::{self, InterraError};
+use crate::components::interra::InterraTcpClient;
+use crate::components::persist;
+use crate::components::serde_models::{ACData, LightUpdate};
+use actix_web::web::Data;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::PathBuf;
+use tokio::sync::RwLock;
+
+/// A light as a scene wants it. `id` is anything `/lights/{id}` takes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LightTarget {
+    pub id: String,
+    #[serde(flatten)]
+    pub state: LightUpdate,
+}
+
+/// A room's ac as a scene wants it, same fields as `PATCH /ac`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AcTarget {
+    /// the default room if it's left out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<u16>,
+    #[serde(flatten)]
+    pub state: ACData,
+}
+
+/// A bunch of device states that get applied together: lights first (in order), then the ac,
+/// since that's the slow one.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Scene {
+    #[serde(default)]
+    pub lights: Vec<LightTarget>,
+    #[serde(default)]
+    pub ac: Vec<AcTarget>,
+}
+
+/// How one device in a scene went.
+#[derive(Serialize, Debug, Clone)]
+pub struct Step {
+    /// `light/{name}` or `ac/{room}`
+    pub device: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SceneReport {
+    pub scene: String,
+    /// every step worked
+    pub ok: bool,
+    pub steps: Vec<Step>,
+}
+
+impl Scene {
+    /// Everything in here that can't work, checked before the scene is saved.
+    pub fn validate(&self, config: &Config) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+        if self.lights.is_empty() && self.ac.is_empty() {
+            problems.push("a scene with nothing in it is just sitting in the dark".to_string());
+        }
+
+        for light in &self.lights {
+            if config.light_id(&light.id).is_none() {
+                problems.push(format!("{:?} is NOT a real light", light.id));
+            }
+            if let Err(e) = light.state.validate() {
+                problems.push(format!("light {}: {e}", light.id));
+            }
+        }
+
+        for ac in &self.ac {
+            let Some(room) = ac.room.or_else(|| config.default_room()) else {
+                problems.push("an ac without a room, and there's no default room".to_string());
+                continue;
+            };
+            match config.ac(room) {
+                Some(ac_config) => {
+                    if let Err(e) = ac.state.validate(ac_config) {
+                        problems.push(format!("ac in room {room}: {e}"));
+                    }
+                }
+                None => problems.push(format!("no ac in room {room}")),
+            }
+        }
+
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(problems),
+        }
+    }
+
+    /// Applies every step, carrying on past the ones that fail.
+    pub async fn apply(&self, interra: &InterraTcpClient) -> Vec<Step> {
+        let config = interra.config();
+        let mut steps = Vec::new();
+
+        for light in &self.lights {
+            let result = match config.light_id(&light.id) {
+                Some(object_id) => {
+                    let room = config.room_of(object_id).or_else(|| config.default_room());
+                    match room {
+                        Some(room) => interra
+                            .update_light(room, object_id, &light.state)
+                            .await
+                            .map(|_| ()),
+                        None => Err(InterraError::ConfigMissing(
+                            "no idea what room that's in".to_string(),
+                        )),
+                    }
+                }
+                None => Err(InterraError::UnknownDevice(
+                    "this is NOT a real ID".to_string(),
+                )),
+            };
+            steps.push(Step::new(format!("light/{}", light.id), result));
+        }
+
+        for ac in &self.ac {
+            let step = match ac.room.or_else(|| config.default_room()) {
+                Some(room) => Step::new(
+                    format!("ac/{room}"),
+                    interra
+                        .set_ac_info(room, &ac.state)
+                        .await
+                        .and_then(|result| match result.converged {
+                            true => Ok(()),
+                            false => {
+                                Err(InterraError::Timeout("the ac never got there".to_string()))
+                            }
+                        }),
+                ),
+                None => Step::new(
+                    "ac".to_string(),
+                    Err(InterraError::ConfigMissing("no default room".to_string())),
+                ),
+            };
+            steps.push(step);
+        }
+
+        steps
+    }
+
+    /// What a room looks like right now, as a scene.
+    pub async fn capture(interra: &InterraTcpClient, room_id: u16) -> error::Result<Self> {
+        let lights = interra
+            .get_room_lights(room_id)
+            .await?
+            .into_iter()
+            .map(|light| LightTarget {
+                id: light.id,
+                state: match light.brightness {
+                    Some(brightness) => LightUpdate {
+                        brightness: Some(brightness),
+                        ..LightUpdate::default()
+                    },
+                    None => LightUpdate {
+                        active: Some(light.active),
+                        ..LightUpdate::default()
+                    },
+                },
+            })
+            .collect();
+
+        let mut ac = Vec::new();
+        if interra.config().ac(room_id).is_some() {
+            let state = interra.get_ac_info(room_id).await?;
+            ac.push(AcTarget {
+                room: Some(room_id),
+                // not something a scene can set
+                state: ACData {
+                    room_temp: None,
+                    ..state
+                },
+            });
+        }
+
+        Ok(Self { lights, ac })
+    }
+}
+
+impl Step {
+    fn new(device: String, result: error::Result<()>) -> Self {
+        Self {
+            device,
+            ok: result.is_ok(),
+            error: result.err().map(|e| e.to_string()),
+        }
+    }
+}
+
+/// Scenes by name, kept in a JSON file.
+pub struct SceneStore {
+    path: PathBuf,
+    scenes: RwLock<BTreeMap<String, Scene>>,
+}
+
+impl SceneStore {
+    /// Reads the file if there is one, starts empty if there isn't. Scenes get the same checks
+    /// as the ones coming in through the api, and a file with any bad ones doesn't load.
+    pub async fn load(config: &Config, path: PathBuf) -> io::Result<Data<Self>> {
+        let scenes: BTreeMap<String, Scene> = persist::load(&path).await?;
+
+        let mut problems = Vec::new();
+        for (name, scene) in &scenes {
+            if !valid_name(name) {
+                problems.push(format!("{name:?} isn't a name a scene can have"));
+            }
+            if let Err(e) = scene.validate(config) {
+                problems.extend(e.into_iter().map(|problem| format!("{name}: {problem}")));
+            }
+        }
+        if !problems.is_empty() {
+            return Err(io::Error::other(format!(
+                "{} has {} problem(s):\n  - {}",
+                path.display(),
+                problems.len(),
+                problems.join("\n  - ")
+            )));
+        }
+
+        Ok(Data::new(Self {
+            path,
+            scenes: RwLock::new(scenes),
+        }))
+    }
+
+    pub async fn list(&self) -> BTreeMap<String, Scene> {
+        self.scenes.read().await.clone()
+    }
+
+    pub async fn get(&self, name: &str) -> Option<Scene> {
+        self.scenes.read().await.get(name).cloned()
+    }
+
+    /// Adds or replaces a scene. True if it's new.
+    pub async fn put(&self, name: &str, scene: Scene) -> io::Result<bool> {
+        let mut scenes = self.scenes.write().await;
+        let new = scenes.insert(name.to_string(), scene).is_none();
+        self.save(&scenes).await?;
+        Ok(new)
+    }
+
+    /// Adds a scene, unless there's one called that already. True if it went in.
+    pub async fn insert(&self, name: &str, scene: Scene) -> io::Result<bool> {
+        let mut scenes = self.scenes.write().await;
+        if scenes.contains_key(name) {
+            return Ok(false);
+        }
+        scenes.insert(name.to_string(), scene);
+        self.save(&scenes).await?;
+        Ok(true)
+    }
+
+    /// False if there was nothing to delete.
+    pub async fn delete(&self, name: &str) -> io::Result<bool> {
+        let mut scenes = self.scenes.write().await;
+        if scenes.remove(name).is_none() {
+            return Ok(false);
+        }
+        self.save(&scenes).await?;
+        Ok(true)
+    }
+
+    async fn save(&self, scenes: &BTreeMap<String, Scene>) -> io::Result<()> {
+        persist::save(&self.path, scenes).await
+    }
+}
+
+/// Scene names end up in urls, so keep them boring.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
@@ -0,0 +1,296 @@
+use crate::components::config::Config;
+use crate::components::cron::{self, CronExpr};
+use crate::components::interra::InterraTcpClient;
+use crate::components::persist;
+use crate::components::scenes::{AcTarget, LightTarget, Scene, SceneStore};
+use actix_web::web::Data;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify};
+use tokio::time;
+
+/// When a schedule goes off: a cron expression in a timezone, or once at a fixed time.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum When {
+    Cron {
+        cron: String,
+        /// IANA name like "Europe/Athens", UTC if it's left out
+        #[serde(default = "cron::utc")]
+        timezone: String,
+    },
+    At {
+        at: DateTime<Utc>,
+    },
+}
+
+impl When {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Self::Cron { cron, timezone } = self {
+            CronExpr::parse(cron)?;
+            cron::timezone(timezone)?;
+        }
+        Ok(())
+    }
+
+    /// The next time this goes off after `after`, if it ever does.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Self::Cron { cron, timezone } => CronExpr::parse(cron)
+                .ok()?
+                .next_after(after, timezone.parse().ok()?),
+            Self::At { at } => (*at > after).then_some(*at),
+        }
+    }
+}
+
+/// What a schedule does. Lights and the ac take the same json as in a scene.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Action {
+    Light(LightTarget),
+    Ac(AcTarget),
+    Scene { scene: String },
+}
+
+impl Action {
+    /// Everything wrong with it, given the config and the scenes there are.
+    pub async fn validate(&self, config: &Config, scenes: &SceneStore) -> Vec<String> {
+        match (self, self.as_scene()) {
+            (_, Some(scene)) => scene.validate(config).err().unwrap_or_default(),
+            (Self::Scene { scene }, None) if scenes.get(scene).await.is_none() => {
+                vec![format!("no scene called {scene}")]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    pub async fn run(&self, interra: &InterraTcpClient, scenes: &SceneStore) -> Result<(), String> {
+        let scene = match self {
+            Self::Scene { scene } => scenes
+                .get(scene)
+                .await
+                .ok_or_else(|| format!("no scene called {scene}"))?,
+            _ => self.as_scene().unwrap_or_default(),
+        };
+
+        let failed: Vec<String> = scene
+            .apply(interra)
+            .await
+            .into_iter()
+            .filter(|step| !step.ok)
+            .map(|step| format!("{}: {}", step.device, step.error.unwrap_or_default()))
+            .collect();
+        match failed.is_empty() {
+            true => Ok(()),
+            false => Err(failed.join("; ")),
+        }
+    }
+
+    // lights and the ac run as a one step scene
+    fn as_scene(&self) -> Option<Scene> {
+        match self {
+            Self::Light(light) => Some(Scene {
+                lights: vec![light.clone()],
+                ..Scene::default()
+            }),
+            Self::Ac(ac) => Some(Scene {
+                ac: vec![ac.clone()],
+                ..Scene::default()
+            }),
+            Self::Scene { .. } => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LastRun {
+    pub at: DateTime<Utc>,
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Schedule {
+    #[serde(flatten)]
+    pub when: When,
+    pub action: Action,
+    #[serde(default = "Schedule::enabled")]
+    pub enabled: bool,
+    /// worked out by the scheduler, whatever gets sent in is ignored
+    #[serde(default)]
+    pub next_fire: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub last_run: Option<LastRun>,
+}
+
+impl Schedule {
+    fn enabled() -> bool {
+        true
+    }
+
+    // a one-shot that never got to run (we were down, say) still goes off, just late
+    fn first_fire(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match (&self.when, &self.last_run) {
+            (When::At { at }, None) => Some(*at),
+            (When::At { .. }, Some(_)) => None,
+            (when, _) => when.next_after(now),
+        }
+    }
+}
+
+/// Runs schedules off a single timer, and keeps them (and how they went) in a JSON file.
+pub struct Scheduler {
+    interra: Data<InterraTcpClient>,
+    scenes: Data<SceneStore>,
+    path: PathBuf,
+    schedules: Mutex<BTreeMap<String, Schedule>>,
+    // poked whenever the schedules change so the timer can start over
+    changed: Notify,
+}
+
+impl Scheduler {
+    pub async fn start(
+        interra: Data<InterraTcpClient>,
+        scenes: Data<SceneStore>,
+        path: PathBuf,
+    ) -> io::Result<Data<Self>> {
+        let mut schedules: BTreeMap<String, Schedule> = persist::load(&path).await?;
+        let now = Utc::now();
+        for schedule in schedules.values_mut() {
+            schedule.next_fire = schedule.first_fire(now);
+        }
+
+        let scheduler = Data::new(Self {
+            interra,
+            scenes,
+            path,
+            schedules: Mutex::new(schedules),
+            changed: Notify::new(),
+        });
+        tokio::spawn(Self::run(scheduler.clone()));
+        Ok(scheduler)
+    }
+
+    async fn run(scheduler: Data<Self>) {
+        loop {
+            let next = scheduler
+                .schedules
+                .lock()
+                .await
+                .values()
+                .filter(|s| s.enabled)
+                .filter_map(|s| s.next_fire)
+                .min();
+            // nothing to do, sleep until something changes
+            let wait = match next {
+                Some(next) => (next - Utc::now()).to_std().unwrap_or_default(),
+                None => Duration::from_secs(60 * 60 * 24 * 365),
+            };
+
+            tokio::select! {
+                _ = time::sleep(wait) => Self::fire_due(&scheduler).await,
+                _ = scheduler.changed.notified() => {}
+            }
+        }
+    }
+
+    async fn fire_due(scheduler: &Data<Self>) {
+        let now = Utc::now();
+        let mut schedules = scheduler.schedules.lock().await;
+
+        for (id, schedule) in schedules.iter_mut() {
+            if !schedule.enabled || schedule.next_fire.is_none_or(|next| next > now) {
+                continue;
+            }
+            schedule.next_fire = schedule.when.next_after(now);
+
+            tracing::info!(schedule = %id, "schedule going off");
+            let scheduler = scheduler.clone();
+            let (id, schedule) = (id.clone(), schedule.clone());
+            tokio::spawn(async move {
+                let result = schedule
+                    .action
+                    .run(&scheduler.interra, &scheduler.scenes)
+                    .await;
+                scheduler.finished(&id, result).await;
+            });
+        }
+
+        if let Err(e) = persist::save(&scheduler.path, &*schedules).await {
+            tracing::warn!(error = %e, "couldn't save schedules");
+        }
+    }
+
+    async fn finished(&self, id: &str, result: Result<(), String>) {
+        if let Err(e) = &result {
+            tracing::warn!(schedule = %id, error = %e, "schedule failed");
+        }
+
+        let mut schedules = self.schedules.lock().await;
+        // deleted while it was running
+        let Some(schedule) = schedules.get_mut(id) else {
+            return;
+        };
+        schedule.last_run = Some(LastRun {
+            at: Utc::now(),
+            ok: result.is_ok(),
+            error: result.err(),
+        });
+        if let Err(e) = persist::save(&self.path, &*schedules).await {
+            tracing::warn!(error = %e, "couldn't save schedules");
+        }
+    }
+
+    /// Everything wrong with a schedule before it goes in.
+    pub async fn validate(&self, schedule: &Schedule, config: &Config) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+        if let Err(e) = schedule.when.validate() {
+            problems.push(e);
+        }
+        problems.extend(schedule.action.validate(config, &self.scenes).await);
+
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(problems),
+        }
+    }
+
+    pub async fn list(&self) -> BTreeMap<String, Schedule> {
+        self.schedules.lock().await.clone()
+    }
+
+    pub async fn get(&self, id: &str) -> Option<Schedule> {
+        self.schedules.lock().await.get(id).cloned()
+    }
+
+    /// Adds or replaces a schedule, giving it back with its next fire time filled in.
+    pub async fn put(&self, id: &str, mut schedule: Schedule) -> io::Result<Schedule> {
+        schedule.last_run = None;
+        schedule.next_fire = schedule.first_fire(Utc::now());
+
+        let mut schedules = self.schedules.lock().await;
+        schedules.insert(id.to_string(), schedule.clone());
+        persist::save(&self.path, &*schedules).await?;
+        self.changed.notify_one();
+        Ok(schedule)
+    }
+
+    /// False if there was nothing to delete.
+    pub async fn delete(&self, id: &str) -> io::Result<bool> {
+        let mut schedules = self.schedules.lock().await;
+        if schedules.remove(id).is_none() {
+            return Ok(false);
+        }
+        persist::save(&self.path, &*schedules).await?;
+        self.changed.notify_one();
+        Ok(true)
+    }
+}
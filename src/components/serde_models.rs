@@ -1,4 +1,7 @@
-use serde::{Deserialize, Deserializer, Serialize};
+use crate::components::config::AcConfig;
+use crate::components::protocol::InterraFrame;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
 #[derive(Serialize)]
@@ -9,64 +12,129 @@ pub struct Example {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CustomError {
     pub message: String,
+    /// what kind of error it is, for code. see `InterraError::code` for the gateway ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
 }
 impl CustomError {
+    fn body(message: &str, code: &str) -> String {
+        serde_json::to_string(&Self {
+            message: message.to_string(),
+            code: Some(code.to_string()),
+        })
+        .unwrap()
+    }
+
     pub fn internal_server_error(message: &str) -> actix_web::Error {
-        actix_web::error::ErrorInternalServerError(
-            serde_json::to_string(&Self {
-                message: message.to_string(),
-            })
-            .unwrap(),
-        )
+        actix_web::error::ErrorInternalServerError(Self::body(message, "internal_error"))
     }
 
     pub fn bad_request(message: &str) -> actix_web::Error {
-        actix_web::error::ErrorBadRequest(
-            serde_json::to_string(&Self {
-                message: message.to_string(),
-            })
-            .unwrap(),
-        )
+        actix_web::error::ErrorBadRequest(Self::body(message, "bad_request"))
+    }
+
+    pub fn conflict(message: &str) -> actix_web::Error {
+        actix_web::error::ErrorConflict(Self::body(message, "conflict"))
+    }
+
+    pub fn not_found(message: &str) -> actix_web::Error {
+        actix_web::error::ErrorNotFound(Self::body(message, "not_found"))
     }
 
     pub fn unauthorized(message: &str) -> actix_web::Error {
-        actix_web::error::ErrorUnauthorized(
-            serde_json::to_string(&Self {
-                message: message.to_string(),
-            })
-            .unwrap(),
-        )
+        actix_web::error::ErrorUnauthorized(Self::body(message, "unauthorized"))
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// A light, called by its name from the config (or its object id if it hasn't got one).
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Light {
-    #[serde(deserialize_with = "light_deser")]
     pub id: String,
-    #[serde(rename(deserialize = "isActive"))]
     pub active: bool,
+    /// 0-100, dimmers only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub brightness: Option<u8>,
 }
 
 impl Light {
-    pub fn id_u16(&self) -> Result<u16, actix_web::Error> {
-        match &*self.id {
-            "ceilingLights" => Ok(13),
-            "shelfLight" => Ok(146),
-            _ => Err(CustomError::bad_request("this is NOT a real ID",
-            )),
+    /// A dimmer's level from its `readValue`. Some of them report "40.0" instead of "40".
+    pub fn brightness_from(read_value: Option<&str>) -> Option<u8> {
+        percent(read_value)
+    }
+}
+
+// dimmer levels and cover positions, whatever shape the number shows up in
+fn percent(read_value: Option<&str>) -> Option<u8> {
+    let value = read_value?.trim().parse::<f64>().ok()?;
+    Some(value.round().clamp(0.0, 100.0) as u8)
+}
+
+/// Body of `PATCH /lights/{id}`, at least one of `active`/`brightness`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LightUpdate {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub brightness: Option<u8>,
+    /// get to `brightness` gradually over this many milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fade: Option<u64>,
+}
+
+impl LightUpdate {
+    pub const MAX_FADE: u64 = 30_000;
+
+    pub fn validate(&self) -> Result<(), &'static str> {
+        match (self.active, self.brightness) {
+            (None, None) => Err("terrible json. I am sorry"),
+            (_, Some(b)) if b > 100 => Err("brightness goes from 0 to 100, buddy"),
+            (Some(active), Some(b)) if active != (b > 0) => Err("on at 0%? off at 40%? pick one"),
+            (_, None) if self.fade.is_some() => Err("fade to what? needs a brightness"),
+            _ if self.fade.is_some_and(|f| f > Self::MAX_FADE) => {
+                Err("30 seconds is the longest fade, be patient some other way")
+            }
+            _ => Ok(()),
         }
     }
 }
-fn light_deser<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
-    let id = u16::deserialize(deserializer)?;
-    Ok(String::from(match id {
-        13 => "ceilingLights",
-        146 => "shelfLight",
-        _ => "???",
-    }))
+
+/// A blind or shutter.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Cover {
+    pub id: String,
+    /// 0 is all the way up, 100 all the way down
+    pub position: Option<u8>,
+}
+
+impl Cover {
+    pub fn position_from(read_value: Option<&str>) -> Option<u8> {
+        percent(read_value)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum CoverAction {
+    Up,
+    Down,
+    Stop,
+}
+
+/// Body of `PATCH /covers/{id}`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct CoverUpdate {
+    pub action: CoverAction,
+}
+
+/// What `PATCH /covers/{id}` answers: the gateway took the command, the cover is (probably)
+/// on its way. Covers take their time, so where it ends up is for a GET later.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CoverMove {
+    pub id: String,
+    pub action: CoverAction,
 }
 
-#[derive(Serialize_repr, Deserialize_repr, Debug, Copy, Clone)]
+#[derive(Serialize_repr, Deserialize_repr, Debug, Copy, Clone, PartialEq, Eq)]
 #[repr(u8)]
 pub enum FanSpeed {
     Auto = 0,
@@ -88,14 +156,14 @@ impl FanSpeed {
 
 #[derive(Deserialize)]
 pub struct ACDatum {
-    id: u8,
+    id: u16,
     #[serde(rename = "isActive")]
     active: bool,
     #[serde(rename = "readValue", default)]
     value: String,
 }
 
-#[derive(Serialize, Debug, Deserialize)]
+#[derive(Serialize, Debug, Deserialize, Clone, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct ACData {
     pub room_temp: Option<f64>,
@@ -103,24 +171,68 @@ pub struct ACData {
     pub fan_speed: Option<FanSpeed>,
     pub active: Option<bool>,
 }
-impl From<Vec<ACDatum>> for ACData {
-    fn from(ac: Vec<ACDatum>) -> Self {
+/// The ac as read back after a change, and whether it got where it was asked to go.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ACSetResult {
+    #[serde(flatten)]
+    pub state: ACData,
+    /// false if it still didn't match when time ran out
+    pub converged: bool,
+}
+
+impl From<&DeviceEvent> for ACDatum {
+    fn from(event: &DeviceEvent) -> Self {
+        Self {
+            id: event.object_id,
+            active: event.active,
+            value: event.read_value.clone().unwrap_or_default(),
+        }
+    }
+}
+
+impl From<&RoomObject> for ACDatum {
+    fn from(object: &RoomObject) -> Self {
+        Self {
+            id: object.id,
+            active: object.active,
+            value: object.read_value.clone().unwrap_or_default(),
+        }
+    }
+}
+
+impl ACData {
+    pub fn validate(&self, ac: &AcConfig) -> Result<(), String> {
+        match self.set_temp {
+            Some(t) if t > ac.max_temp => Err(format!("sorry, {} is the max temp!", ac.max_temp)),
+            Some(t) if t < ac.min_temp => Err(format!("sorry, {} is the min temp!", ac.min_temp)),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.room_temp.is_none()
+            && self.set_temp.is_none()
+            && self.fan_speed.is_none()
+            && self.active.is_none()
+    }
+}
+impl ACData {
+    /// Picks the ac's state out of the objects it's read from (see [`AcConfig`]).
+    pub fn read(ac: &[ACDatum], config: &AcConfig) -> Self {
         let room_temp = ac
             .iter()
-            .find(|v| v.id == 60)
-            .map(|v| v.value.parse::<f64>().ok())
-            .flatten();
+            .find(|v| v.id == config.room_temp)
+            .and_then(|v| v.value.parse::<f64>().ok());
         let set_temp = ac
             .iter()
-            .find(|v| v.id == 62)
-            .map(|v| v.value.parse::<f64>().map(|v| v as u8).ok())
-            .flatten();
+            .find(|v| v.id == config.set_temp)
+            .and_then(|v| v.value.parse::<f64>().map(|v| v as u8).ok());
         let fan_speed = ac
             .iter()
-            .find(|v| v.id == 67)
-            .map(|v| FanSpeed::from(&v.value))
-            .flatten();
-        let active = ac.iter().find(|v| v.id == 57).map(|v| v.active);
+            .find(|v| v.id == config.fan_speed)
+            .and_then(|v| FanSpeed::from(&v.value));
+        let active = ac.iter().find(|v| v.id == config.power).map(|v| v.active);
 
         Self {
             room_temp,
@@ -130,3 +242,52 @@ impl From<Vec<ACDatum>> for ACData {
         }
     }
 }
+
+/// A state change the gateway pushed on its own (requestType 19), e.g. someone hit a wall switch.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceEvent {
+    pub object_id: u16,
+    pub active: bool,
+    pub read_value: Option<String>,
+    pub received_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct PushDatum {
+    id: u16,
+    #[serde(rename = "isActive", default)]
+    active: bool,
+    #[serde(rename = "readValue", default)]
+    value: Option<String>,
+}
+
+impl DeviceEvent {
+    pub fn from_frame(frame: &InterraFrame) -> Option<Self> {
+        if !frame.is_push() {
+            return None;
+        }
+        let datum = PushDatum::deserialize(&frame.data).ok()?;
+
+        Some(Self {
+            object_id: datum.id,
+            active: datum.active,
+            read_value: datum.value,
+            received_at: Utc::now(),
+        })
+    }
+}
+
+/// Whatever a room query hands back for one object, before anyone decides what it is.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomObject {
+    pub id: u16,
+    #[serde(rename(deserialize = "isActive"), default)]
+    pub active: bool,
+    #[serde(default)]
+    pub read_value: Option<String>,
+    // not something we've seen in my room, but keep it if the gateway has one
+    #[serde(default)]
+    pub name: Option<String>,
+}
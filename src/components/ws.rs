@@ -0,0 +1,267 @@
+use crate::components::error::InterraError;
+use crate::components::feed::{EventFeed, StateChange};
+use crate::components::interra::InterraTcpClient;
+use crate::components::serde_models::{ACData, CustomError, Light};
+use actix_web::web::Data;
+use actix_ws::{Message, MessageStream, Session};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time;
+
+const PING_EVERY: Duration = Duration::from_secs(30);
+
+/// What a client can send. `id` is echoed back on the reply so it can match them up.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientMessage {
+    #[serde(default)]
+    pub id: Option<Value>,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Command {
+    /// topics look like `rooms/12/lights/ceilingLights` and `rooms/12/ac`. subscribing to the
+    /// start of one (`rooms/12`) gets everything under it, and `*` stands in for any one part
+    /// (`rooms/*/ac`, or just `*` for everything)
+    Subscribe {
+        topics: Vec<String>,
+    },
+    Unsubscribe {
+        topics: Vec<String>,
+    },
+    /// `room` is the config's default room when it's left out, same for the ac ones
+    GetLights {
+        room: Option<u16>,
+    },
+    GetAc {
+        room: Option<u16>,
+    },
+    SwitchLight {
+        light: String,
+        active: bool,
+    },
+    SetAc {
+        room: Option<u16>,
+        #[serde(flatten)]
+        ac: ACData,
+    },
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ServerMessage {
+    Ok {
+        id: Option<Value>,
+        data: Value,
+    },
+    Error {
+        id: Option<Value>,
+        error: CustomError,
+    },
+    #[serde(rename_all = "camelCase")]
+    Event {
+        event_id: u64,
+        topic: String,
+        data: StateChange,
+    },
+}
+
+impl ServerMessage {
+    fn error(id: Option<Value>, e: InterraError) -> Self {
+        Self::Error {
+            id,
+            error: CustomError {
+                message: e.to_string(),
+                code: Some(e.code().to_string()),
+            },
+        }
+    }
+}
+
+fn subscribed(topics: &HashSet<String>, topic: &str) -> bool {
+    topics.iter().any(|subscription| {
+        let mut parts = topic.split('/');
+        subscription
+            .split('/')
+            .all(|want| parts.next().is_some_and(|part| want == "*" || want == part))
+    })
+}
+
+async fn send(session: &mut Session, message: &ServerMessage) -> bool {
+    match serde_json::to_string(message) {
+        Ok(text) => session.text(text).await.is_ok(),
+        Err(_) => true,
+    }
+}
+
+/// Runs one websocket connection until either side hangs up.
+pub async fn serve(
+    mut session: Session,
+    mut messages: MessageStream,
+    interra: Data<InterraTcpClient>,
+    feed: Data<EventFeed>,
+) {
+    let (_, mut changes) = feed.since(None);
+    let mut topics = HashSet::new();
+    let mut ping = time::interval(PING_EVERY);
+    ping.reset();
+
+    loop {
+        tokio::select! {
+            message = messages.next() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Ping(bytes))) => {
+                        if session.pong(&bytes).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+
+                let message = match serde_json::from_str::<ClientMessage>(&text) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        let e = InterraError::Validation(format!("terrible json. I am sorry ({e})"));
+                        let reply = ServerMessage::error(None, e);
+                        if !send(&mut session, &reply).await {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+
+                match message.command {
+                    Command::Subscribe { topics: add } => {
+                        topics.extend(add);
+                        let data = serde_json::json!(topics);
+                        if !send(&mut session, &ServerMessage::Ok { id: message.id, data }).await {
+                            break;
+                        }
+                    }
+                    Command::Unsubscribe { topics: remove } => {
+                        for topic in &remove {
+                            topics.remove(topic);
+                        }
+                        let data = serde_json::json!(topics);
+                        if !send(&mut session, &ServerMessage::Ok { id: message.id, data }).await {
+                            break;
+                        }
+                    }
+                    // anything that talks to the gateway runs on its own so a slow ac change
+                    // doesn't hold up state pushes
+                    command => {
+                        let mut session = session.clone();
+                        let interra = interra.clone();
+                        actix_web::rt::spawn(async move {
+                            let reply = execute(&interra, message.id, command).await;
+                            send(&mut session, &reply).await;
+                        });
+                    }
+                }
+            }
+            change = changes.recv() => {
+                let entry = match change {
+                    Ok(entry) => entry,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                };
+                let topic = entry.change.topic();
+                if !subscribed(&topics, &topic) {
+                    continue;
+                }
+
+                let event = ServerMessage::Event { event_id: entry.id, topic, data: entry.change };
+                if !send(&mut session, &event).await {
+                    break;
+                }
+            }
+            _ = ping.tick() => {
+                if session.ping(b"").await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    _ = session.close(None).await;
+}
+
+async fn execute(interra: &InterraTcpClient, id: Option<Value>, command: Command) -> ServerMessage {
+    let room = |room: Option<u16>| {
+        room.or_else(|| interra.config().default_room())
+            .ok_or_else(|| InterraError::ConfigMissing("which room though".to_string()))
+    };
+
+    let result = match command {
+        Command::GetLights { room: r } => {
+            let room = match room(r) {
+                Ok(room) => room,
+                Err(e) => return ServerMessage::error(id, e),
+            };
+            interra
+                .get_room_lights(room)
+                .await
+                .map(|lights| serde_json::json!(lights))
+        }
+        Command::GetAc { room: r } => {
+            let room = match room(r) {
+                Ok(room) => room,
+                Err(e) => return ServerMessage::error(id, e),
+            };
+            interra
+                .get_ac_info(room)
+                .await
+                .map(|ac| serde_json::json!(ac))
+        }
+        Command::SwitchLight { light, active } => {
+            let Some(object_id) = interra.config().light_id(&light) else {
+                let e = InterraError::UnknownDevice(format!("no light called {light}"));
+                return ServerMessage::error(id, e);
+            };
+            let light = Light {
+                id: interra.config().light_name(object_id),
+                active,
+                brightness: None,
+            };
+            interra
+                .switch_light(object_id, active)
+                .await
+                .map(|_| serde_json::json!(light))
+        }
+        Command::SetAc { room: r, ac } => {
+            let room = match room(r) {
+                Ok(room) => room,
+                Err(e) => return ServerMessage::error(id, e),
+            };
+            let Some(ac_config) = interra.config().ac(room) else {
+                let e = InterraError::UnknownDevice(format!("room {room} has no ac in the config"));
+                return ServerMessage::error(id, e);
+            };
+            if let Err(message) = ac.validate(ac_config) {
+                return ServerMessage::error(id, InterraError::Validation(message));
+            }
+            interra
+                .set_ac_info(room, &ac)
+                .await
+                .map(|ac| serde_json::json!(ac))
+        }
+        Command::Subscribe { .. } | Command::Unsubscribe { .. } => {
+            unreachable!("handled by the connection loop")
+        }
+    };
+
+    match result {
+        Ok(data) => ServerMessage::Ok { id, data },
+        Err(e) => ServerMessage::error(id, e),
+    }
+}
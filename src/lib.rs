@@ -1,56 +1,133 @@
-use actix_web::web::Data;
-use actix_web::{middleware, App, HttpServer};
-use std::env;
+use actix_web::web::{self, Data};
+use actix_web::{App, HttpServer};
 use std::time::Duration;
 use tokio::{io, time};
 
-pub(crate) mod components {
+pub mod components {
     pub mod auth;
+    pub mod cache;
+    pub mod cli;
+    pub mod config;
+    pub mod connection;
+    pub mod cron;
+    pub mod discovery;
     pub mod endpoints;
+    pub mod error;
+    pub mod feed;
     pub mod interra;
+    pub mod logging;
+    pub mod metrics;
+    pub mod mock;
+    pub mod persist;
+    pub mod protocol;
+    pub mod queue;
+    pub mod recording;
+    pub mod rules;
+    pub mod scenes;
+    pub mod scheduler;
     pub mod serde_models;
+    pub mod ws;
 }
+use components::cli::ServeArgs;
+use components::config::Config;
+use components::discovery::{Discovery, DiscoveryOptions};
 use components::endpoints;
+use components::error::InterraError;
+use components::feed::EventFeed;
 use components::interra::InterraTcpClient;
+use components::logging::RequestTracing;
+use components::metrics::RequestMetrics;
+use components::rules::RuleEngine;
+use components::scenes::SceneStore;
+use components::scheduler::Scheduler;
 
-pub async fn run() -> io::Result<()> {
-    env::set_var("RUST_LOG", "actix_web=debug,actix_server=info");
-    env_logger::init();
+/// Runs the api with `config`, until it's stopped.
+pub async fn serve(config: Config, options: ServeArgs) -> io::Result<()> {
+    // doesn't wait for the gateway, the server comes up either way and the client catches up
+    let data = Data::from(InterraTcpClient::start(config.gateway()?, config));
+    let feed = EventFeed::start(data.clone());
+    let walk = DiscoveryOptions::from(&data.config().discovery);
+    let discovery = Discovery::start(data.clone(), walk);
+    let scenes = SceneStore::load(data.config(), options.scenes_file).await?;
+    let scheduler = Scheduler::start(data.clone(), scenes.clone(), options.schedules_file).await?;
+    let rules = RuleEngine::start(data.clone(), scenes.clone(), options.rules_file).await?;
 
-    let interra = InterraTcpClient::connect().await?;
-    let data = Data::new(interra);
-    
     let data_loop = data.clone();
     tokio::spawn(async move {
         loop {
             time::sleep(Duration::from_secs(180)).await;
             if let Err(e) = data_loop.keep_alive().await {
-                println!("error with the ol' loop :// {e}");
+                tracing::warn!(error = %e, "error with the ol' loop :/");
             }
         }
     });
 
-    let ip = if cfg!(debug_assertions) {
-        "localhost:8080"
-    } else {
-        "0.0.0.0:80"
-    };
-
     HttpServer::new(move || {
         App::new()
             .app_data(data.clone())
-            .wrap(middleware::Logger::default())
-            .service(endpoints::root)
-            .service(endpoints::set_light)
-            .service(endpoints::get_lights)
-            .service(endpoints::get_light)
-            .service(endpoints::restart)
-            .service(endpoints::get_ac)
-            .service(endpoints::set_ac)
+            .app_data(feed.clone())
+            .app_data(discovery.clone())
+            .app_data(scenes.clone())
+            .app_data(scheduler.clone())
+            .app_data(rules.clone())
+            .wrap(RequestMetrics)
+            .wrap(RequestTracing)
+            .configure(routes)
     })
-    .bind(ip)?
+    .bind(&options.bind)?
     .run()
     .await?;
 
     Ok(())
 }
+
+/// Every endpoint. Expects `Data<InterraTcpClient>`, `Data<EventFeed>`, `Data<Discovery>`,
+/// `Data<SceneStore>`, `Data<Scheduler>` and `Data<RuleEngine>` in the app data.
+/// Wrap the app in [`RequestMetrics`] for the requests to show up in `/metrics`, and in
+/// [`RequestTracing`] for them to get a span and an `X-Request-Id`.
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    // bodies that aren't json (or aren't the json a route wants) get the same 422 as ones that
+    // are but don't make sense
+    let json = web::JsonConfig::default().error_handler(|e, _| {
+        InterraError::Validation(format!("terrible json. I am sorry ({e})")).into()
+    });
+
+    cfg.app_data(json)
+        .service(endpoints::root)
+        .service(endpoints::set_light)
+        .service(endpoints::get_lights)
+        .service(endpoints::get_light)
+        .service(endpoints::restart)
+        .service(endpoints::get_ac)
+        .service(endpoints::set_ac)
+        .service(endpoints::get_room_lights)
+        .service(endpoints::get_room_light)
+        .service(endpoints::set_room_light)
+        .service(endpoints::get_room_ac)
+        .service(endpoints::set_room_ac)
+        .service(endpoints::get_covers)
+        .service(endpoints::get_room_covers)
+        .service(endpoints::get_cover)
+        .service(endpoints::set_cover)
+        .service(endpoints::get_scenes)
+        .service(endpoints::capture_scene)
+        .service(endpoints::get_scene)
+        .service(endpoints::put_scene)
+        .service(endpoints::delete_scene)
+        .service(endpoints::activate_scene)
+        .service(endpoints::get_schedules)
+        .service(endpoints::get_schedule)
+        .service(endpoints::put_schedule)
+        .service(endpoints::delete_schedule)
+        .service(endpoints::get_rules)
+        .service(endpoints::get_rule)
+        .service(endpoints::put_rule)
+        .service(endpoints::delete_rule)
+        .service(endpoints::get_rule_log)
+        .service(endpoints::events)
+        .service(endpoints::websocket)
+        .service(endpoints::get_rooms)
+        .service(endpoints::get_devices)
+        .service(endpoints::discover)
+        .service(endpoints::get_metrics);
+}
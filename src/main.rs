@@ -1,6 +1,10 @@
+use clap::Parser;
+use interra_api::components::cli::{self, Cli};
+
 #[actix_web::main]
 async fn main() {
-    if let Err(e) = interra_api::run().await {
-        eprintln!("App error: {e}")
+    if let Err(e) = cli::run(Cli::parse()).await {
+        eprintln!("App error: {e}");
+        std::process::exit(1);
     }
 }
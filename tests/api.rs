@@ -0,0 +1,1527 @@
+mod common;
+
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::Payload;
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::web::Data;
+use actix_web::{test, App};
+use interra_api::components::config::Config;
+use interra_api::components::discovery::{Discovery, DiscoveryOptions};
+use interra_api::components::feed::EventFeed;
+use interra_api::components::logging::RequestTracing;
+use interra_api::components::metrics::RequestMetrics;
+use interra_api::components::mock::{Fault, MockDevice, COVERS, DIMMERS, LIGHTS};
+use interra_api::components::protocol::RequestType;
+use interra_api::components::rules::RuleEngine;
+use interra_api::components::scenes::SceneStore;
+use interra_api::components::scheduler::Scheduler;
+use serde_json::{json, Value};
+use std::env;
+use std::future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+// a real app (every route) wired to a client that's logged into a fresh mock gateway
+macro_rules! app {
+    () => {
+        app!(Config::default())
+    };
+    ($config:expr) => {{
+        env::set_var("AUTH_TOKEN", common::TOKEN);
+        let (gateway, client) = common::connected_with($config).await;
+        let client = Data::from(client);
+        let feed = EventFeed::start(client.clone());
+        let discovery = Discovery::start(
+            client.clone(),
+            DiscoveryOptions {
+                rooms: 1..=16,
+                object_types: 1..=4,
+            },
+        );
+        let scenes = SceneStore::load(client.config(), common::temp_path("scenes.json"))
+            .await
+            .unwrap();
+        let scheduler = Scheduler::start(
+            client.clone(),
+            scenes.clone(),
+            common::temp_path("schedules.json"),
+        )
+        .await
+        .unwrap();
+        let rules = RuleEngine::start(
+            client.clone(),
+            scenes.clone(),
+            common::temp_path("rules.json"),
+        )
+        .await
+        .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(client)
+                .app_data(feed)
+                .app_data(discovery)
+                .app_data(scenes)
+                .app_data(scheduler)
+                .app_data(rules)
+                .wrap(RequestMetrics)
+                .wrap(RequestTracing)
+                .configure(interra_api::routes),
+        )
+        .await;
+        (gateway, app)
+    }};
+}
+
+// drops the cache's updatedAt/stale so a body can be compared whole
+fn state(mut body: Value) -> Value {
+    match &mut body {
+        Value::Array(items) => items.iter_mut().for_each(|item| *item = state(item.take())),
+        Value::Object(fields) => {
+            fields.remove("updatedAt");
+            fields.remove("stale");
+        }
+        _ => {}
+    }
+    body
+}
+
+#[actix_web::test]
+async fn needs_the_token() {
+    let (_gateway, app) = app!();
+
+    let req = test::TestRequest::get().uri("/lights").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    let req = test::TestRequest::get()
+        .uri("/lights")
+        .insert_header(("Authorization", "what you think it is"))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn lists_lights() {
+    let (_gateway, app) = app!();
+
+    let req = test::TestRequest::get()
+        .uri("/lights")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+
+    assert_eq!(
+        state(body),
+        json!([
+            { "id": "ceilingLights", "active": false },
+            { "id": "shelfLight", "active": false },
+        ])
+    );
+}
+
+#[actix_web::test]
+async fn gets_one_light() {
+    let (gateway, app) = app!();
+    gateway.update(13, true, None);
+
+    let req = test::TestRequest::get()
+        .uri("/lights/ceilingLights")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        state(body),
+        json!({ "id": "ceilingLights", "active": true })
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/lights/floorLamp")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "unknown_device");
+}
+
+#[actix_web::test]
+async fn switches_a_light() {
+    let (gateway, app) = app!();
+
+    let req = test::TestRequest::patch()
+        .uri("/lights/shelfLight")
+        .insert_header(("Authorization", common::TOKEN))
+        .set_json(json!({ "active": true }))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body, json!({ "id": "shelfLight", "active": true }));
+
+    // the action is fire-and-forget, a read after it is ordered behind it on the link
+    let req = test::TestRequest::get()
+        .uri("/lights/shelfLight")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["active"], true);
+    assert!(gateway.device(146).unwrap().active);
+}
+
+#[actix_web::test]
+async fn reads_the_ac() {
+    let (_gateway, app) = app!();
+
+    let req = test::TestRequest::get()
+        .uri("/ac")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+
+    assert_eq!(
+        state(body),
+        json!({ "roomTemp": 24.38, "setTemp": 23, "fanSpeed": 0, "active": false })
+    );
+}
+
+#[actix_web::test]
+async fn a_silent_gateway_is_a_504() {
+    let mut config = Config::default();
+    config.timeouts.response = 200;
+    let (gateway, app) = app!(config);
+    // waits out the startup walk, so discovery isn't the one that gets ignored
+    let req = test::TestRequest::post()
+        .uri("/devices/discover")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    test::call_service(&app, req).await;
+
+    gateway.inject(Fault::Silence);
+
+    let req = test::TestRequest::get()
+        .uri("/ac?fresh=true")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+}
+
+#[actix_web::test]
+async fn gateway_errors_come_back_with_a_code() {
+    let (gateway, app) = app!();
+    let req = test::TestRequest::post()
+        .uri("/devices/discover")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    test::call_service(&app, req).await;
+
+    gateway.inject(Fault::Refuse(7));
+
+    let req = test::TestRequest::get()
+        .uri("/lights?fresh=true")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "gateway_error");
+    assert!(body["message"].as_str().unwrap().contains("bad day"));
+
+    // and the ones that are the request's fault
+    let req = test::TestRequest::patch()
+        .uri("/lights/999")
+        .insert_header(("Authorization", common::TOKEN))
+        .set_json(json!({ "brightness": 40 }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "unknown_device");
+
+    // a walk where the gateway turns down every room is its fault too, not ours (every room
+    // query has a keep-alive behind it, those get turned down too)
+    for _ in 0..16 * 4 * 2 {
+        gateway.inject(Fault::Refuse(7));
+    }
+    let req = test::TestRequest::post()
+        .uri("/devices/discover")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "gateway_error");
+}
+
+#[actix_web::test]
+async fn reads_say_how_old_they_are() {
+    let (_gateway, app) = app!();
+
+    for uri in ["/lights", "/lights?fresh=true"] {
+        let req = test::TestRequest::get()
+            .uri(uri)
+            .insert_header(("Authorization", common::TOKEN))
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body[0]["stale"], false, "{uri}");
+        assert!(body[0]["updatedAt"].is_string(), "{uri}");
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/ac?fresh=maybe")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn sets_the_ac() {
+    let (gateway, app) = app!();
+
+    let req = test::TestRequest::patch()
+        .uri("/ac")
+        .insert_header(("Authorization", common::TOKEN))
+        .set_json(json!({ "setTemp": 24, "active": true }))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+
+    // what the ac says now, not just what was asked for
+    assert_eq!(
+        body,
+        json!({ "roomTemp": 24.38, "setTemp": 24, "fanSpeed": 0, "active": true, "converged": true })
+    );
+    assert_eq!(
+        gateway.device(62).unwrap().read_value.as_deref(),
+        Some("24")
+    );
+    assert!(gateway.device(57).unwrap().active);
+}
+
+#[actix_web::test]
+async fn rejects_silly_temperatures() {
+    let (gateway, app) = app!();
+
+    let req = test::TestRequest::patch()
+        .uri("/ac")
+        .insert_header(("Authorization", common::TOKEN))
+        .set_json(json!({ "setTemp": 30 }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(
+        gateway.device(62).unwrap().read_value.as_deref(),
+        Some("23")
+    );
+}
+
+#[actix_web::test]
+async fn restart_logs_in_again() {
+    let (gateway, app) = app!();
+
+    let req = test::TestRequest::get()
+        .uri("/restart")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(gateway.logins(), 2);
+}
+
+#[actix_web::test]
+async fn discovers_rooms_and_devices() {
+    let (gateway, app) = app!();
+    gateway.set_device(7, LIGHTS, MockDevice::new(201, true, None));
+
+    let req = test::TestRequest::post()
+        .uri("/devices/discover")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let req = test::TestRequest::get()
+        .uri("/rooms")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let rooms: Value = test::call_and_read_body_json(&app, req).await;
+    let ids: Vec<_> = rooms.as_array().unwrap().iter().map(|r| &r["id"]).collect();
+    assert_eq!(ids, [7, 12]);
+    assert_eq!(rooms[1]["objectTypes"], json!(["lights", "ac"]));
+
+    let req = test::TestRequest::get()
+        .uri("/devices?room=7")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let devices: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(devices[0]["id"], 201);
+    assert_eq!(devices[0]["objectType"], "lights");
+    assert_eq!(devices[0]["active"], true);
+
+    let req = test::TestRequest::get()
+        .uri("/devices?type=ac")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let devices: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(devices.as_array().unwrap().len(), 4);
+
+    // one room the gateway won't talk about doesn't sink the rest of the walk
+    gateway.inject(Fault::Refuse(7));
+    let req = test::TestRequest::post()
+        .uri("/devices/discover")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let registry: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(registry["rooms"].as_object().unwrap().len(), 2);
+    assert_eq!(registry["rooms"]["7"]["devices"][0]["id"], 201);
+}
+
+#[actix_web::test]
+async fn rooms_have_their_own_routes() {
+    let (gateway, app) = app!();
+    gateway.set_device(7, LIGHTS, MockDevice::new(201, true, None));
+
+    let req = test::TestRequest::get()
+        .uri("/rooms/12/lights")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        state(body)[1],
+        json!({ "id": "shelfLight", "active": false })
+    );
+
+    let req = test::TestRequest::get()
+        // the mock adds it without a push, so the cache can't know
+        .uri("/rooms/7/lights?fresh=true")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(state(body), json!([{ "id": "201", "active": true }]));
+
+    // aliases work, the answer has the real name
+    let req = test::TestRequest::patch()
+        .uri("/rooms/12/lights/shelf")
+        .insert_header(("Authorization", common::TOKEN))
+        .set_json(json!({ "active": true }))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body, json!({ "id": "shelfLight", "active": true }));
+
+    let req = test::TestRequest::patch()
+        .uri("/rooms/7/lights/201")
+        .insert_header(("Authorization", common::TOKEN))
+        .set_json(json!({ "active": false }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let req = test::TestRequest::get()
+        .uri("/rooms/7/lights/201")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(state(body), json!({ "id": "201", "active": false }));
+    assert_eq!(gateway.device(146).map(|d| d.active), Some(true));
+}
+
+#[actix_web::test]
+async fn room_routes_say_what_is_wrong() {
+    let (_gateway, app) = app!();
+
+    for (method, uri, status, code) in [
+        (
+            "PATCH",
+            "/rooms/7/lights/ceilingLights",
+            StatusCode::NOT_FOUND,
+            "not_found",
+        ),
+        ("GET", "/rooms/7/ac", StatusCode::NOT_FOUND, "not_found"),
+        (
+            "GET",
+            "/rooms/twelve/lights",
+            StatusCode::BAD_REQUEST,
+            "bad_request",
+        ),
+    ] {
+        let req = match method {
+            "PATCH" => test::TestRequest::patch().set_json(json!({ "active": true })),
+            _ => test::TestRequest::get(),
+        }
+        .uri(uri)
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), status, "{method} {uri}");
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body["code"], code, "{method} {uri}");
+    }
+}
+
+#[actix_web::test]
+async fn sets_a_rooms_ac() {
+    let (gateway, app) = app!();
+
+    let req = test::TestRequest::patch()
+        .uri("/rooms/12/ac")
+        .insert_header(("Authorization", common::TOKEN))
+        .set_json(json!({ "setTemp": 24, "active": true }))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["setTemp"], 24);
+    assert_eq!(body["active"], true);
+
+    let req = test::TestRequest::get()
+        .uri("/rooms/12/ac")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["setTemp"], 24);
+    assert_eq!(
+        gateway.device(62).and_then(|d| d.read_value).as_deref(),
+        Some("24")
+    );
+
+    let req = test::TestRequest::patch()
+        .uri("/rooms/12/ac")
+        .insert_header(("Authorization", common::TOKEN))
+        .set_json(json!({ "setTemp": 30 }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[actix_web::test]
+async fn dims_a_dimmer() {
+    let (gateway, app) = app!();
+    gateway.set_device(12, DIMMERS, MockDevice::new(150, true, Some("40.0")));
+
+    let req = test::TestRequest::get()
+        .uri("/lights/150?fresh=true")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        state(body),
+        json!({ "id": "150", "active": true, "brightness": 40 })
+    );
+
+    let req = test::TestRequest::patch()
+        .uri("/lights/150")
+        .insert_header(("Authorization", common::TOKEN))
+        .set_json(json!({ "brightness": 70 }))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        body,
+        json!({ "id": "150", "active": true, "brightness": 70 })
+    );
+
+    // 70 -> 20 over 600ms is a few steps, ending on the target
+    let req = test::TestRequest::patch()
+        .uri("/lights/150")
+        .insert_header(("Authorization", common::TOKEN))
+        .set_json(json!({ "brightness": 20, "fade": 600 }))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["brightness"], 20);
+
+    let req = test::TestRequest::get()
+        .uri("/lights/150")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["brightness"], 20);
+
+    let levels: Vec<_> = gateway
+        .received()
+        .into_iter()
+        .filter(|f| f.request_type() == Some(RequestType::Action) && f.data["actionType"] == 3)
+        .map(|f| f.data["value"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(levels.first().map(String::as_str), Some("70"));
+    assert_eq!(levels.last().map(String::as_str), Some("20"));
+    assert!(levels.len() > 2, "{levels:?}");
+}
+
+#[actix_web::test]
+async fn brightness_has_rules() {
+    let (gateway, app) = app!();
+    gateway.set_device(12, DIMMERS, MockDevice::new(150, false, Some("0")));
+
+    // the body is fine, the light just doesn't dim
+    let req = test::TestRequest::patch()
+        .uri("/lights/shelfLight")
+        .insert_header(("Authorization", common::TOKEN))
+        .set_json(json!({ "brightness": 50 }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "validation_failed");
+
+    for (uri, body) in [
+        ("/lights/150", json!({ "brightness": 150 })),
+        ("/lights/150", json!({ "active": false, "brightness": 30 })),
+        ("/lights/150", json!({ "active": true, "fade": 1000 })),
+    ] {
+        let req = test::TestRequest::patch()
+            .uri(uri)
+            .insert_header(("Authorization", common::TOKEN))
+            .set_json(&body)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(
+            res.status(),
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "{uri} {body}"
+        );
+    }
+}
+
+#[actix_web::test]
+async fn bodies_that_arent_the_right_json_are_validation_errors() {
+    let (_gateway, app) = app!();
+
+    for (uri, body) in [
+        ("/ac", "{ \"setTemp\": "),
+        ("/ac", "{ \"setTemp\": \"warm\" }"),
+        ("/lights/ceilingLights", "on please"),
+        ("/lights/150", "{ \"brightness\": \"lots\" }"),
+    ] {
+        let req = test::TestRequest::patch()
+            .uri(uri)
+            .insert_header(("Authorization", common::TOKEN))
+            .insert_header(("Content-Type", "application/json"))
+            .set_payload(body)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(
+            res.status(),
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "{uri} {body}"
+        );
+        let error: Value = test::read_body_json(res).await;
+        assert_eq!(error["code"], "validation_failed", "{uri} {body}");
+    }
+}
+
+#[actix_web::test]
+async fn rolls_the_blinds() {
+    let (gateway, app) = app!();
+    gateway.set_device(12, COVERS, MockDevice::new(170, false, Some("35")));
+
+    let req = test::TestRequest::get()
+        .uri("/covers?fresh=true")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(state(body), json!([{ "id": "170", "position": 35 }]));
+
+    let req = test::TestRequest::patch()
+        .uri("/covers/170")
+        .insert_header(("Authorization", common::TOKEN))
+        .set_json(json!({ "action": "down" }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body, json!({ "id": "170", "action": "down" }));
+
+    // where it got to is a read away
+    let req = test::TestRequest::get()
+        .uri("/covers/170?fresh=true")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["position"], 100);
+
+    let req = test::TestRequest::patch()
+        .uri("/covers/170")
+        .insert_header(("Authorization", common::TOKEN))
+        .set_json(json!({ "action": "up" }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+
+    let actions: Vec<_> = gateway
+        .received()
+        .into_iter()
+        .filter(|f| f.request_type() == Some(RequestType::Action))
+        .map(|f| f.data["actionType"].clone())
+        .collect();
+    assert_eq!(actions, [json!(11), json!(10)]);
+
+    for (uri, body, status) in [
+        (
+            "/covers/170",
+            json!({ "action": "sideways" }),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        ),
+        (
+            "/covers/171",
+            json!({ "action": "up" }),
+            StatusCode::NOT_FOUND,
+        ),
+    ] {
+        let req = test::TestRequest::patch()
+            .uri(uri)
+            .insert_header(("Authorization", common::TOKEN))
+            .set_json(&body)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), status, "{uri} {body}");
+    }
+}
+
+#[actix_web::test]
+async fn scenes_round_trip() {
+    let (gateway, app) = app!();
+    let movie_night = json!({
+        "lights": [
+            { "id": "ceilingLights", "active": false },
+            { "id": "shelf", "active": true },
+        ],
+        "ac": [{ "setTemp": 22, "fanSpeed": 1 }],
+    });
+
+    let req = test::TestRequest::put()
+        .uri("/scenes/movieNight")
+        .insert_header(("Authorization", common::TOKEN))
+        .set_json(&movie_night)
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let req = test::TestRequest::get()
+        .uri("/scenes")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        body["movieNight"]["lights"][1],
+        json!({ "id": "shelf", "active": true })
+    );
+    assert_eq!(body["movieNight"]["ac"][0]["setTemp"], 22);
+
+    let req = test::TestRequest::post()
+        .uri("/scenes/movieNight/activate")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["ok"], true, "{body}");
+    let devices: Vec<_> = body["steps"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|s| s["device"].as_str().unwrap())
+        .collect();
+    assert_eq!(devices, ["light/ceilingLights", "light/shelf", "ac/12"]);
+    assert_eq!(gateway.device(146).map(|d| d.active), Some(true));
+    assert_eq!(
+        gateway.device(62).and_then(|d| d.read_value).as_deref(),
+        Some("22")
+    );
+    assert_eq!(
+        gateway.device(67).and_then(|d| d.read_value).as_deref(),
+        Some("01")
+    );
+
+    let req = test::TestRequest::delete()
+        .uri("/scenes/movieNight")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let req = test::TestRequest::get()
+        .uri("/scenes/movieNight")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn bad_scenes_get_every_problem_listed() {
+    let (_gateway, app) = app!();
+
+    let req = test::TestRequest::put()
+        .uri("/scenes/oops")
+        .insert_header(("Authorization", common::TOKEN))
+        .set_json(json!({
+            "lights": [{ "id": "floorLamp", "active": true }],
+            "ac": [{ "setTemp": 30 }, { "room": 7, "active": true }],
+        }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "validation_failed");
+    let message = body["message"].as_str().unwrap();
+    assert!(
+        message.contains("\"floorLamp\" is NOT a real light"),
+        "{message}"
+    );
+    assert!(message.contains("25 is the max temp"), "{message}");
+    assert!(message.contains("no ac in room 7"), "{message}");
+
+    let req = test::TestRequest::put()
+        .uri("/scenes/no%20spaces")
+        .insert_header(("Authorization", common::TOKEN))
+        .set_json(json!({ "lights": [{ "id": "shelf", "active": true }] }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[actix_web::test]
+async fn scenes_report_steps_that_fail() {
+    let (_gateway, app) = app!();
+
+    // fine on paper, but the shelf light can't dim
+    let req = test::TestRequest::put()
+        .uri("/scenes/half")
+        .insert_header(("Authorization", common::TOKEN))
+        .set_json(json!({
+            "lights": [
+                { "id": "shelfLight", "brightness": 50 },
+                { "id": "ceilingLights", "active": true },
+            ],
+        }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let req = test::TestRequest::post()
+        .uri("/scenes/half/activate")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["ok"], false);
+    assert_eq!(body["steps"][0]["ok"], false);
+    assert!(body["steps"][0]["error"]
+        .as_str()
+        .unwrap()
+        .contains("doesn't dim"));
+    assert_eq!(
+        body["steps"][1],
+        json!({ "device": "light/ceilingLights", "ok": true })
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/lights/ceilingLights")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["active"], true);
+}
+
+#[actix_web::test]
+async fn captures_a_room() {
+    let (gateway, app) = app!();
+    gateway.set_device(12, DIMMERS, MockDevice::new(150, true, Some("40")));
+    gateway.update(13, true, None);
+
+    let req = test::TestRequest::post()
+        .uri("/scenes/capture")
+        .insert_header(("Authorization", common::TOKEN))
+        .set_json(json!({ "name": "now" }))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        body["lights"],
+        json!([
+            { "id": "ceilingLights", "active": true },
+            { "id": "shelfLight", "active": false },
+            { "id": "150", "brightness": 40 },
+        ])
+    );
+    assert_eq!(body["ac"][0]["room"], 12);
+    assert_eq!(body["ac"][0]["setTemp"], 23);
+    assert!(body["ac"][0]["roomTemp"].is_null());
+
+    let req = test::TestRequest::post()
+        .uri("/scenes/capture")
+        .insert_header(("Authorization", common::TOKEN))
+        .set_json(json!({ "name": "now" }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+}
+
+#[actix_web::test]
+async fn one_shot_schedules_run_and_report() {
+    let (_gateway, app) = app!();
+    let at = chrono::Utc::now() + chrono::Duration::milliseconds(300);
+
+    let req = test::TestRequest::put()
+        .uri("/schedules/shelfOn")
+        .insert_header(("Authorization", common::TOKEN))
+        .set_json(json!({
+            "at": at,
+            "action": { "type": "light", "id": "shelf", "active": true },
+        }))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["enabled"], true);
+    assert!(body["nextFire"].is_string(), "{body}");
+    assert_eq!(body["lastRun"], Value::Null);
+
+    let mut schedule = Value::Null;
+    for _ in 0..50 {
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let req = test::TestRequest::get()
+            .uri("/schedules/shelfOn")
+            .insert_header(("Authorization", common::TOKEN))
+            .to_request();
+        schedule = test::call_and_read_body_json(&app, req).await;
+        if !schedule["lastRun"].is_null() {
+            break;
+        }
+    }
+    assert_eq!(schedule["lastRun"]["ok"], true, "{schedule}");
+    // one-shots don't go off again
+    assert_eq!(schedule["nextFire"], Value::Null);
+
+    let req = test::TestRequest::get()
+        .uri("/lights/shelf")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["active"], true);
+}
+
+#[actix_web::test]
+async fn cron_schedules_know_when_they_fire() {
+    let (_gateway, app) = app!();
+
+    let req = test::TestRequest::put()
+        .uri("/scenes/night")
+        .insert_header(("Authorization", common::TOKEN))
+        .set_json(json!({ "lights": [{ "id": "ceiling", "active": false }] }))
+        .to_request();
+    test::call_service(&app, req).await;
+
+    let req = test::TestRequest::put()
+        .uri("/schedules/bedtime")
+        .insert_header(("Authorization", common::TOKEN))
+        .set_json(json!({
+            "cron": "30 23 * * *",
+            "timezone": "Europe/Athens",
+            "action": { "type": "scene", "scene": "night" },
+        }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let req = test::TestRequest::get()
+        .uri("/schedules")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    let next: chrono::DateTime<chrono::Utc> =
+        serde_json::from_value(body["bedtime"]["nextFire"].clone()).unwrap();
+    let local = next.with_timezone(&chrono_tz::Europe::Athens);
+    assert_eq!(local.format("%H:%M").to_string(), "23:30");
+    assert!(next > chrono::Utc::now());
+
+    let req = test::TestRequest::delete()
+        .uri("/schedules/bedtime")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let req = test::TestRequest::get()
+        .uri("/schedules/bedtime")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn bad_schedules_get_every_problem_listed() {
+    let (_gateway, app) = app!();
+
+    let req = test::TestRequest::put()
+        .uri("/schedules/oops")
+        .insert_header(("Authorization", common::TOKEN))
+        .set_json(json!({
+            "cron": "61 * * * *",
+            "timezone": "Mars/Olympus",
+            "action": { "type": "scene", "scene": "nope" },
+        }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "validation_failed");
+    let message = body["message"].as_str().unwrap();
+    assert!(message.contains("minute"), "{message}");
+    assert!(message.contains("no scene called nope"), "{message}");
+
+    let req = test::TestRequest::put()
+        .uri("/schedules/oops")
+        .insert_header(("Authorization", common::TOKEN))
+        .set_json(json!({
+            "cron": "@daily",
+            "timezone": "Mars/Olympus",
+            "action": { "type": "ac", "setTemp": 30 },
+        }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "validation_failed");
+    let message = body["message"].as_str().unwrap();
+    assert!(
+        message.contains("\"Mars/Olympus\" isn't a timezone"),
+        "{message}"
+    );
+    assert!(message.contains("25 is the max temp"), "{message}");
+
+    // neither cron nor at
+    let req = test::TestRequest::put()
+        .uri("/schedules/oops")
+        .insert_header(("Authorization", common::TOKEN))
+        .set_json(json!({ "action": { "type": "light", "id": "shelf", "active": true } }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+// a rule's log, once it has at least `n` entries (or whatever it has after a few seconds)
+macro_rules! rule_log {
+    ($app:expr, $id:expr, $n:expr) => {{
+        let mut log = Value::Null;
+        for _ in 0..50 {
+            let req = test::TestRequest::get()
+                .uri(&format!("/rules/{}/log", $id))
+                .insert_header(("Authorization", common::TOKEN))
+                .to_request();
+            log = test::call_and_read_body_json(&$app, req).await;
+            if log.as_array().unwrap().len() >= $n {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        log
+    }};
+}
+
+#[actix_web::test]
+async fn rules_go_off_on_pushed_changes() {
+    let (gateway, app) = app!();
+    gateway.update(146, true, None);
+
+    // a window around right now, and one that's nowhere near it
+    let now = chrono::Utc::now().time();
+    let hour = chrono::Duration::hours(1);
+    for (id, after, before) in [
+        ("shelfFollows", now - hour, now + hour),
+        ("wrongTime", now + hour, now + hour * 2),
+    ] {
+        let req = test::TestRequest::put()
+            .uri(&format!("/rules/{id}"))
+            .insert_header(("Authorization", common::TOKEN))
+            .set_json(json!({
+                "trigger": { "type": "device", "id": "ceilingLights", "active": false },
+                "conditions": [{ "type": "time", "after": after, "before": before }],
+                "actions": [{ "type": "light", "id": "shelf", "active": false }],
+            }))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    gateway.update(13, true, None);
+    gateway.update(13, false, None);
+
+    let log = rule_log!(app, "shelfFollows", 1);
+    assert_eq!(log[0]["outcome"], "ran", "{log}");
+    assert_eq!(log[0]["trigger"], "13 off");
+    assert_eq!(log[0]["conditions"][0]["ok"], true);
+    let log = rule_log!(app, "wrongTime", 1);
+    assert_eq!(log[0]["outcome"], "conditionsFailed", "{log}");
+
+    let req = test::TestRequest::get()
+        .uri("/lights/shelf")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["active"], false);
+
+    let req = test::TestRequest::get()
+        .uri("/rules/shelfFollows")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert!(body["lastFired"].is_string(), "{body}");
+}
+
+#[actix_web::test]
+async fn threshold_rules_read_and_check_conditions() {
+    let (gateway, app) = app!();
+
+    let req = test::TestRequest::put()
+        .uri("/rules/tooHot")
+        .insert_header(("Authorization", common::TOKEN))
+        .set_json(json!({
+            "trigger": { "type": "threshold", "id": "60", "above": 26, "every": 1 },
+            "conditions": [{ "type": "device", "id": "57", "active": false }],
+            "actions": [{ "type": "ac", "active": true, "fanSpeed": 0 }],
+        }))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert!(body["nextCheck"].is_string(), "{body}");
+
+    // 24.38 to start with, nothing happens
+    tokio::time::sleep(std::time::Duration::from_millis(1200)).await;
+    let req = test::TestRequest::get()
+        .uri("/rules/tooHot/log")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let log: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(log, json!([]));
+
+    gateway.update(60, true, Some("26.5"));
+    let log = rule_log!(app, "tooHot", 1);
+    assert_eq!(log[0]["outcome"], "ran", "{log}");
+    assert_eq!(log[0]["trigger"], "60 on (26.5)");
+
+    let req = test::TestRequest::get()
+        .uri("/ac")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["active"], true);
+
+    // still hot on the next reads, but it only goes off on the way in
+    tokio::time::sleep(std::time::Duration::from_millis(2200)).await;
+    assert_eq!(rule_log!(app, "tooHot", 1).as_array().unwrap().len(), 1);
+}
+
+#[actix_web::test]
+async fn rules_debounce_and_dry_run() {
+    let (gateway, app) = app!();
+
+    for (id, dry_run) in [("anyChange", false), ("justLooking", true)] {
+        let req = test::TestRequest::put()
+            .uri(&format!("/rules/{id}"))
+            .insert_header(("Authorization", common::TOKEN))
+            .set_json(json!({
+                "trigger": { "type": "device", "id": "ceiling" },
+                "actions": [{ "type": "light", "id": "shelf", "active": true }],
+                "debounce": 60000,
+                "dryRun": dry_run,
+            }))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    gateway.update(13, true, None);
+    let log = rule_log!(app, "anyChange", 1);
+    assert_eq!(log[0]["outcome"], "ran", "{log}");
+    gateway.update(13, false, None);
+    let log = rule_log!(app, "anyChange", 2);
+    assert_eq!(log[1]["outcome"], "debounced", "{log}");
+
+    // dry runs never fire for real, so they don't get debounced either
+    let log = rule_log!(app, "justLooking", 2);
+    assert_eq!(log[0]["outcome"], "dryRun", "{log}");
+    assert_eq!(log[1]["outcome"], "dryRun", "{log}");
+}
+
+#[actix_web::test]
+async fn bad_rules_get_every_problem_listed() {
+    let (_gateway, app) = app!();
+
+    let req = test::TestRequest::put()
+        .uri("/rules/oops")
+        .insert_header(("Authorization", common::TOKEN))
+        .set_json(json!({
+            "trigger": { "type": "threshold", "id": "999", "above": 30, "below": 20, "every": u64::MAX },
+            "conditions": [{ "type": "time", "after": "23:00", "before": "06:00", "timezone": "Nowhere" }],
+            "actions": [],
+            "debounce": u64::MAX,
+        }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "validation_failed");
+    let message = body["message"].as_str().unwrap();
+    for problem in [
+        "\"999\" isn't in the config",
+        "never happens",
+        "\"Nowhere\" isn't a timezone",
+        "a rule that does nothing",
+        "every is in seconds",
+        "debounce is in milliseconds",
+    ] {
+        assert!(message.contains(problem), "{message}");
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/rules/oops/log")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn metrics_cover_requests_the_link_and_the_house() {
+    let (_gateway, app) = app!();
+
+    for (uri, token) in [
+        ("/lights", common::TOKEN),
+        ("/ac", common::TOKEN),
+        ("/lights", "what you think it is"),
+    ] {
+        let req = test::TestRequest::get()
+            .uri(uri)
+            .insert_header(("Authorization", token))
+            .to_request();
+        test::call_service(&app, req).await;
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/metrics")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+
+    for line in [
+        "interra_http_requests_total{route=\"/lights\",method=\"GET\",status=\"200\"} 1",
+        "interra_http_requests_total{route=\"/lights\",method=\"GET\",status=\"401\"} 1",
+        "interra_http_unauthorized_total 1",
+        "interra_gateway_up 1",
+        "interra_light_on{room=\"12\",light=\"ceilingLights\"} 0",
+        "interra_ac_setpoint_celsius{room=\"12\"} 23",
+        "interra_ac_room_temperature_celsius{room=\"12\"} 24.38",
+        "interra_ac_power{room=\"12\"} 0",
+    ] {
+        assert!(body.lines().any(|l| l == line), "no {line} in\n{body}");
+    }
+    assert!(body.contains("interra_gateway_round_trip_seconds_count{request_type=\"RoomQuery\"}"));
+}
+
+// everything logged on this thread, as JSON lines
+#[derive(Clone, Default)]
+struct Logs(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for Logs {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[actix_web::test]
+async fn a_request_id_follows_the_call_down_to_its_frames() {
+    let logs = Logs::default();
+    let writer = logs.clone();
+    let _logging = tracing::subscriber::set_default(
+        tracing_subscriber::fmt()
+            .json()
+            .with_span_list(true)
+            .with_max_level(tracing::Level::DEBUG)
+            .with_writer(move || writer.clone())
+            .finish(),
+    );
+    let (_gateway, app) = app!();
+
+    let req = test::TestRequest::patch()
+        .uri("/lights/ceilingLights")
+        .insert_header(("Authorization", common::TOKEN))
+        .insert_header(("X-Request-Id", "follow-me"))
+        .set_json(json!({ "active": true }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.headers().get("x-request-id").unwrap(), "follow-me");
+
+    // no id? it gets one anyway
+    let req = test::TestRequest::get()
+        .uri("/lights")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert!(!res.headers().get("x-request-id").unwrap().is_empty());
+
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    let followed = |message: &str| {
+        logs.lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .find(|line| {
+                line["fields"]["message"] == message
+                    && line["spans"]
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .any(|span| span["request_id"] == "follow-me")
+            })
+    };
+    let sent = followed("frame out").expect("the switch went out under the request's id");
+    // the reader task has its own span, but the answer still goes under the request that waited
+    followed("reply in").expect("the answer came back under the request's id");
+    let spans = sent["spans"].as_array().unwrap();
+    assert!(spans
+        .iter()
+        .any(|span| span["name"] == "gateway_send" && span["object_id"] == "13"));
+
+    // the frames had the session and the login had the password, the log has neither
+    assert!(!logs.contains("mock-session"), "{logs}");
+    assert!(!logs.contains("\"password\":\"mock\""), "{logs}");
+}
+
+// the next chunk of a streaming body, or None if nothing shows up for a couple of seconds
+async fn next_chunk(body: &mut BoxBody) -> Option<Bytes> {
+    let chunk = future::poll_fn(|cx| Pin::new(&mut *body).poll_next(cx));
+    tokio::time::timeout(std::time::Duration::from_secs(2), chunk)
+        .await
+        .ok()??
+        .ok()
+}
+
+// splits one `id: ..\nevent: ..\ndata: ..` block into its id, event and parsed data
+fn sse_event(chunk: &[u8]) -> (u64, String, Value) {
+    let text = std::str::from_utf8(chunk).unwrap();
+    let field = |name: &str| {
+        text.lines()
+            .find_map(|line| line.strip_prefix(name))
+            .unwrap_or_else(|| panic!("no {name} in {text:?}"))
+            .to_string()
+    };
+    (
+        field("id: ").parse().unwrap(),
+        field("event: "),
+        serde_json::from_str(&field("data: ")).unwrap(),
+    )
+}
+
+#[actix_web::test]
+async fn pushes_come_out_of_the_event_stream() {
+    let (gateway, app) = app!();
+
+    let req = test::TestRequest::get().uri("/events").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    let req = test::TestRequest::get()
+        .uri("/events")
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.headers().get("Content-Type").unwrap(),
+        "text/event-stream"
+    );
+    let mut body = res.into_body();
+
+    gateway.update(13, true, None);
+    let (light_id, event, data) = sse_event(&next_chunk(&mut body).await.unwrap());
+    assert_eq!(event, "light");
+    assert_eq!(
+        data,
+        json!({ "type": "light", "data": { "room": 12, "id": "ceilingLights", "active": true } })
+    );
+
+    gateway.update(62, true, Some("21"));
+    let (ac_id, event, data) = sse_event(&next_chunk(&mut body).await.unwrap());
+    assert_eq!(event, "ac");
+    assert_eq!(data["data"]["room"], 12);
+    assert_eq!(data["data"]["setTemp"], 21);
+    assert_eq!(ac_id, light_id + 1);
+
+    // the gateway saying the same thing again isn't news
+    gateway.update(62, true, Some("21"));
+    assert!(next_chunk(&mut body).await.is_none());
+
+    // coming back with the first id gets just the one after it, then carries on live
+    let req = test::TestRequest::get()
+        .uri("/events")
+        .insert_header(("Authorization", common::TOKEN))
+        .insert_header(("Last-Event-ID", light_id.to_string()))
+        .to_request();
+    let mut resumed = test::call_service(&app, req).await.into_body();
+    let (id, event, _) = sse_event(&next_chunk(&mut resumed).await.unwrap());
+    assert_eq!((id, event.as_str()), (ac_id, "ac"));
+
+    gateway.update(146, true, None);
+    let (id, _, data) = sse_event(&next_chunk(&mut resumed).await.unwrap());
+    assert_eq!(id, ac_id + 1);
+    assert_eq!(data["data"]["id"], "shelfLight");
+}
+
+// a websocket client for the test service: frames go in through a channel feeding the request
+// body and come back out of the streaming response
+struct WsClient {
+    frames: mpsc::UnboundedSender<Bytes>,
+    body: BoxBody,
+    buffer: Vec<u8>,
+}
+
+impl WsClient {
+    fn handshake() -> test::TestRequest {
+        test::TestRequest::get()
+            .uri("/ws")
+            .insert_header(("Connection", "upgrade"))
+            .insert_header(("Upgrade", "websocket"))
+            .insert_header(("Sec-WebSocket-Version", "13"))
+            .insert_header(("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="))
+    }
+
+    fn payload() -> (mpsc::UnboundedSender<Bytes>, Payload) {
+        let (frames, rx) = mpsc::unbounded_channel();
+        let stream = futures_util::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|frame| (Ok(frame), rx))
+        });
+        (
+            frames,
+            Payload::Stream {
+                payload: Box::pin(stream),
+            },
+        )
+    }
+
+    // clients have to mask what they send
+    fn send(&self, text: &str) {
+        let mask = [7u8, 1, 8, 2];
+        let mut frame = vec![0x81];
+        match text.len() {
+            n if n < 126 => frame.push(0x80 | n as u8),
+            n => {
+                frame.push(0x80 | 126);
+                frame.extend((n as u16).to_be_bytes());
+            }
+        }
+        frame.extend(mask);
+        frame.extend(text.bytes().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+        self.frames.send(Bytes::from(frame)).unwrap();
+    }
+
+    // the next text message, or None if nothing comes
+    async fn recv(&mut self) -> Option<Value> {
+        loop {
+            while let Some((opcode, payload)) = self.frame() {
+                if opcode == 1 {
+                    return Some(serde_json::from_slice(&payload).unwrap());
+                }
+            }
+            let chunk = next_chunk(&mut self.body).await?;
+            self.buffer.extend_from_slice(&chunk);
+        }
+    }
+
+    fn frame(&mut self) -> Option<(u8, Vec<u8>)> {
+        let b = &self.buffer;
+        if b.len() < 2 {
+            return None;
+        }
+        let (len, start) = match b[1] & 0x7f {
+            126 if b.len() >= 4 => (u16::from_be_bytes([b[2], b[3]]) as usize, 4),
+            127 if b.len() >= 10 => (
+                u64::from_be_bytes(b[2..10].try_into().unwrap()) as usize,
+                10,
+            ),
+            126 | 127 => return None,
+            n => (n as usize, 2),
+        };
+        if b.len() < start + len {
+            return None;
+        }
+        let frame = (b[0] & 0x0f, b[start..start + len].to_vec());
+        self.buffer.drain(..start + len);
+        Some(frame)
+    }
+}
+
+#[actix_web::test]
+async fn websocket_subscriptions_and_commands() {
+    let (gateway, app) = app!();
+
+    let res = test::call_service(&app, WsClient::handshake().to_request()).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    let (frames, payload) = WsClient::payload();
+    let (req, _) = WsClient::handshake()
+        .insert_header(("Authorization", common::TOKEN))
+        .to_request()
+        .replace_payload(payload);
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::SWITCHING_PROTOCOLS);
+    let mut ws = WsClient {
+        frames,
+        body: res.into_body(),
+        buffer: Vec::new(),
+    };
+
+    ws.send(r#"{ "id": "sub-1", "type": "subscribe", "topics": ["rooms/*/ac"] }"#);
+    assert_eq!(
+        ws.recv().await.unwrap(),
+        json!({ "type": "ok", "id": "sub-1", "data": ["rooms/*/ac"] })
+    );
+
+    // the light isn't something it asked for, the ac is
+    gateway.update(13, true, None);
+    gateway.update(62, true, Some("21"));
+    let event = ws.recv().await.unwrap();
+    assert_eq!(event["type"], "event");
+    assert_eq!(event["topic"], "rooms/12/ac");
+    assert!(event["eventId"].is_u64());
+    assert_eq!(event["data"]["type"], "ac");
+    assert_eq!(event["data"]["data"]["room"], 12);
+    assert_eq!(event["data"]["data"]["setTemp"], 21);
+
+    // replies carry whatever id the command came with
+    ws.send(r#"{ "id": 2, "type": "switchLight", "light": "shelf", "active": true }"#);
+    assert_eq!(
+        ws.recv().await.unwrap(),
+        json!({ "type": "ok", "id": 2, "data": { "id": "shelfLight", "active": true } })
+    );
+    assert!(gateway.device(146).unwrap().active);
+
+    ws.send(r#"{ "id": 3, "type": "getAc", "room": 3 }"#);
+    let reply = ws.recv().await.unwrap();
+    assert_eq!(
+        (reply["type"].as_str(), &reply["id"]),
+        (Some("error"), &json!(3))
+    );
+    assert_eq!(reply["error"]["code"], "unknown_device");
+
+    ws.send(r#"{ "id": 4, "type": "setAc", "setTemp": 99 }"#);
+    let reply = ws.recv().await.unwrap();
+    assert_eq!(reply["id"], 4);
+    assert_eq!(reply["error"]["code"], "validation_failed");
+
+    ws.send("lights off please");
+    let reply = ws.recv().await.unwrap();
+    assert_eq!(
+        (reply["type"].as_str(), &reply["id"]),
+        (Some("error"), &Value::Null)
+    );
+    assert_eq!(reply["error"]["code"], "validation_failed");
+
+    ws.send(r#"{ "id": "sub-2", "type": "unsubscribe", "topics": ["rooms/*/ac"] }"#);
+    assert_eq!(
+        ws.recv().await.unwrap(),
+        json!({ "type": "ok", "id": "sub-2", "data": [] })
+    );
+    gateway.update(62, true, Some("22"));
+    assert_eq!(ws.recv().await, None);
+}
@@ -0,0 +1,116 @@
+mod common;
+
+use actix_web::web::Data;
+use clap::Parser;
+use interra_api::components::cli::{self, AcCommand, Cli, Command, Fan, LightsCommand};
+use interra_api::components::error::InterraError;
+use interra_api::components::logging::LogFormat;
+
+#[test]
+fn no_command_means_serve() {
+    let cli =
+        Cli::try_parse_from(["interra", "--bind", "0.0.0.0:9000", "--log-format", "json"]).unwrap();
+
+    assert!(cli.command.is_none());
+    assert_eq!(cli.serve.bind, "0.0.0.0:9000");
+    assert_eq!(cli.serve.log_format, LogFormat::Json);
+}
+
+#[test]
+fn serve_flags_dont_go_with_other_commands() {
+    assert!(Cli::try_parse_from(["interra", "--bind", "0.0.0.0:9000", "lights", "list"]).is_err());
+}
+
+#[test]
+fn device_commands_parse() {
+    let cli = Cli::try_parse_from([
+        "interra", "ac", "set", "--temp", "21", "--fan", "fast", "--json",
+    ])
+    .unwrap();
+    assert!(cli.json);
+    assert!(matches!(
+        cli.command,
+        Some(Command::Ac {
+            command: AcCommand::Set {
+                room: None,
+                temp: Some(21),
+                fan: Some(Fan::Fast),
+                power: None,
+            }
+        })
+    ));
+
+    let cli = Cli::try_parse_from(["interra", "discover", "--rooms", "12"]).unwrap();
+    let Some(Command::Discover { rooms, types }) = cli.command else {
+        panic!("not discover: {:?}", cli.command);
+    };
+    assert_eq!((rooms, types), (Some(12..=12), None));
+
+    assert!(Cli::try_parse_from(["interra", "discover", "--rooms", "9-3"]).is_err());
+    assert!(Cli::try_parse_from(["interra", "ac", "set", "--fan", "turbo"]).is_err());
+}
+
+#[tokio::test]
+async fn lights_get_switched_and_listed() {
+    let (gateway, client) = common::connected().await;
+    let client = Data::from(client);
+
+    let on = Command::Lights {
+        command: LightsCommand::On {
+            light: "shelf".to_string(),
+        },
+    };
+    let output = cli::control(client.clone(), on, true).await.unwrap();
+    let light: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(light["id"], "shelfLight");
+    assert_eq!(light["active"], true);
+    assert!(gateway.device(146).unwrap().active);
+
+    let list = Command::Lights {
+        command: LightsCommand::List { room: None },
+    };
+    let output = cli::control(client, list, false).await.unwrap();
+    assert!(output.contains("shelfLight     on"), "{output}");
+    assert!(output.contains("ceilingLights  off"), "{output}");
+}
+
+#[tokio::test]
+async fn ac_set_checks_before_pressing_anything() {
+    let (gateway, client) = common::connected().await;
+    let client = Data::from(client);
+    let set = |temp| Command::Ac {
+        command: AcCommand::Set {
+            room: None,
+            temp,
+            fan: None,
+            power: None,
+        },
+    };
+
+    let too_hot = cli::control(client.clone(), set(Some(99)), false).await;
+    assert!(matches!(too_hot, Err(InterraError::Validation(_))));
+    let nothing = cli::control(client.clone(), set(None), false).await;
+    assert!(matches!(nothing, Err(InterraError::Validation(_))));
+
+    let output = cli::control(client, set(Some(21)), false).await.unwrap();
+    assert!(output.contains("set to 21°"), "{output}");
+    assert_eq!(
+        gateway.device(62).unwrap().read_value.as_deref(),
+        Some("21")
+    );
+}
+
+#[tokio::test]
+async fn discover_lists_what_it_found() {
+    let (_gateway, client) = common::connected().await;
+    let discover = Command::Discover {
+        rooms: Some(12..=13),
+        types: Some(1..=4),
+    };
+
+    let output = cli::control(Data::from(client), discover, false)
+        .await
+        .unwrap();
+    assert!(output.starts_with("room 12:"), "{output}");
+    assert!(output.contains("  146 "), "{output}");
+}
@@ -0,0 +1,629 @@
+mod common;
+
+use actix_web::web::Data;
+use interra_api::components::config::Config;
+use interra_api::components::connection::ConnectionState;
+use interra_api::components::discovery::{Discovery, DiscoveryOptions};
+use interra_api::components::error::InterraError;
+use interra_api::components::interra::InterraTcpClient;
+use interra_api::components::metrics;
+use interra_api::components::mock::{Fault, MockDevice, MockGateway, DIMMERS, LIGHTS};
+use interra_api::components::protocol::{DeviceType, RequestType};
+use interra_api::components::serde_models::{ACData, FanSpeed};
+use std::time::Duration;
+use tokio::time;
+
+#[tokio::test]
+async fn logs_in_and_reads_the_room() {
+    let (gateway, client) = common::connected().await;
+
+    let lights = client.get_room_lights(12).await.unwrap();
+    let ids: Vec<_> = lights.iter().map(|l| l.id.as_str()).collect();
+    assert_eq!(ids, ["ceilingLights", "shelfLight"]);
+
+    let auth = &gateway.received()[0];
+    assert_eq!(auth.request_type(), Some(RequestType::Auth));
+    assert_eq!(auth.data["userName"], "mock");
+    assert_eq!(gateway.logins(), 1);
+
+    // every request after the login carries the session
+    let query = gateway.received().pop().unwrap();
+    assert_eq!(query.meta.auth_id.as_deref(), Some("mock-session"));
+}
+
+#[tokio::test]
+async fn concurrent_reads_get_their_own_replies() {
+    let (gateway, client) = common::connected().await;
+    gateway.inject(Fault::Delay(Duration::from_millis(50)));
+
+    let reads = (0..10).map(|_| {
+        let client = client.clone();
+        async move { tokio::join!(client.get_room_lights(12), client.get_ac_info(12)) }
+    });
+
+    for (lights, ac) in futures_util::future::join_all(reads).await {
+        assert_eq!(lights.unwrap().len(), 2);
+        assert_eq!(ac.unwrap().set_temp, Some(23));
+    }
+}
+
+#[tokio::test]
+async fn push_frames_reach_subscribers() {
+    let (gateway, client) = common::connected().await;
+    let mut events = client.subscribe();
+
+    gateway.update(60, true, Some("26.5"));
+
+    let event = time::timeout(Duration::from_secs(2), events.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(event.object_id, 60);
+    assert_eq!(event.read_value.as_deref(), Some("26.5"));
+}
+
+#[tokio::test]
+async fn push_frames_in_between_dont_get_mistaken_for_replies() {
+    let (gateway, client) = common::connected().await;
+
+    for _ in 0..5 {
+        gateway.update(13, true, None);
+        assert_eq!(client.get_ac_info(12).await.unwrap().room_temp, Some(24.38));
+    }
+}
+
+#[tokio::test]
+async fn switches_lights() {
+    let (gateway, client) = common::connected().await;
+    let mut events = client.subscribe();
+
+    client.switch_light(146, true).await.unwrap();
+
+    let event = time::timeout(Duration::from_secs(2), events.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!((event.object_id, event.active), (146, true));
+    assert!(gateway.device(146).unwrap().active);
+}
+
+#[tokio::test]
+async fn steps_the_ac_to_the_requested_setpoint() {
+    let (gateway, client) = common::connected().await;
+
+    let ac = ACData {
+        set_temp: Some(21),
+        fan_speed: Some(FanSpeed::Fast),
+        active: Some(true),
+        ..ACData::default()
+    };
+    let result = client.set_ac_info(12, &ac).await.unwrap();
+
+    assert!(result.converged);
+    assert_eq!(result.state.set_temp, Some(21));
+    assert_eq!(
+        gateway.device(62).unwrap().read_value.as_deref(),
+        Some("21")
+    );
+    assert_eq!(
+        gateway.device(67).unwrap().read_value.as_deref(),
+        Some("03")
+    );
+    assert!(gateway.device(57).unwrap().active);
+}
+
+// how many times the ac's down button got pressed
+fn temp_down_presses(gateway: &MockGateway) -> usize {
+    gateway
+        .received()
+        .iter()
+        .filter(|f| f.request_type() == Some(RequestType::Action) && f.data["id"] == "63")
+        .count()
+}
+
+#[tokio::test]
+async fn ac_steps_that_get_lost_are_retried() {
+    let (gateway, client) = common::connected().await;
+    gateway.lose_actions(1);
+
+    let ac = ACData {
+        set_temp: Some(21),
+        ..ACData::default()
+    };
+    let result = client.set_ac_info(12, &ac).await.unwrap();
+
+    // 23 -> 21 is two presses, one got lost, so one more after reading back 22
+    assert!(result.converged);
+    assert_eq!(result.state.set_temp, Some(21));
+    assert_eq!(temp_down_presses(&gateway), 3);
+}
+
+#[tokio::test]
+async fn ac_waits_for_a_slow_readback_instead_of_pressing_again() {
+    let (gateway, client) = common::connected().await;
+    // the setpoint shows each press a bit after it's been made
+    gateway.lag(Duration::from_millis(400));
+
+    let ac = ACData {
+        set_temp: Some(21),
+        ..ACData::default()
+    };
+    let result = client.set_ac_info(12, &ac).await.unwrap();
+
+    assert!(result.converged);
+    assert_eq!(result.state.set_temp, Some(21));
+    assert_eq!(temp_down_presses(&gateway), 2);
+    time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(
+        gateway.device(62).unwrap().read_value.as_deref(),
+        Some("21")
+    );
+}
+
+#[tokio::test]
+async fn ac_says_so_when_it_cant_get_there() {
+    let (gateway, client) = common::connected().await;
+    // a setpoint that can't be read can't be stepped from
+    gateway.update(62, true, Some("??"));
+
+    let ac = ACData {
+        set_temp: Some(21),
+        ..ACData::default()
+    };
+    let result = client.set_ac_info(12, &ac).await.unwrap();
+
+    assert!(!result.converged);
+    assert_eq!(result.state.set_temp, None);
+    assert_eq!(temp_down_presses(&gateway), 0);
+}
+
+#[tokio::test]
+async fn ac_setpoint_can_be_written_directly() {
+    let mut config = Config::default();
+    config.rooms[0].ac.as_mut().unwrap().set_temp_direct = true;
+    let (gateway, client) = common::connected_with(config).await;
+
+    let ac = ACData {
+        set_temp: Some(21),
+        ..ACData::default()
+    };
+    let result = client.set_ac_info(12, &ac).await.unwrap();
+
+    assert!(result.converged);
+    assert_eq!(temp_down_presses(&gateway), 0);
+    let writes: Vec<_> = gateway
+        .received()
+        .into_iter()
+        .filter(|f| f.request_type() == Some(RequestType::Action))
+        .collect();
+    assert_eq!(writes.len(), 1);
+    assert_eq!(writes[0].data["id"], "62");
+    assert_eq!(writes[0].data["value"], "21");
+}
+
+#[tokio::test]
+async fn ac_changes_dont_trip_over_each_other() {
+    let (gateway, client) = common::connected().await;
+
+    let warm = ACData {
+        set_temp: Some(25),
+        ..ACData::default()
+    };
+    let cool = ACData {
+        set_temp: Some(20),
+        ..ACData::default()
+    };
+    let (warm, cool) = tokio::join!(client.set_ac_info(12, &warm), client.set_ac_info(12, &cool));
+    let (warm, cool) = (warm.unwrap(), cool.unwrap());
+
+    // one after the other, each one getting where it was going
+    assert!(warm.converged && cool.converged);
+    assert_eq!(warm.state.set_temp, Some(25));
+    assert_eq!(cool.state.set_temp, Some(20));
+    let last = gateway.device(62).unwrap().read_value.unwrap();
+    assert!(last == "25" || last == "20", "{last}");
+}
+
+#[tokio::test]
+async fn reads_survive_a_dropped_connection() {
+    let (gateway, client) = common::connected().await;
+    gateway.inject(Fault::Drop);
+
+    let ac = client.get_ac_info(12).await.unwrap();
+
+    assert_eq!(ac.set_temp, Some(23));
+    assert_eq!(gateway.logins(), 2);
+    assert_eq!(client.status().reconnects, 1);
+    assert_eq!(client.state(), ConnectionState::Ready);
+}
+
+#[tokio::test]
+async fn reconnects_when_the_gateway_hangs_up() {
+    let (gateway, client) = common::connected().await;
+
+    gateway.disconnect_all();
+    time::sleep(Duration::from_millis(100)).await;
+    client.wait_ready(Duration::from_secs(5)).await.unwrap();
+
+    assert_eq!(gateway.logins(), 2);
+    assert!(client.status().last_error.is_some());
+    assert_eq!(client.get_room_lights(12).await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn skips_garbage_lines() {
+    let (gateway, client) = common::connected().await;
+    gateway.inject(Fault::Garbage);
+
+    assert_eq!(client.get_room_lights(12).await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn keep_alive_gets_answered() {
+    let (_gateway, client) = common::connected().await;
+
+    client.keep_alive().await.unwrap();
+}
+
+#[tokio::test]
+async fn comes_up_without_a_gateway() {
+    let gateway = MockGateway::start().await.unwrap();
+    let config = gateway.config();
+    drop(gateway);
+    time::sleep(Duration::from_millis(50)).await;
+
+    let client = InterraTcpClient::start(config, Config::default());
+    time::sleep(Duration::from_millis(200)).await;
+
+    let status = client.status();
+    assert_ne!(status.state, ConnectionState::Ready);
+    assert!(status.last_error.is_some());
+    assert!(client.wait_ready(Duration::from_millis(100)).await.is_err());
+}
+
+#[tokio::test]
+async fn wrong_password_never_gets_ready() {
+    let gateway = MockGateway::start().await.unwrap();
+    let mut config = gateway.config();
+    config.password = "not what you think it is".to_string();
+
+    let client = InterraTcpClient::start(config, Config::default());
+
+    assert!(client.wait_ready(Duration::from_millis(300)).await.is_err());
+    let error = client.status().last_error.unwrap();
+    assert!(error.contains("wrong username or password"), "{error}");
+}
+
+#[tokio::test]
+async fn credentials_with_quotes_and_newlines_log_in() {
+    let gateway = MockGateway::start().await.unwrap();
+    let (username, password) = ("o'brien \"the\" admin", "back\\slash\nnew line\t}{");
+    gateway.set_credentials(username, password);
+    let mut config = gateway.config();
+    config.username = username.to_string();
+    config.password = password.to_string();
+
+    let client = InterraTcpClient::start(config, Config::default());
+    client.wait_ready(Duration::from_secs(2)).await.unwrap();
+
+    // one frame, one line, and the mock read back exactly what was sent
+    let login = gateway
+        .received()
+        .into_iter()
+        .find(|f| f.request_type() == Some(RequestType::Auth))
+        .unwrap();
+    assert_eq!(login.data["userName"], username);
+    assert_eq!(login.data["password"], password);
+    assert_eq!(client.get_room_lights(12).await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn a_restart_that_fails_keeps_the_link_it_had() {
+    let (gateway, client) = common::connected().await;
+    gateway.set_credentials("someone", "else");
+
+    let err = client.reconnect().await.unwrap_err();
+    assert!(matches!(err, InterraError::AuthRejected(_)), "{err}");
+
+    let status = client.status();
+    assert_eq!(status.state, ConnectionState::Ready);
+    assert!(status.last_error.is_some());
+    assert_eq!(status.reconnects, 0);
+    assert_eq!(client.get_room_lights(12).await.unwrap().len(), 2);
+    client.switch_light(13, true).await.unwrap();
+}
+
+fn room_queries(gateway: &MockGateway) -> usize {
+    gateway
+        .received()
+        .iter()
+        .filter(|f| f.request_type() == Some(RequestType::RoomQuery))
+        .count()
+}
+
+#[tokio::test]
+async fn reads_come_out_of_the_cache_once_its_seeded() {
+    let (gateway, client) = common::connected().await;
+
+    client.room_lights(12, false).await.unwrap();
+    let asked = room_queries(&gateway);
+    let lights = client.room_lights(12, false).await.unwrap();
+    assert_eq!(room_queries(&gateway), asked);
+    assert!(lights.iter().all(|l| !l.stale));
+
+    client.room_lights(12, true).await.unwrap();
+    assert!(room_queries(&gateway) > asked);
+}
+
+#[tokio::test]
+async fn pushes_and_writes_keep_the_cache_current() {
+    let (gateway, client) = common::connected().await;
+    client.room_lights(12, false).await.unwrap();
+    let asked = room_queries(&gateway);
+
+    gateway.update(13, true, None);
+    time::sleep(Duration::from_millis(100)).await;
+    client.switch_light(146, true).await.unwrap();
+
+    let lights = client.room_lights(12, false).await.unwrap();
+    assert!(lights.iter().all(|l| l.value.active), "{lights:?}");
+    assert_eq!(room_queries(&gateway), asked);
+}
+
+#[tokio::test]
+async fn stale_reads_get_refreshed_behind_the_scenes() {
+    let mut config = Config::default();
+    config.cache.max_age = 0;
+    let (gateway, client) = common::connected_with(config).await;
+    client.room_lights(12, false).await.unwrap();
+
+    // no push, so only a refresh can find out
+    gateway.set_device(12, LIGHTS, MockDevice::new(13, true, None));
+    time::sleep(Duration::from_millis(10)).await;
+
+    let lights = client.room_lights(12, false).await.unwrap();
+    assert!(lights.iter().all(|l| l.stale));
+    assert!(!lights[0].value.active);
+
+    time::sleep(Duration::from_millis(100)).await;
+    let lights = client.room_lights(12, false).await.unwrap();
+    assert!(lights
+        .iter()
+        .any(|l| l.value.id == "ceilingLights" && l.value.active));
+}
+
+#[tokio::test]
+async fn writes_nobody_backs_up_stay_unconfirmed() {
+    let (gateway, client) = common::connected().await;
+    client.room_lights(12, false).await.unwrap();
+
+    // the gateway takes it without a word and does nothing, like when it loses one
+    gateway.lose_actions(1);
+    client.switch_light(146, true).await.unwrap();
+    let lights = client.room_lights(12, false).await.unwrap();
+    let desk = lights
+        .iter()
+        .find(|l| l.value.id != "ceilingLights")
+        .unwrap();
+    assert!(desk.value.active && desk.stale, "{desk:?}");
+
+    // and the refresh that set off finds out it never happened
+    time::sleep(Duration::from_millis(100)).await;
+    let lights = client.room_lights(12, false).await.unwrap();
+    assert!(
+        lights.iter().all(|l| !l.value.active && !l.stale),
+        "{lights:?}"
+    );
+
+    // one that gets pushed back is as good as read
+    client.switch_light(146, true).await.unwrap();
+    let lights = client.room_lights(12, false).await.unwrap();
+    assert!(lights.iter().all(|l| !l.stale), "{lights:?}");
+}
+
+#[tokio::test]
+async fn a_failed_write_leaves_the_cache_alone() {
+    let (gateway, client) = common::connected().await;
+    client.room_lights(12, false).await.unwrap();
+
+    gateway.expire_session();
+    gateway.set_credentials("someone", "else");
+    client.switch_light(146, true).await.unwrap_err();
+
+    let lights = client.room_lights(12, false).await.unwrap();
+    assert!(
+        lights.iter().all(|l| !l.value.active && !l.stale),
+        "{lights:?}"
+    );
+}
+
+#[tokio::test]
+async fn empty_rooms_get_refreshed_too() {
+    let mut config = Config::default();
+    config.cache.max_age = 0;
+    let (gateway, client) = common::connected_with(config).await;
+    assert_eq!(client.room_lights(12, false).await.unwrap().len(), 2);
+
+    // room 12 had no dimmers when it was read, now it's got one
+    gateway.set_device(12, DIMMERS, MockDevice::new(150, true, Some("40")));
+    time::sleep(Duration::from_millis(10)).await;
+    client.room_lights(12, false).await.unwrap();
+
+    time::sleep(Duration::from_millis(100)).await;
+    let lights = client.room_lights(12, false).await.unwrap();
+    assert_eq!(lights.len(), 3, "{lights:?}");
+}
+
+#[tokio::test]
+async fn unanswered_reads_time_out_and_the_link_gets_replaced() {
+    let mut config = Config::default();
+    config.timeouts.response = 200;
+    let (gateway, client) = common::connected_with(config).await;
+    gateway.inject(Fault::Silence);
+
+    let start = time::Instant::now();
+    let err = client.get_room_lights(12).await.unwrap_err();
+    assert!(matches!(err, InterraError::Timeout(_)), "{err}");
+    assert!(start.elapsed() < Duration::from_secs(3));
+
+    client.wait_ready(Duration::from_secs(5)).await.unwrap();
+    assert_eq!(client.get_room_lights(12).await.unwrap().len(), 2);
+    assert!(gateway.logins() >= 2);
+}
+
+#[tokio::test]
+async fn a_login_nobody_answers_times_out() {
+    let gateway = MockGateway::start().await.unwrap();
+    gateway.inject(Fault::Silence);
+    let mut config = Config::default();
+    config.timeouts.response = 200;
+
+    let client = InterraTcpClient::start(gateway.config(), config);
+    time::sleep(Duration::from_millis(400)).await;
+    assert!(client.status().last_error.unwrap().contains("too long"));
+
+    // the next go gets an answer
+    client.wait_ready(Duration::from_secs(5)).await.unwrap();
+}
+
+#[tokio::test]
+async fn a_read_given_up_on_doesnt_answer_the_next_one() {
+    let (gateway, client) = common::connected().await;
+    gateway.inject(Fault::Delay(Duration::from_millis(300)));
+
+    let abandoned = time::timeout(Duration::from_millis(50), client.get_room_lights(12)).await;
+    assert!(abandoned.is_err());
+
+    // the late lights answer goes to the read nobody's waiting on anymore
+    let ac = client.get_ac_info(12).await.unwrap();
+    assert_eq!(ac.set_temp, Some(23));
+    assert_eq!(client.state(), ConnectionState::Ready);
+}
+
+#[tokio::test]
+async fn an_expired_session_logs_in_again_and_carries_on() {
+    let (gateway, client) = common::connected().await;
+    gateway.expire_session();
+
+    // two at once, only one of them should have to log in
+    let (lights, ac) = tokio::join!(client.get_room_lights(12), client.get_ac_info(12));
+    assert_eq!(lights.unwrap().len(), 2);
+    assert_eq!(ac.unwrap().set_temp, Some(23));
+
+    assert_eq!(gateway.logins(), 2);
+    let status = client.status();
+    assert_eq!(status.reauths, 1);
+    // same link the whole time
+    assert_eq!(status.reconnects, 0);
+    assert_eq!(client.state(), ConnectionState::Ready);
+}
+
+#[tokio::test]
+async fn a_session_that_cant_be_got_back_is_an_auth_error() {
+    let (gateway, client) = common::connected().await;
+    gateway.expire_session();
+    gateway.set_credentials("someone", "else");
+
+    let err = client.get_room_lights(12).await.unwrap_err();
+    assert!(matches!(err, InterraError::AuthRejected(_)), "{err}");
+    assert_eq!(client.status().auth_failures, 1);
+}
+
+#[tokio::test]
+async fn an_action_on_an_expired_session_is_sent_again_after_logging_in() {
+    let (gateway, client) = common::connected().await;
+    gateway.expire_session();
+
+    client.switch_light(13, true).await.unwrap();
+
+    assert!(gateway.device(13).unwrap().active);
+    let actions = gateway
+        .received()
+        .iter()
+        .filter(|f| f.request_type() == Some(RequestType::Action))
+        .count();
+    assert_eq!(actions, 2);
+    assert_eq!(gateway.logins(), 2);
+    let status = client.status();
+    assert_eq!(status.reauths, 1);
+    assert_eq!(status.reconnects, 0);
+}
+
+#[tokio::test]
+async fn an_action_the_gateway_turns_down_is_an_error() {
+    let (gateway, client) = common::connected().await;
+    // the keep-alive behind it gets the refusal, the action has nobody to tell
+    gateway.expire_session();
+    gateway.set_credentials("someone", "else");
+
+    let err = client.switch_light(13, true).await.unwrap_err();
+    assert!(matches!(err, InterraError::AuthRejected(_)), "{err}");
+    assert!(!gateway.device(13).unwrap().active);
+}
+
+#[tokio::test]
+async fn only_session_codes_get_an_action_sent_again() {
+    let (gateway, client) = common::connected().await;
+    gateway.refuse_action(500, "token bucket is empty, slow down");
+
+    let err = client.switch_light(13, true).await.unwrap_err();
+    assert!(matches!(err, InterraError::Gateway { .. }), "{err}");
+    assert!(!err.is_auth());
+    let actions = gateway
+        .received()
+        .iter()
+        .filter(|f| f.request_type() == Some(RequestType::Action))
+        .count();
+    assert_eq!(actions, 1);
+    assert_eq!(gateway.logins(), 1);
+    assert!(!gateway.device(13).unwrap().active);
+
+    // a string code still counts
+    let err = InterraError::from_meta(Some(&"nope".into()), Some(&"403".into())).unwrap();
+    assert!(err.is_auth());
+}
+
+#[tokio::test]
+async fn keep_alives_and_junk_get_counted() {
+    let mut config = Config::default();
+    config.timeouts.response = 200;
+    let (gateway, client) = common::connected_with(config).await;
+
+    gateway.inject(Fault::Garbage);
+    client.keep_alive().await.unwrap();
+    gateway.inject(Fault::Silence);
+    assert!(client.keep_alive().await.is_err());
+
+    let metrics = metrics::render(&client);
+    for line in [
+        "interra_gateway_keep_alives_total 2",
+        "interra_gateway_keep_alive_failures_total 1",
+        "interra_gateway_frames_skipped_total 1",
+    ] {
+        assert!(
+            metrics.lines().any(|l| l == line),
+            "no {line} in\n{metrics}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn discovery_takes_silence_for_nothing_there() {
+    let (gateway, client) = common::connected().await;
+    let client = Data::from(client);
+    let options = DiscoveryOptions {
+        rooms: 12..=12,
+        object_types: 1..=4,
+    };
+    let discovery = Discovery::new(client.clone(), options);
+
+    // room 12's lights get no answer at all
+    gateway.inject(Fault::Silence);
+    let registry = discovery.discover().await.unwrap();
+
+    assert_eq!(registry.rooms[&12].object_types, [DeviceType::Ac]);
+    let status = client.status();
+    assert_eq!(status.state, ConnectionState::Ready);
+    assert_eq!(status.reconnects, 0);
+    assert_eq!(gateway.logins(), 1);
+}
@@ -0,0 +1,35 @@
+// shared by several test binaries, not all of them use everything
+#![allow(dead_code)]
+
+use interra_api::components::config::Config;
+use interra_api::components::interra::InterraTcpClient;
+use interra_api::components::mock::MockGateway;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const TOKEN: &str = "test token";
+
+/// A fresh mock gateway and a client that's already logged into it.
+pub async fn connected() -> (MockGateway, Arc<InterraTcpClient>) {
+    connected_with(Config::default()).await
+}
+
+pub async fn connected_with(config: Config) -> (MockGateway, Arc<InterraTcpClient>) {
+    let gateway = MockGateway::start().await.expect("mock gateway binds");
+    let client = InterraTcpClient::start(gateway.config(), config);
+    client
+        .wait_ready(Duration::from_secs(5))
+        .await
+        .expect("client logs into the mock");
+
+    (gateway, client)
+}
+
+/// A file name in the temp dir nothing else in this run is using.
+pub fn temp_path(name: &str) -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("interra-{}-{n}-{name}", std::process::id()))
+}
@@ -0,0 +1,338 @@
+mod common;
+
+use actix_web::web::Data;
+use interra_api::components::config::{Config, Format};
+use interra_api::components::mock::{MockDevice, LIGHTS};
+use interra_api::components::rules::RuleEngine;
+use interra_api::components::scenes::{Scene, SceneStore};
+use interra_api::components::serde_models::ACData;
+use serde_json::json;
+use std::fs;
+
+const ROOM: &str = r#"
+[[rooms]]
+id = 7
+
+[[rooms.devices]]
+id = 201
+type = "lights"
+name = "deskLamp"
+aliases = ["desk"]
+
+[rooms.ac]
+power = 1
+room_temp = 2
+set_temp = 3
+fan_speed = 4
+power_on = 5
+power_off = 6
+temp_up = 7
+temp_down = 8
+min_temp = 18
+max_temp = 28
+fan = { auto = 9, slow = 10, medium = 11, fast = 12 }
+"#;
+
+#[test]
+fn built_in_config_is_my_room() {
+    let config = Config::default();
+    assert!(config.validate().is_ok());
+
+    assert_eq!(config.light_name(13), "ceilingLights");
+    assert_eq!(config.light_id("shelfLight"), Some(146));
+    assert_eq!(config.light_id("SHELF"), Some(146));
+    // unnamed lights go by their id instead of "???"
+    assert_eq!(config.light_name(201), "201");
+    assert_eq!(config.light_id("201"), Some(201));
+    assert_eq!(config.light_id("kitchen"), None);
+    // an id the config says is something else isn't a light
+    assert_eq!(config.light_id("63"), None);
+    assert_eq!(config.light_id("60"), None);
+    assert_eq!(config.cover_id("13"), None);
+    assert_eq!(config.device_id("60"), Some(60));
+
+    let ac = config.ac(12).unwrap();
+    assert_eq!((ac.min_temp, ac.max_temp), (20, 25));
+    assert_eq!(ac.temp_up, 64);
+}
+
+#[test]
+fn toml_and_json_say_the_same_thing() {
+    let toml = Config::parse(ROOM, Format::Toml).unwrap();
+    let json = Config::parse(
+        r#"{"rooms": [{"id": 7, "devices": [{"id": 201, "type": "lights", "name": "deskLamp",
+            "aliases": ["desk"]}], "ac": {"power": 1, "room_temp": 2, "set_temp": 3,
+            "fan_speed": 4, "power_on": 5, "power_off": 6, "temp_up": 7, "temp_down": 8,
+            "min_temp": 18, "max_temp": 28,
+            "fan": {"auto": 9, "slow": 10, "medium": 11, "fast": 12}}}]}"#,
+        Format::Json,
+    )
+    .unwrap();
+
+    for config in [toml, json] {
+        assert!(config.validate().is_ok());
+        assert_eq!(config.light_id("desk"), Some(201));
+        let ac = config.ac(7).unwrap();
+        assert_eq!((ac.min_temp, ac.max_temp, ac.fan.fast), (18, 28, 12));
+        assert!(config.ac(12).is_none());
+    }
+}
+
+#[test]
+fn setpoint_limits_come_from_the_config() {
+    let config = Config::parse(ROOM, Format::Toml).unwrap();
+    let ac = config.ac(7).unwrap();
+
+    let set = |t| ACData {
+        set_temp: Some(t),
+        ..ACData::default()
+    };
+    assert!(set(18).validate(ac).is_ok());
+    assert!(set(28).validate(ac).is_ok());
+    assert_eq!(
+        set(29).validate(ac).unwrap_err(),
+        "sorry, 28 is the max temp!"
+    );
+    assert_eq!(
+        set(17).validate(ac).unwrap_err(),
+        "sorry, 18 is the min temp!"
+    );
+}
+
+#[test]
+fn discovery_ranges_come_from_the_config() {
+    let config = Config::parse("", Format::Toml).unwrap();
+    assert_eq!(config.discovery.rooms, 1..=32);
+    assert_eq!(config.discovery.object_types, 1..=10);
+
+    let config = Config::parse(
+        "[discovery]\nrooms = \"40-60\"\nobject_types = \"4\"\n",
+        Format::Toml,
+    )
+    .unwrap();
+    assert_eq!(config.discovery.rooms, 40..=60);
+    assert_eq!(config.discovery.object_types, 4..=4);
+
+    let err = Config::parse("[discovery]\nrooms = \"9-3\"\n", Format::Toml).unwrap_err();
+    assert!(err.to_string().contains("backwards"), "{err}");
+}
+
+#[test]
+fn validation_reports_every_problem() {
+    let config = Config::parse(
+        r#"
+        default_room = 5
+
+        [[gateways]]
+        name = "home"
+        [[gateways]]
+        name = "home"
+
+        [[rooms]]
+        id = 1
+        [[rooms.devices]]
+        id = 10
+        type = "lights"
+        name = "lamp"
+        [[rooms.devices]]
+        id = 11
+        type = "lights"
+        name = "other"
+        aliases = ["LAMP", "42"]
+        [[rooms.devices]]
+        id = 12
+        type = "dimmers"
+        name = "desk"
+        codes = { object_type = 6 }
+        [[rooms.devices]]
+        id = 13
+        type = "dimmers"
+        name = "bed"
+        codes = { object_type = 7 }
+
+        [[rooms]]
+        id = 1
+        [[rooms.devices]]
+        id = 10
+        type = 3
+        name = "dupe"
+
+        [rooms.ac]
+        power = 1
+        room_temp = 2
+        set_temp = 3
+        fan_speed = 4
+        power_on = 5
+        power_off = 6
+        temp_up = 7
+        temp_down = 8
+        min_temp = 30
+        max_temp = 20
+        fan = { auto = 9, slow = 10, medium = 11, fast = 12 }
+        "#,
+        Format::Toml,
+    )
+    .unwrap();
+
+    let problems = config.validate().unwrap_err();
+    assert_eq!(problems.len(), 8, "{problems:#?}");
+    for expected in [
+        "gateway \"home\" is declared twice",
+        "room 1 is declared twice",
+        "device 10 is declared twice",
+        "\"LAMP\" is used by both device 10 and device 11",
+        "names can't be numbers",
+        "min_temp 30 above max_temp 20",
+        "default_room 5 isn't one of the rooms",
+        "room 1 has Dimmers with object_type 6 and 7",
+    ] {
+        assert!(
+            problems.iter().any(|p| p.contains(expected)),
+            "missing {expected:?} in {problems:#?}"
+        );
+    }
+}
+
+#[test]
+fn reading_a_bad_file_lists_the_problems() {
+    let path = std::env::temp_dir().join(format!("interra-config-{}.toml", std::process::id()));
+    fs::write(&path, ROOM.replace("min_temp = 18", "min_temp = 40")).unwrap();
+
+    let error = Config::read(&path).unwrap_err().to_string();
+    assert!(error.contains("1 problem(s)"), "{error}");
+    assert!(error.contains("room 7's ac has min_temp 40"), "{error}");
+
+    fs::write(&path, "[[rooms]]\nid = \"twelve\"\n").unwrap();
+    assert!(Config::read(&path).is_err());
+    fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn the_client_names_lights_from_the_config() {
+    let (gateway, client) =
+        common::connected_with(Config::parse(ROOM, Format::Toml).unwrap()).await;
+    gateway.set_device(7, LIGHTS, MockDevice::new(201, true, None));
+    gateway.set_device(7, LIGHTS, MockDevice::new(202, false, None));
+
+    let lights = client.get_room_lights(7).await.unwrap();
+    let names: Vec<_> = lights.iter().map(|l| l.id.as_str()).collect();
+    assert_eq!(names, ["deskLamp", "202"]);
+
+    // my room's lights aren't in this config, so they're just numbers now
+    let lights = client.get_room_lights(12).await.unwrap();
+    let names: Vec<_> = lights.iter().map(|l| l.id.as_str()).collect();
+    assert_eq!(names, ["13", "146"]);
+}
+
+#[tokio::test]
+async fn scenes_survive_a_restart() {
+    let path = common::temp_path("scenes.json");
+    let scene: Scene =
+        serde_json::from_value(json!({ "lights": [{ "id": "shelf", "active": true }] })).unwrap();
+
+    let store = SceneStore::load(&Config::default(), path.clone())
+        .await
+        .unwrap();
+    assert!(store.put("evening", scene).await.unwrap());
+    assert!(!store.insert("evening", Scene::default()).await.unwrap());
+    drop(store);
+
+    let store = SceneStore::load(&Config::default(), path.clone())
+        .await
+        .unwrap();
+    let scene = store.get("evening").await.unwrap();
+    assert_eq!(scene.lights[0].id, "shelf");
+    assert_eq!(scene.lights[0].state.active, Some(true));
+
+    assert!(store.delete("evening").await.unwrap());
+    assert!(!store.delete("evening").await.unwrap());
+    assert!(SceneStore::load(&Config::default(), path.clone())
+        .await
+        .unwrap()
+        .list()
+        .await
+        .is_empty());
+    fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn hand_written_scenes_get_checked_on_startup() {
+    let path = common::temp_path("scenes.json");
+    fs::write(
+        &path,
+        json!({
+            "evening": { "lights": [{ "id": "shelf", "active": true }] },
+            "broken": { "lights": [{ "id": "lavaLamp", "brightness": 250 }] },
+            "empty": {},
+            "no spaces": { "lights": [{ "id": "shelf", "active": false }] },
+        })
+        .to_string(),
+    )
+    .unwrap();
+
+    let e = SceneStore::load(&Config::default(), path.clone())
+        .await
+        .err()
+        .unwrap()
+        .to_string();
+    // every problem, not just the first one
+    assert!(e.contains("has 4 problem(s)"), "{e}");
+    assert!(
+        e.contains("broken: \"lavaLamp\" is NOT a real light"),
+        "{e}"
+    );
+    assert!(e.contains("broken: light lavaLamp:"), "{e}");
+    assert!(e.contains("empty: a scene with nothing in it"), "{e}");
+    assert!(e.contains("\"no spaces\" isn't a name"), "{e}");
+    assert!(!e.contains("evening"), "{e}");
+    fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn hand_written_rules_get_checked_on_startup() {
+    let (_gateway, client) = common::connected().await;
+    let client = Data::from(client);
+    let scenes = SceneStore::load(client.config(), common::temp_path("scenes.json"))
+        .await
+        .unwrap();
+    let path = common::temp_path("rules.json");
+
+    fs::write(
+        &path,
+        json!({
+            "late": {
+                "trigger": { "type": "time", "cron": "0 23 * * *", "timezone": "Europe/Athens" },
+                "actions": [{ "type": "scene", "scene": "bedtime" }],
+            },
+        })
+        .to_string(),
+    )
+    .unwrap();
+    let e = RuleEngine::start(client.clone(), scenes.clone(), path.clone())
+        .await
+        .err()
+        .unwrap();
+    assert!(
+        e.to_string().contains("late: no scene called bedtime"),
+        "{e}"
+    );
+
+    fs::write(
+        &path,
+        json!({
+            "late": {
+                "trigger": { "type": "time", "cron": "0 23 * * *", "timezone": "Europe/Athens" },
+                "actions": [{ "type": "light", "id": "ceiling", "active": false }],
+            },
+        })
+        .to_string(),
+    )
+    .unwrap();
+    let engine = RuleEngine::start(client, scenes, path.clone())
+        .await
+        .unwrap();
+    let rule = engine.get("late").await.unwrap();
+    assert!(rule.enabled);
+    assert!(rule.next_check.is_some());
+    fs::remove_file(&path).unwrap();
+}
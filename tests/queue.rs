@@ -0,0 +1,181 @@
+use interra_api::components::config::{Config, Format};
+use interra_api::components::protocol::{ActionData, ActionType};
+use interra_api::components::queue::{Command, CommandQueue, Lane, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// a queue that writes down what it sends instead of sending it
+fn queue(pacing: &str) -> (CommandQueue, Arc<Mutex<Vec<ActionData>>>) {
+    let config = Config::parse(pacing, Format::Toml).unwrap();
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let log = sent.clone();
+    let send: Sender = Arc::new(move |action| {
+        log.lock().unwrap().push(action);
+        Box::pin(async { Ok(()) })
+    });
+    (CommandQueue::new(Arc::new(config), send), sent)
+}
+
+fn ids(sent: &Mutex<Vec<ActionData>>) -> Vec<String> {
+    sent.lock().unwrap().iter().map(|a| a.id.clone()).collect()
+}
+
+#[tokio::test]
+async fn waiting_steps_add_up() {
+    let (queue, sent) = queue("");
+    let step = |n| Command::Step {
+        up: 64,
+        down: 63,
+        n,
+    };
+
+    // nothing has gone out yet when these go in, so they all fold into one +2
+    let mut handles = Vec::new();
+    for _ in 0..5 {
+        handles.push(queue.submit(Lane::Ac(12), step(1)));
+    }
+    for _ in 0..3 {
+        handles.push(queue.submit(Lane::Ac(12), step(-1)));
+    }
+    for handle in handles {
+        handle.await.unwrap();
+    }
+
+    assert_eq!(ids(&sent), ["64", "64"]);
+}
+
+#[tokio::test]
+async fn steps_that_cancel_out_send_nothing() {
+    let (queue, sent) = queue("");
+    let up = queue.submit(
+        Lane::Ac(12),
+        Command::Step {
+            up: 64,
+            down: 63,
+            n: 3,
+        },
+    );
+    let down = queue.submit(
+        Lane::Ac(12),
+        Command::Step {
+            up: 64,
+            down: 63,
+            n: -3,
+        },
+    );
+
+    up.await.unwrap();
+    down.await.unwrap();
+    assert!(sent.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn the_last_set_wins() {
+    let (queue, sent) = queue("");
+    let on = queue.submit(
+        Lane::Object(13),
+        Command::Set(ActionData::new(ActionType::On, 13)),
+    );
+    let off = queue.submit(
+        Lane::Object(13),
+        Command::Set(ActionData::new(ActionType::Off, 13)),
+    );
+    let press = queue.submit(Lane::Ac(12), Command::Press(66));
+    let press_again = queue.submit(Lane::Ac(12), Command::Press(66));
+
+    on.await.unwrap();
+    off.await.unwrap();
+    press.await.unwrap();
+    press_again.await.unwrap();
+
+    let sent = sent.lock().unwrap();
+    assert_eq!(sent.len(), 2);
+    assert_eq!(sent[0].action_type, ActionType::Off);
+    assert_eq!(sent[1].action_type, ActionType::Press);
+}
+
+#[tokio::test]
+async fn lanes_are_paced_by_type_and_run_side_by_side() {
+    let (queue, sent) = queue("[pacing]\nac = 100\n");
+    let start = Instant::now();
+
+    let steps = queue.submit(
+        Lane::Ac(12),
+        Command::Step {
+            up: 64,
+            down: 63,
+            n: -3,
+        },
+    );
+    let light = queue.submit(
+        Lane::Object(13),
+        Command::Set(ActionData::new(ActionType::On, 13)),
+    );
+
+    // lights don't wait, and don't wait behind the ac either
+    light.await.unwrap();
+    assert!(start.elapsed() < Duration::from_millis(100));
+
+    steps.await.unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(300));
+    assert_eq!(ids(&sent).iter().filter(|id| *id == "63").count(), 3);
+}
+
+#[tokio::test]
+async fn idle_lanes_go_away() {
+    let (queue, sent) = queue("");
+    let light = queue.submit(
+        Lane::Object(13),
+        Command::Set(ActionData::new(ActionType::On, 13)),
+    );
+    let press = queue.submit(Lane::Ac(12), Command::Press(66));
+    assert_eq!(queue.lanes(), 2);
+    light.await.unwrap();
+    press.await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(queue.lanes(), 0);
+
+    // a hold keeps its lane around until it's let go, even with nothing queued
+    let hold = queue.hold(Lane::Ac(12)).await;
+    queue
+        .submit(Lane::Ac(12), Command::Press(67))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(queue.lanes(), 1);
+    drop(hold);
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert_eq!(queue.lanes(), 0);
+
+    // and the next command gets a new worker
+    queue
+        .submit(Lane::Ac(12), Command::Press(66))
+        .await
+        .unwrap();
+    assert_eq!(ids(&sent), ["13", "66", "67", "66"]);
+}
+
+#[tokio::test]
+async fn only_back_to_back_commands_fold_together() {
+    let (queue, sent) = queue("");
+    let step = Command::Step {
+        up: 64,
+        down: 63,
+        n: 1,
+    };
+
+    // fan low, high, low has to end on low
+    let handles = vec![
+        queue.submit(Lane::Ac(12), Command::Press(66)),
+        queue.submit(Lane::Ac(12), Command::Press(68)),
+        queue.submit(Lane::Ac(12), Command::Press(66)),
+        queue.submit(Lane::Ac(12), step.clone()),
+        queue.submit(Lane::Ac(12), Command::Press(57)),
+        queue.submit(Lane::Ac(12), step),
+    ];
+    for handle in handles {
+        handle.await.unwrap();
+    }
+
+    assert_eq!(ids(&sent), ["66", "68", "66", "64", "57", "64"]);
+}
@@ -0,0 +1,176 @@
+mod common;
+
+use actix_web::web::Data;
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use interra_api::components::cron::CronExpr;
+use interra_api::components::mock::{MockDevice, LIGHTS};
+use interra_api::components::scenes::SceneStore;
+use interra_api::components::scheduler::Scheduler;
+use serde_json::json;
+use std::fs;
+use std::time::Duration;
+
+fn utc(s: &str) -> DateTime<Utc> {
+    s.parse().unwrap()
+}
+
+fn athens() -> Tz {
+    "Europe/Athens".parse().unwrap()
+}
+
+#[test]
+fn cron_fields_parse() {
+    let every_15 = CronExpr::parse("*/15 9-17 * * mon-fri").unwrap();
+    let after = NaiveDate::from_ymd_opt(2026, 10, 16) // a friday
+        .unwrap()
+        .and_hms_opt(17, 50, 0)
+        .unwrap();
+    // rolls over the weekend
+    assert_eq!(
+        every_15.next_local(after).unwrap().to_string(),
+        "2026-10-19 09:00:00"
+    );
+
+    // 7 is sunday as well as 0
+    assert_eq!(CronExpr::parse("0 8 * * 7"), CronExpr::parse("0 8 * * sun"));
+    assert_eq!(CronExpr::parse("@daily"), CronExpr::parse("0 0 * * *"));
+
+    for bad in [
+        "* * * *",
+        "60 * * * *",
+        "* * * 13 *",
+        "*/0 * * * *",
+        "5-1 * * * *",
+        "x * * * *",
+    ] {
+        assert!(CronExpr::parse(bad).is_err(), "{bad} parsed");
+    }
+}
+
+#[test]
+fn cron_never_fires_on_impossible_dates() {
+    let feb_30 = CronExpr::parse("0 0 30 2 *").unwrap();
+    assert_eq!(
+        feb_30.next_after(utc("2026-01-01T00:00:00Z"), Tz::UTC),
+        None
+    );
+}
+
+#[test]
+fn day_of_month_or_weekday() {
+    // the 1st, or any monday, like real cron
+    let expr = CronExpr::parse("0 12 1 * mon").unwrap();
+    let next = expr
+        .next_after(utc("2026-10-18T00:00:00Z"), Tz::UTC)
+        .unwrap();
+    assert_eq!(next, utc("2026-10-19T12:00:00Z"));
+    let next = expr
+        .next_after(utc("2026-10-26T13:00:00Z"), Tz::UTC)
+        .unwrap();
+    assert_eq!(next, utc("2026-11-01T12:00:00Z"));
+}
+
+#[test]
+fn timezones_shift_the_fire_time() {
+    let seven = CronExpr::parse("0 7 * * *").unwrap();
+    // athens is utc+3 in summer
+    assert_eq!(
+        seven.next_after(utc("2026-07-01T00:00:00Z"), athens()),
+        Some(utc("2026-07-01T04:00:00Z"))
+    );
+}
+
+#[test]
+fn skipped_times_fire_when_the_gap_ends() {
+    // 2026-03-29 in athens goes 03:00 -> 04:00, so 03:30 never happens
+    let half_three = CronExpr::parse("30 3 * * *").unwrap();
+    assert_eq!(
+        half_three.next_after(utc("2026-03-28T12:00:00Z"), athens()),
+        Some(utc("2026-03-29T01:00:00Z"))
+    );
+    // and back to normal the day after
+    assert_eq!(
+        half_three.next_after(utc("2026-03-29T01:00:00Z"), athens()),
+        Some(utc("2026-03-30T00:30:00Z"))
+    );
+}
+
+#[test]
+fn repeated_times_fire_once() {
+    // 2026-10-25 in athens goes 04:00 -> 03:00, so 03:30 happens twice
+    let half_three = CronExpr::parse("30 3 * * *").unwrap();
+    let first = half_three
+        .next_after(utc("2026-10-24T12:00:00Z"), athens())
+        .unwrap();
+    assert_eq!(first, utc("2026-10-25T00:30:00Z"));
+    // not again an hour later
+    assert_eq!(
+        half_three.next_after(first, athens()),
+        Some(utc("2026-10-26T01:30:00Z"))
+    );
+
+    // something every 30 minutes keeps going through both copies of the hour
+    let halves = CronExpr::parse("*/30 * * * *").unwrap();
+    let mut at = utc("2026-10-25T00:00:00Z");
+    let mut fires = Vec::new();
+    for _ in 0..4 {
+        at = halves.next_after(at, athens()).unwrap();
+        fires.push(at);
+    }
+    assert_eq!(
+        fires,
+        [
+            utc("2026-10-25T00:30:00Z"),
+            utc("2026-10-25T01:00:00Z"),
+            utc("2026-10-25T01:30:00Z"),
+            utc("2026-10-25T02:00:00Z"),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn missed_one_shots_run_after_a_restart() {
+    let (gateway, client) = common::connected().await;
+    gateway.set_device(12, LIGHTS, MockDevice::new(146, false, None));
+    let client = Data::from(client);
+    let scenes = SceneStore::load(client.config(), common::temp_path("scenes.json"))
+        .await
+        .unwrap();
+
+    // written while we were down, and it was meant to go off an hour ago
+    let path = common::temp_path("schedules.json");
+    let schedules = json!({
+        "shelfOn": {
+            "at": Utc::now() - chrono::Duration::hours(1),
+            "action": { "type": "light", "id": "shelf", "active": true },
+        },
+        "alreadyDone": {
+            "at": Utc::now() - chrono::Duration::hours(1),
+            "action": { "type": "light", "id": "ceiling", "active": true },
+            "lastRun": { "at": Utc::now() - chrono::Duration::hours(1), "ok": true },
+        },
+    });
+    fs::write(&path, schedules.to_string()).unwrap();
+
+    let scheduler = Scheduler::start(client, scenes, path.clone())
+        .await
+        .unwrap();
+    let mut ran = None;
+    for _ in 0..50 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        ran = scheduler.get("shelfOn").await.unwrap().last_run;
+        if ran.is_some() {
+            break;
+        }
+    }
+    assert!(ran.unwrap().ok);
+    let done = scheduler.get("alreadyDone").await.unwrap();
+    assert_eq!(done.next_fire, None);
+
+    // and how it went is in the file
+    let saved: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(saved["shelfOn"]["lastRun"]["ok"], true);
+    fs::remove_file(&path).unwrap();
+}